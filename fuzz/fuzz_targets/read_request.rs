@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::BufReader;
+
+//webber is a binary, so the parser is pulled in on its own
+#[allow(dead_code)]
+#[path = "../../src/http.rs"]
+mod http;
+
+fuzz_target!(|data: &[u8]| {
+    //A small buffer makes lines and chunks straddle refills
+    let mut reader = BufReader::with_capacity(16, data);
+    //Keep reading like a kept alive connection would, until the input runs out
+    while let Ok(Some(request)) = http::read_request(&mut reader) {
+        assert!(request.target.starts_with('/') || request.target == "*");
+        let _ = request.keep_alive();
+    }
+});
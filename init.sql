@@ -7,6 +7,21 @@ CREATE TABLE users (
     salt INTEGER NOT NULL
 );
 
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    user_id TEXT NOT NULL,
+    created TEXT NOT NULL,
+    expires TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    user_agent TEXT,
+    refresh_hash TEXT,
+    ip TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX sessions_refresh_hash ON sessions (refresh_hash);
+
 CREATE TABLE tasks (
     id TEXT PRIMARY KEY,
     assign_date TEXT NOT NULL,
@@ -3,12 +3,17 @@ const textarea = document.getElementById("textarea")
 document.getElementById("login").addEventListener("click", async () => {
     let loginResponse = await fetch("/api/login", {
         method: "POST",
-        body: JSON.stringify(gatherLogin()),
+        body: JSON.stringify({
+            ...gatherLogin(),
+            cookie: document.getElementById("cookieinput").checked,
+        }),
     })
 
     if (loginResponse.ok) {
         loginResponse = await loginResponse.json()
-        window.localStorage.setItem("authority", loginResponse.authority)
+        //Only one of them comes back, the cookie carries the session otherwise
+        window.localStorage.setItem("authority", loginResponse.authority ?? "")
+        window.localStorage.setItem("csrf", loginResponse.csrf ?? "")
     }
 
     textarea.value = JSON.stringify(loginResponse, null, 4)
@@ -24,12 +29,8 @@ document.getElementById("create").addEventListener("click", async () => {
 })
 
 document.getElementById("task").addEventListener("click", async () => {
-    const authority = window.localStorage.getItem("authority")
-    const headers = new Headers()
-    headers.append("authority", authority)
-
     const r = await fetch("/api/task", {
-        headers: headers,
+        headers: authHeaders(),
     }).then((r) => r.json())
 
     console.log(r)
@@ -38,10 +39,6 @@ document.getElementById("task").addEventListener("click", async () => {
 })
 
 document.getElementById("newtask").addEventListener("click", async () => {
-    const authority = window.localStorage.getItem("authority")
-    const headers = new Headers()
-    headers.append("authority", authority)
-
     const body = {
         due_date: document.getElementById("inputdate").value,
         assign_date: new Date(Date.now()).toISOString().slice(0, 10),
@@ -54,7 +51,7 @@ document.getElementById("newtask").addEventListener("click", async () => {
 
     const r = await fetch("/api/task", {
         method: "POST",
-        headers: headers,
+        headers: authHeaders(),
         body: JSON.stringify(body),
     }).then((r) => r.json())
 
@@ -63,10 +60,26 @@ document.getElementById("newtask").addEventListener("click", async () => {
     textarea.value = JSON.stringify(r, null, 4)
 })
 
-document.getElementById("deauth").addEventListener("click", () => {
+document.getElementById("deauth").addEventListener("click", async () => {
+    await fetch("/api/logout", { method: "POST", headers: authHeaders() })
     window.localStorage.setItem("authority", "")
+    window.localStorage.setItem("csrf", "")
 })
 
+//The authority when logged in without a cookie, the CSRF token going with
+//the cookie otherwise
+function authHeaders() {
+    const headers = new Headers()
+    const authority = window.localStorage.getItem("authority")
+    const csrf = window.localStorage.getItem("csrf")
+    if (authority) {
+        headers.append("authority", authority)
+    } else if (csrf) {
+        headers.append("x-csrf-token", csrf)
+    }
+    return headers
+}
+
 function gatherLogin() {
     const username = document.getElementById("userinput").value
     const password = document.getElementById("passwordinput").value
@@ -0,0 +1,2017 @@
+use chrono::{NaiveDate, Utc};
+use rusqlite::{Connection, Params};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use crate::authorization::{authorize, Access, Resource};
+use crate::connection::Responder;
+use crate::cors;
+use crate::data_structs::{
+    Child, CompleteSubtask, CompleteTask, IdCarrier, LoginRequest, Occurrence, PasswordSettings,
+    RefreshRequest, Settings, SkipSubtask, SkipTask, Sql, Subtask, Task, TaskPatch, User,
+};
+use crate::events::{self, Events};
+use crate::http::{Request, Version};
+use crate::password::{self, Verified};
+use crate::router::Router;
+use crate::session::Sessions;
+use crate::websocket::{self, HandshakeError, WebSockets};
+use crate::{serve_200_json, serve_error_json, write_200_json, write_error_json, HttpError};
+
+const MAX_AGENDA_DAYS: i64 = 366;
+
+///Everything a handler gets to work with for one request.
+///`params` holds the captures of the matched route and `query` the decoded query string.
+pub struct ApiRequest<'a> {
+    pub stream: &'a Responder<'a>,
+    pub request: &'a Request,
+    pub settings: &'a Settings,
+    pub sql_connection: Arc<Mutex<Connection>>,
+    pub session: Arc<Sessions>,
+    pub events: Arc<Events>,
+    pub websockets: WebSockets,
+    ///The WebSocket the request came in on, its own changes aren't echoed
+    ///back to it.
+    pub origin: Option<u64>,
+    pub params: HashMap<String, String>,
+    pub query: HashMap<String, String>,
+}
+
+pub type Handler = fn(ApiRequest);
+
+pub fn router() -> Router<Handler> {
+    Router::<Handler>::new()
+        .route("GET", "/api/task", get_task)
+        .route("POST", "/api/task", post_task)
+        .route("PATCH", "/api/task", patch_task)
+        .route("DELETE", "/api/task", delete_task)
+        .route("PATCH", "/api/task/{id}", patch_task)
+        .route("DELETE", "/api/task/{id}", delete_task)
+        .route("GET", "/api/task/{task_id}/subtask", get_subtask)
+        .route("POST", "/api/task/{task_id}/subtask", post_subtask)
+        .route("POST", "/api/task/{task_id}/complete", post_complete_task)
+        .route("POST", "/api/task/{task_id}/skip", post_skip_task)
+        .route("GET", "/api/agenda", get_agenda)
+        .route("POST", "/api/complete_task", post_complete_task)
+        .route("DELETE", "/api/complete_task", delete_complete_task)
+        .route("DELETE", "/api/complete_task/{id}", delete_complete_task)
+        .route("POST", "/api/skip_task", post_skip_task)
+        .route("DELETE", "/api/skip_task", delete_skip_task)
+        .route("DELETE", "/api/skip_task/{id}", delete_skip_task)
+        .route("GET", "/api/subtask", get_subtask)
+        .route("POST", "/api/subtask", post_subtask)
+        .route("DELETE", "/api/subtask", delete_subtask)
+        .route("DELETE", "/api/subtask/{id}", delete_subtask)
+        .route(
+            "POST",
+            "/api/subtask/{subtask_id}/complete",
+            post_complete_subtask,
+        )
+        .route("POST", "/api/subtask/{subtask_id}/skip", post_skip_subtask)
+        .route("POST", "/api/complete_subtask", post_complete_subtask)
+        .route("DELETE", "/api/complete_subtask", delete_complete_subtask)
+        .route(
+            "DELETE",
+            "/api/complete_subtask/{id}",
+            delete_complete_subtask,
+        )
+        .route("POST", "/api/skip_subtask", post_skip_subtask)
+        .route("DELETE", "/api/skip_subtask", delete_skip_subtask)
+        .route("DELETE", "/api/skip_subtask/{id}", delete_skip_subtask)
+        .route("GET", "/api/user", get_user)
+        .route("POST", "/api/user", post_user)
+        .route("DELETE", "/api/user", delete_user)
+        .route("POST", "/api/login", post_login)
+        .route("POST", "/api/refresh", post_refresh)
+        .route("POST", "/api/logout", post_logout)
+        .route("POST", "/api/logout_all", post_logout_all)
+        .route("GET", "/api/sessions", get_sessions)
+        .route("DELETE", "/api/sessions/{id}", delete_session)
+        .route("GET", "/api/events", get_events)
+        .route("GET", "/api/ws", get_ws)
+}
+
+fn get_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let mut tasks: Vec<Box<Task>> = match query_to_object::<Task>(
+        sql_connection.clone(),
+        "SELECT * FROM tasks WHERE user_id = ?1;",
+        [&user_id],
+    ) {
+        Ok(vec_of_boxes) => vec_of_boxes,
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+
+    //Long task lists are sent one task at a time instead of built up in memory
+    let mut body = match stream.chunked("200 OK", "application/json") {
+        Ok(body) => body,
+        Err(err) => {
+            println!("Could not write header to stream");
+            println!("{err}");
+            stream.abort();
+            return;
+        }
+    };
+
+    let today = Utc::now().date_naive();
+    for (i, t) in tasks.iter_mut().enumerate() {
+        //Past the head there is no way to report an error but cutting the body short
+        if let Err(err) = load_marks(sql_connection.clone(), t) {
+            println!("{err}");
+            stream.abort();
+            return;
+        }
+        t.subtasks = match load_subtasks(sql_connection.clone(), &t.id) {
+            Ok(subtasks) => subtasks,
+            Err(err) => {
+                println!("{err}");
+                stream.abort();
+                return;
+            }
+        };
+        t.schedule(today);
+
+        let separator = if i == 0 { "[" } else { "," };
+        if let Err(err) = body.write_all(format!("{separator}{}", t.to_json()).as_bytes()) {
+            println!("Could not write body to stream");
+            println!("{err}");
+            stream.abort();
+            return;
+        }
+    }
+
+    let end = if tasks.is_empty() { "[]" } else { "]" };
+    if let Err(err) = body.write_all(end.as_bytes()).and_then(|_| body.finish()) {
+        println!("Could not write body to stream");
+        println!("{err}");
+        stream.abort();
+    }
+}
+
+fn get_agenda(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        query,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let (from, to) = match parse_date_range(&query) {
+        Ok(range) => range,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err);
+            return;
+        }
+    };
+
+    let tasks: Vec<Box<Task>> = match query_to_object::<Task>(
+        sql_connection.clone(),
+        "SELECT * FROM tasks WHERE user_id = ?1;",
+        [&user_id],
+    ) {
+        Ok(vec_of_boxes) => vec_of_boxes,
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+
+    let today = Utc::now().date_naive();
+    let mut agenda: Vec<Occurrence> = Vec::new();
+    for mut t in tasks {
+        match load_marks(sql_connection.clone(), &mut t) {
+            Ok(_) => (),
+            Err(err) => {
+                serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+                return;
+            }
+        }
+        agenda.extend(t.agenda(from, to, today));
+    }
+
+    agenda.sort_by(|a, b| a.date.cmp(&b.date).then_with(|| a.title.cmp(&b.title)));
+
+    serve_200_json(stream, serde_json::to_string(&agenda).unwrap());
+}
+
+fn post_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = extract_body(stream, request);
+    if body.is_none() {
+        return;
+    }
+
+    let mut task = match Task::from_json(body.unwrap().as_str()) {
+        Ok(task) => task,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Err(err) = task.validate() {
+        serve_error_json(stream, HttpError::BadRequest, err);
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    task.user_id = user_id.clone();
+    match task.insert(&sql_connection) {
+        Ok(_) => {}
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    task.schedule(Utc::now().date_naive());
+    let json = task.to_json();
+    events.publish(&user_id, origin, "task_created", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn patch_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = match extract_body(stream, request) {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut patch: TaskPatch = match serde_json::from_str(&body) {
+        Ok(patch) => patch,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Some(id) = params.get("id") {
+        patch.id = id.clone();
+    }
+
+    if !check_access(stream, &sql_connection, &user_id, Resource::Task(&patch.id)) {
+        return;
+    }
+
+    let mut task = match query_to_object::<Task>(
+        sql_connection.clone(),
+        "SELECT * FROM tasks WHERE id = ?1;",
+        [&patch.id],
+    ) {
+        Ok(mut tasks) if !tasks.is_empty() => tasks.remove(0),
+        Ok(_) => {
+            serve_error_json(stream, HttpError::NotFound, format!("No task {}", patch.id));
+            return;
+        }
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+
+    task.apply(patch);
+    if let Err(err) = task.validate() {
+        serve_error_json(stream, HttpError::BadRequest, err);
+        return;
+    }
+
+    let conn = sql_connection.lock().unwrap();
+    match task.update(&conn) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(conn);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(conn);
+
+    match load_marks(sql_connection.clone(), &mut task) {
+        Ok(_) => (),
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    task.subtasks = match load_subtasks(sql_connection, &task.id) {
+        Ok(subtasks) => subtasks,
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+    task.schedule(Utc::now().date_naive());
+
+    let json = task.to_json();
+    events.publish(&user_id, origin, "task_updated", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn delete_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let id_carrier = match extract_id(stream, request, &params) {
+        Some(ic) => ic,
+        None => return,
+    };
+
+    let task_id = &id_carrier.id;
+    if !check_access(stream, &sql_connection, &user_id, Resource::Task(task_id)) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute("DELETE FROM tasks WHERE id = ?1;", [task_id]) {
+        Ok(_) => (),
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+    drop(sql_connection);
+
+    let json = serde_json::to_string(&id_carrier).unwrap();
+    events.publish(&user_id, origin, "task_deleted", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn post_complete_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = match extract_body(stream, request) {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut complete_task = match CompleteTask::from_json(&body) {
+        Ok(ct) => ct,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Some(task_id) = params.get("task_id") {
+        complete_task.task_id = task_id.clone();
+    }
+
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::Task(&complete_task.task_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match complete_task.insert(&sql_connection) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    let json = complete_task.to_json();
+    events.publish(
+        &user_id,
+        origin,
+        "complete_task_created",
+        complete_task.event_json(),
+    );
+    serve_200_json(stream, json);
+}
+
+fn delete_complete_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let id_carrier = match extract_id(stream, request, &params) {
+        Some(ic) => ic,
+        None => return,
+    };
+
+    let complete_task_id = &id_carrier.id;
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::CompleteTask(complete_task_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute(
+        "DELETE FROM complete_tasks WHERE id = ?1;",
+        [complete_task_id],
+    ) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    drop(sql_connection);
+
+    let json = serde_json::ser::to_string(&id_carrier).unwrap();
+    events.publish(&user_id, origin, "complete_task_deleted", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn post_skip_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = match extract_body(stream, request) {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut skip_task = match SkipTask::from_json(&body) {
+        Ok(st) => st,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Some(task_id) = params.get("task_id") {
+        skip_task.task_id = task_id.clone();
+    }
+
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::Task(&skip_task.task_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match skip_task.insert(&sql_connection) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    let json = skip_task.to_json();
+    events.publish(
+        &user_id,
+        origin,
+        "skip_task_created",
+        skip_task.event_json(),
+    );
+    serve_200_json(stream, json);
+}
+
+fn delete_skip_task(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let id_carrier = match extract_id(stream, request, &params) {
+        Some(ic) => ic,
+        None => return,
+    };
+
+    let skip_task_id = &id_carrier.id;
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::SkipTask(skip_task_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute("DELETE FROM skip_tasks WHERE id = ?1;", [skip_task_id]) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+    drop(sql_connection);
+
+    let json = serde_json::to_string(&id_carrier).unwrap();
+    events.publish(&user_id, origin, "skip_task_deleted", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn get_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        query,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let task_id = match params.get("task_id").or(query.get("task_id")) {
+        Some(task_id) => task_id,
+        None => {
+            serve_error_json(
+                stream,
+                HttpError::BadRequest,
+                String::from("Missing query parameter task_id"),
+            );
+            return;
+        }
+    };
+
+    if !check_access(stream, &sql_connection, &user_id, Resource::Task(task_id)) {
+        return;
+    }
+
+    let subtasks = match load_subtasks(sql_connection, task_id) {
+        Ok(subtasks) => subtasks,
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+
+    serve_200_json(stream, serde_json::to_string(&subtasks).unwrap());
+}
+
+fn post_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = match extract_body(stream, request) {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut subtask = match Subtask::from_json(&body) {
+        Ok(subtask) => subtask,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Some(task_id) = params.get("task_id") {
+        subtask.task_id = task_id.clone();
+    }
+
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::Task(&subtask.task_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match subtask.insert(&sql_connection) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    let json = subtask.to_json();
+    events.publish(&user_id, origin, "subtask_created", subtask.event_json());
+    serve_200_json(stream, json);
+}
+
+fn delete_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let id_carrier = match extract_id(stream, request, &params) {
+        Some(ic) => ic,
+        None => return,
+    };
+
+    let id = &id_carrier.id;
+    if !check_access(stream, &sql_connection, &user_id, Resource::Subtask(id)) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute("DELETE FROM subtasks WHERE id = ?1;", [id]) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+    drop(sql_connection);
+
+    let json = serde_json::to_string(&id_carrier).unwrap();
+    events.publish(&user_id, origin, "subtask_deleted", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn post_complete_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = match extract_body(stream, request) {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut complete_subtask = match CompleteSubtask::from_json(&body) {
+        Ok(complete_subtask) => complete_subtask,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Some(subtask_id) = params.get("subtask_id") {
+        complete_subtask.subtask_id = subtask_id.clone();
+    }
+
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::Subtask(&complete_subtask.subtask_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match complete_subtask.insert(&sql_connection) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    let json = complete_subtask.to_json();
+    events.publish(
+        &user_id,
+        origin,
+        "complete_subtask_created",
+        complete_subtask.event_json(),
+    );
+    serve_200_json(stream, json);
+}
+
+fn delete_complete_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let id_carrier = match extract_id(stream, request, &params) {
+        Some(ic) => ic,
+        None => return,
+    };
+
+    let id = &id_carrier.id;
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::CompleteSubtask(id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute("DELETE FROM complete_subtasks WHERE id = ?1;", [id]) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+    drop(sql_connection);
+
+    let json = serde_json::to_string(&id_carrier).unwrap();
+    events.publish(&user_id, origin, "complete_subtask_deleted", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn post_skip_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let body = match extract_body(stream, request) {
+        Some(b) => b,
+        None => return,
+    };
+
+    let mut skip_subtask = match SkipSubtask::from_json(&body) {
+        Ok(skip_subtask) => skip_subtask,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if let Some(subtask_id) = params.get("subtask_id") {
+        skip_subtask.subtask_id = subtask_id.clone();
+    }
+
+    if !check_access(
+        stream,
+        &sql_connection,
+        &user_id,
+        Resource::Subtask(&skip_subtask.subtask_id),
+    ) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match skip_subtask.insert(&sql_connection) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    let json = skip_subtask.to_json();
+    events.publish(
+        &user_id,
+        origin,
+        "skip_subtask_created",
+        skip_subtask.event_json(),
+    );
+    serve_200_json(stream, json);
+}
+
+fn delete_skip_subtask(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        origin,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let id_carrier = match extract_id(stream, request, &params) {
+        Some(ic) => ic,
+        None => return,
+    };
+
+    let id = &id_carrier.id;
+    if !check_access(stream, &sql_connection, &user_id, Resource::SkipSubtask(id)) {
+        return;
+    }
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute("DELETE FROM skip_subtasks WHERE id = ?1;", [id]) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    };
+    drop(sql_connection);
+
+    let json = serde_json::to_string(&id_carrier).unwrap();
+    events.publish(&user_id, origin, "skip_subtask_deleted", json.clone());
+    serve_200_json(stream, json);
+}
+
+fn get_user(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    serve_200_json(stream, format!("{{\"userId\":\"{}\"}}", user_id));
+}
+
+fn post_user(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        settings,
+        sql_connection,
+        ..
+    } = req;
+
+    let body = match extract_body(stream, request) {
+        Some(body) => body,
+        None => return,
+    };
+
+    let mut user = match User::from_json(body.as_str()) {
+        Ok(user) => user,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    user.password = match password::hash(&user.password, &settings.password) {
+        Ok(hash) => hash,
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err);
+            return;
+        }
+    };
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match user.insert(&sql_connection) {
+        Ok(_) => {}
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    serve_200_json(stream, user.to_json());
+}
+
+fn delete_user(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        sql_connection,
+        session,
+        events,
+        websockets,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session.clone()) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let sql_connection = sql_connection.lock().unwrap();
+    match sql_connection.execute("DELETE FROM users WHERE id = ?1;", [&user_id]) {
+        Ok(_) => (),
+        Err(err) => {
+            drop(sql_connection);
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            return;
+        }
+    }
+    drop(sql_connection);
+
+    let ended = session.remove_user(&user_id);
+    close_streams(&events, &websockets, &ended);
+
+    let body = r#"{"user_id":"{}"}"#;
+    let body = body.replace("{}", user_id.as_str());
+    serve_200_json(stream, body);
+}
+
+fn post_login(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        settings,
+        sql_connection,
+        session,
+        ..
+    } = req;
+
+    let body = match extract_body(stream, request) {
+        Some(body) => body,
+        None => return,
+    };
+
+    let login: LoginRequest = match serde_json::de::from_str(body.as_str()) {
+        Ok(login) => login,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+    if login.cookie && !session.cookies_enabled() {
+        serve_error_json(
+            stream,
+            HttpError::BadRequest,
+            String::from("Session cookies are not enabled"),
+        );
+        return;
+    }
+
+    let passwd = login.password;
+
+    let user = match query_to_object::<User>(
+        sql_connection.clone(),
+        "SELECT * FROM users WHERE username = ?1;",
+        [&login.username],
+    ) {
+        Ok(user) => user,
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            return;
+        }
+    };
+
+    let user = match user.first() {
+        Some(user) => user,
+        None => {
+            password::verify_nobody(&passwd, &settings.password);
+            serve_error_json(
+                stream,
+                HttpError::BadRequest,
+                String::from("User not found or invalid password"),
+            );
+            return;
+        }
+    };
+
+    let verified = password::verify(&passwd, &user.password, user.salt, &settings.password);
+    if verified == Verified::Outdated {
+        rehash(&sql_connection, user, &passwd, &settings.password);
+    }
+    if verified != Verified::No {
+        let (session_uuid, refresh) =
+            match session.create(&user.id, request.headers.get("user-agent"), request.peer) {
+                Ok(tokens) => tokens,
+                Err(err) => {
+                    serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+                    return;
+                }
+            };
+
+        //Not logged, the tokens are as good as the password
+        if login.cookie {
+            //Kept out of the body, where scripts could read them
+            let json = json!({
+                "username": user.username,
+                "userId": user.id,
+                "csrf": session.csrf_token(&session_uuid),
+            });
+            write_200_json(
+                stream,
+                json.to_string(),
+                &session.set_cookies(&session_uuid, &refresh),
+            );
+            return;
+        }
+        let json = json!({
+            "username": user.username,
+            "userId": user.id,
+            "authority": session_uuid,
+            "refresh": refresh,
+        });
+
+        serve_200_json(stream, json.to_string());
+    } else {
+        serve_error_json(
+            stream,
+            HttpError::BadRequest,
+            String::from("User not found or invalid password"),
+        );
+    }
+}
+
+///Trades a refresh token for a new authority once the old one went idle,
+///or before. The refresh token is replaced too.
+fn post_refresh(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        ..
+    } = req;
+
+    let refresh: RefreshRequest = if request.body.is_empty() {
+        RefreshRequest::default()
+    } else {
+        let body = match extract_body(stream, request) {
+            Some(body) => body,
+            None => return,
+        };
+        match serde_json::from_str(&body) {
+            Ok(refresh) => refresh,
+            Err(err) => {
+                serve_error_json(stream, HttpError::BadRequest, err.to_string());
+                return;
+            }
+        }
+    };
+
+    //Without one in the body, the cookie. It changes something, so the CSRF
+    //token of the authority cookie has to come along.
+    let (refresh, cookie) = match refresh.refresh.as_deref() {
+        Some(refresh) => (refresh, false),
+        None => match session.cookie_refresh(&request.headers) {
+            Some(refresh) => {
+                let token = session.cookie_token(&request.headers).unwrap_or_default();
+                if !session.check_csrf(token, request.headers.get("x-csrf-token")) {
+                    serve_error_json(
+                        stream,
+                        HttpError::Forbidden,
+                        String::from("Missing or wrong X-CSRF-Token"),
+                    );
+                    return;
+                }
+                (refresh, true)
+            }
+            None => {
+                serve_error_json(
+                    stream,
+                    HttpError::BadRequest,
+                    String::from("No refresh token"),
+                );
+                return;
+            }
+        },
+    };
+
+    let (authority, refresh, user_id) = match session.refresh(refresh, request.peer) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+    if cookie {
+        let csrf = session.csrf_token(&authority);
+        write_200_json(
+            stream,
+            format!("{{\"userId\":\"{user_id}\",\"csrf\":\"{csrf}\"}}"),
+            &session.set_cookies(&authority, &refresh),
+        );
+        return;
+    }
+    serve_200_json(
+        stream,
+        format!(
+            "{{\"userId\":\"{user_id}\",\"authority\":\"{authority}\",\"refresh\":\"{refresh}\"}}"
+        ),
+    );
+}
+
+///Ends the session of the authority sent, even one gone idle.
+fn post_logout(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        events,
+        websockets,
+        ..
+    } = req;
+
+    let authority = match extract_token(request, &session) {
+        Ok(authority) => authority,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+    match session.revoke(authority) {
+        Ok(ended) => {
+            close_streams(&events, &websockets, &[ended.id]);
+            write_200_json(
+                stream,
+                format!("{{\"userId\":\"{}\"}}", ended.user_id),
+                &session.clear_cookies(),
+            )
+        }
+        Err(err) => serve_error_json(stream, HttpError::Forbidden, String::from(err)),
+    }
+}
+
+///Ends every session of the caller, on every device.
+fn post_logout_all(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        events,
+        websockets,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session.clone()) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+    let ended = session.remove_user(&user_id);
+    close_streams(&events, &websockets, &ended);
+    write_200_json(
+        stream,
+        format!("{{\"userId\":\"{user_id}\"}}"),
+        &session.clear_cookies(),
+    );
+}
+
+///Where the caller is logged in.
+fn get_sessions(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session.clone()) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    match session.list(&user_id) {
+        Ok(sessions) => serve_200_json(stream, serde_json::to_string(&sessions).unwrap()),
+        Err(err) => serve_error_json(stream, HttpError::InternalServerError, err.to_string()),
+    }
+}
+
+///Logs one of the caller's sessions out, on whatever device it is.
+fn delete_session(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        events,
+        websockets,
+        params,
+        ..
+    } = req;
+
+    let user_id = match extract_user_id(request, session.clone()) {
+        Ok(user_id) => user_id,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+    let id = match params.get("id") {
+        Some(id) => id,
+        None => {
+            serve_error_json(stream, HttpError::BadRequest, String::from("No id"));
+            return;
+        }
+    };
+
+    match session.revoke_id(&user_id, id) {
+        Ok(true) => {
+            close_streams(&events, &websockets, std::slice::from_ref(id));
+            serve_200_json(stream, format!("{{\"id\":\"{id}\"}}"))
+        }
+        //Someone else's session is as unknown to the caller as one that doesn't exist
+        Ok(false) => serve_error_json(stream, HttpError::NotFound, format!("No session {id}")),
+        Err(err) => serve_error_json(stream, HttpError::InternalServerError, err.to_string()),
+    }
+}
+
+///Replaces the stored hash of `user` with one of the current kind and costs.
+///Failing only means trying again on the next login.
+fn rehash(
+    sql_connection: &Arc<Mutex<Connection>>,
+    user: &User,
+    passwd: &str,
+    settings: &PasswordSettings,
+) {
+    let hash = match password::hash(passwd, settings) {
+        Ok(hash) => hash,
+        Err(err) => {
+            println!("Could not rehash password");
+            println!("{err}");
+            return;
+        }
+    };
+    let sql_connection = sql_connection.lock().unwrap();
+    if let Err(err) = sql_connection.execute(
+        "UPDATE users SET password = ?1, salt = 0 WHERE id = ?2;",
+        [&hash, &user.id],
+    ) {
+        println!("Could not rehash password");
+        println!("{err}");
+    }
+}
+
+///Streams changes to the caller's tasks as server-sent events. The
+///connection is handed to `Events` and no longer occupies a worker.
+fn get_events(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        session,
+        events,
+        query,
+        ..
+    } = req;
+
+    //EventSource can't set headers, so browsers pass the authority in the query
+    let user = match query.get("authority") {
+        Some(authority) if !request.headers.contains("authority") => session.lookup(authority),
+        _ => extract_token(request, &session).and_then(|authority| session.lookup(authority)),
+    };
+    let user = match user {
+        Ok(user) => user,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+    let last_event_id = request.headers.get("last-event-id").map(String::from);
+
+    let mut body = format!("retry: {}\n\n", events::RETRY_MS);
+    let mut length = String::new();
+    if request.version == Version::Http2 {
+        //A stream can't be taken out of an HTTP/2 connection. The client gets
+        //what happened so far and comes back for more after the retry delay.
+        body.push_str(&events.replay(&user.user_id, last_event_id.as_deref()));
+        length = format!("Content-Length: {}\r\n", body.len());
+    } else if request.method != "HEAD" {
+        stream.hand_over(move |client| {
+            events.subscribe(client, user.user_id, user.id, last_event_id.as_deref())
+        });
+    }
+
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n{}Content-Type: text/event-stream\r\nCache-Control: no-cache\r\n{length}\r\n{body}",
+        stream.common_headers()
+    );
+    let mut stream = stream;
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        println!("Could not write header to stream");
+        println!("{err}");
+        stream.take_hand_over();
+    }
+}
+
+///Upgrades the connection to a WebSocket taking the same operations as the
+///REST endpoints. The connection is handed to the WebSocket hub and no
+///longer occupies a worker.
+fn get_ws(req: ApiRequest) {
+    let ApiRequest {
+        stream,
+        request,
+        settings,
+        session,
+        websockets,
+        query,
+        ..
+    } = req;
+
+    //Browsers can't set headers on a WebSocket either
+    let authority = match request.headers.get("authority") {
+        Some(authority) => authority,
+        None => match query.get("authority") {
+            Some(authority) => authority.as_str(),
+            None => match session.cookie_token(&request.headers) {
+                //Any site can open a WebSocket, and the browser sends the cookie along
+                Some(_) if !cors::trusted_origin(&settings.cors, request) => {
+                    serve_error_json(
+                        stream,
+                        HttpError::Forbidden,
+                        String::from("WebSocket from an untrusted origin"),
+                    );
+                    return;
+                }
+                Some(authority) => authority,
+                None => {
+                    serve_error_json(
+                        stream,
+                        HttpError::Forbidden,
+                        String::from("No Authority in header"),
+                    );
+                    return;
+                }
+            },
+        },
+    };
+    let user = match session.lookup(authority) {
+        Ok(user) => user,
+        Err(err) => {
+            serve_error_json(stream, HttpError::Forbidden, String::from(err));
+            return;
+        }
+    };
+
+    let accept = match websocket::accept_key(request) {
+        Ok(accept) => accept,
+        Err(HandshakeError::BadRequest(reason)) => {
+            serve_error_json(stream, HttpError::BadRequest, String::from(reason));
+            return;
+        }
+        Err(HandshakeError::UnsupportedVersion) => {
+            write_error_json(
+                stream,
+                HttpError::UpgradeRequired,
+                String::from("Only WebSocket version 13 is supported"),
+                "Sec-WebSocket-Version: 13\r\n",
+            );
+            return;
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    let mut stream = stream;
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        println!("Could not write header to stream");
+        println!("{err}");
+        stream.abort();
+        return;
+    }
+    let authority = authority.to_string();
+    stream.hand_over(move |client| websockets.open(client, user.user_id, user.id, authority));
+}
+
+fn extract_user_id(request: &Request, session: Arc<Sessions>) -> Result<String, &'static str> {
+    let authority = extract_token(request, &session)?;
+    lookup_session(authority, session)
+}
+
+///The authority sent in the authority header or the session cookie.
+///Browsers send cookies along with requests other sites make them send,
+///so with the cookie, requests changing something need the CSRF token too.
+fn extract_token<'a>(request: &'a Request, session: &Sessions) -> Result<&'a str, &'static str> {
+    if let Some(authority) = request.headers.get("authority") {
+        return Ok(authority);
+    }
+    let token = match session.cookie_token(&request.headers) {
+        Some(token) => token,
+        None => return Err("No Authority in header"),
+    };
+    let safe = matches!(request.method.as_str(), "GET" | "HEAD" | "OPTIONS");
+    if !safe && !session.check_csrf(token, request.headers.get("x-csrf-token")) {
+        return Err("Missing or wrong X-CSRF-Token");
+    }
+    Ok(token)
+}
+
+///Closes the event streams and WebSockets opened with the sessions `ids`,
+///which would go on delivering the user's changes otherwise.
+fn close_streams(events: &Events, websockets: &WebSockets, ids: &[String]) {
+    events.close_sessions(ids);
+    websockets.close_sessions(ids);
+}
+
+fn lookup_session(authority: &str, session: Arc<Sessions>) -> Result<String, &'static str> {
+    session.user_id(authority)
+}
+
+///Reads `from` and `to` as YYYY-MM-DD from a query string.
+fn parse_date_range(query: &HashMap<String, String>) -> Result<(NaiveDate, NaiveDate), String> {
+    let mut range = [NaiveDate::MIN; 2];
+    for (i, key) in ["from", "to"].iter().enumerate() {
+        let value = match query.get(*key) {
+            Some(value) => value,
+            None => return Err(format!("Missing query parameter {key}")),
+        };
+        range[i] = match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(err) => return Err(format!("Invalid {key} '{value}': {err}")),
+        };
+    }
+
+    let [from, to] = range;
+    if to < from {
+        return Err(String::from("to must not be before from"));
+    }
+    if (to - from).num_days() > MAX_AGENDA_DAYS {
+        return Err(format!("Range can not exceed {MAX_AGENDA_DAYS} days"));
+    }
+
+    Ok((from, to))
+}
+
+///The id from the path when the route captured one, otherwise from an `IdCarrier` body.
+fn extract_id(
+    stream: &Responder,
+    request: &Request,
+    params: &HashMap<String, String>,
+) -> Option<IdCarrier> {
+    if let Some(id) = params.get("id") {
+        return Some(IdCarrier { id: id.clone() });
+    }
+
+    let body = extract_body(stream, request)?;
+    match serde_json::from_str::<IdCarrier>(body.as_str()) {
+        Ok(ic) => Some(ic),
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            None
+        }
+    }
+}
+
+///The body as UTF-8, serving 411 when there is none.
+fn extract_body(stream: &Responder, request: &Request) -> Option<String> {
+    if request.body.is_empty() {
+        //A framed but empty body is a bad request, no framing at all needs a length
+        if request.headers.contains("content-length")
+            || request.headers.contains("transfer-encoding")
+        {
+            serve_error_json(stream, HttpError::BadRequest, String::from("Empty body"));
+        } else {
+            serve_error_json(stream, HttpError::LengthRequired, String::new());
+        }
+        return None;
+    }
+
+    match String::from_utf8(request.body.clone()) {
+        Ok(body) => Some(body),
+        Err(err) => {
+            serve_error_json(stream, HttpError::BadRequest, err.to_string());
+            None
+        }
+    }
+}
+
+///Serves 403 or 404 unless `user_id` owns `resource`.
+///Returns whether the request may go on.
+fn check_access(
+    stream: &Responder,
+    sql_connection: &Arc<Mutex<Connection>>,
+    user_id: &str,
+    resource: Resource,
+) -> bool {
+    let access = {
+        let conn = sql_connection.lock().unwrap();
+        authorize(&conn, user_id, &resource)
+    };
+
+    match access {
+        Ok(Access::Granted) => true,
+        Ok(Access::Forbidden) => {
+            serve_error_json(
+                stream,
+                HttpError::Forbidden,
+                format!("The {} {} is not yours", resource.name(), resource.id()),
+            );
+            false
+        }
+        Ok(Access::NotFound) => {
+            serve_error_json(
+                stream,
+                HttpError::NotFound,
+                format!("No {} {}", resource.name(), resource.id()),
+            );
+            false
+        }
+        Err(err) => {
+            serve_error_json(stream, HttpError::InternalServerError, err.to_string());
+            false
+        }
+    }
+}
+
+///Fills in the completions and skips of a task.
+fn load_marks(sql_connection: Arc<Mutex<Connection>>, task: &mut Task) -> rusqlite::Result<()> {
+    task.complete_tasks = query_to_object::<CompleteTask>(
+        sql_connection.clone(),
+        "SELECT * FROM complete_tasks WHERE task_id = ?1;",
+        [&task.id],
+    )?
+    .into_iter()
+    .map(|ct| *ct)
+    .collect();
+    task.skip_tasks = query_to_object::<SkipTask>(
+        sql_connection,
+        "SELECT * FROM skip_tasks WHERE task_id = ?1;",
+        [&task.id],
+    )?
+    .into_iter()
+    .map(|st| *st)
+    .collect();
+
+    Ok(())
+}
+
+///Subtasks of a task with their completions and skips filled in.
+fn load_subtasks(
+    sql_connection: Arc<Mutex<Connection>>,
+    task_id: &str,
+) -> rusqlite::Result<Vec<Subtask>> {
+    let subtasks = query_to_object::<Subtask>(
+        sql_connection.clone(),
+        "SELECT * FROM subtasks WHERE task_id = ?1;",
+        [task_id],
+    )?;
+
+    let mut result = Vec::with_capacity(subtasks.len());
+    for mut subtask in subtasks {
+        subtask.complete_subtasks = query_to_object::<CompleteSubtask>(
+            sql_connection.clone(),
+            "SELECT * FROM complete_subtasks WHERE subtask_id = ?1;",
+            [&subtask.id],
+        )?
+        .into_iter()
+        .map(|cs| *cs)
+        .collect();
+        subtask.skip_subtasks = query_to_object::<SkipSubtask>(
+            sql_connection.clone(),
+            "SELECT * FROM skip_subtasks WHERE subtask_id = ?1;",
+            [&subtask.id],
+        )?
+        .into_iter()
+        .map(|ss| *ss)
+        .collect();
+        result.push(*subtask);
+    }
+
+    Ok(result)
+}
+
+///Runs `sql_query` with `params` bound to its `?N` placeholders.
+fn query_to_object<T: Sql>(
+    sql_connection: Arc<Mutex<Connection>>,
+    sql_query: &str,
+    params: impl Params,
+) -> rusqlite::Result<Vec<Box<T>>> {
+    let mut results = Vec::new();
+
+    {
+        let conn = sql_connection.lock().unwrap();
+        let mut statement = conn.prepare(sql_query)?;
+        //Would totally love to drop the connection mutex before any data conversions,
+        //however, the data from 'query()' does not live long enough rip
+        let query = statement.query_map(params, |row| T::from_sql_row(row))?;
+
+        results.extend(query);
+    }
+
+    let vec_of_boxes = results.into_iter().filter_map(|r| r.ok()).collect();
+
+    Ok(vec_of_boxes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structs::SessionSettings;
+
+    const INJECTION: &str = "'); DROP TABLE tasks; --";
+
+    fn connection() -> Arc<Mutex<Connection>> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../init.sql")).unwrap();
+        Arc::new(Mutex::new(conn))
+    }
+
+    #[test]
+    fn bound_parameters_are_stored_literally() {
+        let sql_connection = connection();
+
+        let mut user = User::from_json(&format!(
+            "{{\"username\":\"{INJECTION}\",\"password\":\"\"}}"
+        ))
+        .unwrap();
+        user.password = String::from("' OR '1'='1");
+        let mut task = Task::from_json(&format!(
+            "{{\"assignDate\":\"2025-01-01\",\"title\":\"{INJECTION}\",\"description\":\"\\\"; --\",\
+            \"recurringMonth\":false,\"recurringN\":0,\"recurringStop\":\"2025-01-01\"}}"
+        ))
+        .unwrap();
+        task.user_id = user.id.clone();
+        {
+            let conn = sql_connection.lock().unwrap();
+            user.insert(&conn).unwrap();
+            task.insert(&conn).unwrap();
+        }
+
+        let users = query_to_object::<User>(
+            sql_connection.clone(),
+            "SELECT * FROM users WHERE username = ?1;",
+            [INJECTION],
+        )
+        .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].username, INJECTION);
+        assert_eq!(users[0].password, "' OR '1'='1");
+
+        //Matches nothing instead of every row
+        let users = query_to_object::<User>(
+            sql_connection.clone(),
+            "SELECT * FROM users WHERE password = ?1;",
+            ["' OR '1'='1' --"],
+        )
+        .unwrap();
+        assert!(users.is_empty());
+
+        let tasks = query_to_object::<Task>(
+            sql_connection.clone(),
+            "SELECT * FROM tasks WHERE user_id = ?1;",
+            [&user.id],
+        )
+        .unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].to_json(), task.to_json());
+        assert!(tasks[0].to_json().contains("DROP TABLE tasks"));
+    }
+
+    fn cookie_sessions() -> Sessions {
+        let sql_connection = connection();
+        sql_connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO users (id, username, password, salt) VALUES ('ann', 'ann', '', 0);",
+                [],
+            )
+            .unwrap();
+        let mut settings = SessionSettings::default();
+        settings.cookie.enabled = true;
+        Sessions::new(sql_connection, &settings).unwrap()
+    }
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{method} /api/task HTTP/1.1\r\nHost: a\r\n{headers}\r\n");
+        crate::http::read_request(&mut raw.as_bytes())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn cookies_need_the_csrf_token_to_change_anything() {
+        let sessions = cookie_sessions();
+        let (token, _) = sessions.create("ann", None, None).unwrap();
+        let csrf = sessions.csrf_token(&token);
+        let cookie = format!("Cookie: theme=dark; session={token}\r\n");
+
+        //Reading needs no token, another site can't see the answer
+        for method in ["GET", "HEAD", "OPTIONS"] {
+            let request = request(method, &cookie);
+            assert_eq!(extract_token(&request, &sessions), Ok(token.as_str()));
+        }
+
+        for method in ["POST", "PATCH", "DELETE"] {
+            let missing = request(method, &cookie);
+            assert_eq!(
+                extract_token(&missing, &sessions),
+                Err("Missing or wrong X-CSRF-Token")
+            );
+            let wrong = request(
+                method,
+                &format!("{cookie}X-CSRF-Token: {}\r\n", sessions.csrf_token("other")),
+            );
+            assert_eq!(
+                extract_token(&wrong, &sessions),
+                Err("Missing or wrong X-CSRF-Token")
+            );
+            let right = request(method, &format!("{cookie}X-CSRF-Token: {csrf}\r\n"));
+            assert_eq!(extract_token(&right, &sessions), Ok(token.as_str()));
+        }
+
+        //An authority header is no cookie a browser sends along by itself
+        let request = request("POST", &format!("Authority: {token}\r\n"));
+        assert_eq!(extract_token(&request, &sessions), Ok(token.as_str()));
+    }
+
+    #[test]
+    fn cookies_are_ignored_unless_enabled() {
+        let sessions = Sessions::new(connection(), &SessionSettings::default()).unwrap();
+        let request = request("GET", "Cookie: session=token\r\n");
+        assert_eq!(
+            extract_token(&request, &sessions),
+            Err("No Authority in header")
+        );
+    }
+
+    fn range(query: &str) -> Result<(NaiveDate, NaiveDate), String> {
+        parse_date_range(&crate::router::parse_query(query))
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn date_ranges() {
+        assert_eq!(
+            range("from=2025-01-01&to=2025-01-31"),
+            Ok((date(2025, 1, 1), date(2025, 1, 31)))
+        );
+        assert_eq!(
+            range("to=2025-01-01&from=2025-01-01"),
+            Ok((date(2025, 1, 1), date(2025, 1, 1)))
+        );
+        assert_eq!(
+            range("from=2024-02-29&to=2024-03-01&extra=1"),
+            Ok((date(2024, 2, 29), date(2024, 3, 1)))
+        );
+        assert_eq!(
+            range("from=2025-01-02&to=2025-01-01"),
+            Err(String::from("to must not be before from"))
+        );
+    }
+
+    #[test]
+    fn malformed_date_ranges() {
+        assert_eq!(
+            range("to=2025-01-01"),
+            Err(String::from("Missing query parameter from"))
+        );
+        assert_eq!(
+            range("from=2025-01-01"),
+            Err(String::from("Missing query parameter to"))
+        );
+        assert_eq!(range(""), Err(String::from("Missing query parameter from")));
+        for query in [
+            "from=2025-13-01&to=2025-12-31",
+            "from=2025-02-29&to=2025-12-31",
+            "from=01/01/2025&to=2025-12-31",
+            "from=2025-01-01T00:00:00&to=2025-12-31",
+            "from=&to=2025-12-31",
+            "from=2025-01-01&to=tomorrow",
+            "from=2025-01-01&to=2025-01-01%27%3B",
+        ] {
+            let err = range(query).unwrap_err();
+            assert!(err.starts_with("Invalid "), "{query}: {err}");
+        }
+    }
+
+    #[test]
+    fn date_ranges_are_limited() {
+        let longest = date(2024, 1, 1) + chrono::Days::new(MAX_AGENDA_DAYS as u64);
+        assert_eq!(
+            range(&format!("from=2024-01-01&to={longest}")),
+            Ok((date(2024, 1, 1), longest))
+        );
+        let too_long = longest + chrono::Days::new(1);
+        assert_eq!(
+            range(&format!("from=2024-01-01&to={too_long}")),
+            Err(format!("Range can not exceed {MAX_AGENDA_DAYS} days"))
+        );
+        assert!(range("from=0001-01-01&to=9999-12-31").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use rusqlite::{Connection, OptionalExtension};
+
+///Anything that hangs off a task and so belongs to the task's user.
+///Each variant carries the id of the row being accessed.
+pub enum Resource<'a> {
+    Task(&'a str),
+    CompleteTask(&'a str),
+    SkipTask(&'a str),
+    Subtask(&'a str),
+    CompleteSubtask(&'a str),
+    SkipSubtask(&'a str),
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Access {
+    Granted,
+    ///The row exists but belongs to someone else.
+    Forbidden,
+    NotFound,
+}
+
+impl Resource<'_> {
+    pub fn id(&self) -> &str {
+        match self {
+            Resource::Task(id)
+            | Resource::CompleteTask(id)
+            | Resource::SkipTask(id)
+            | Resource::Subtask(id)
+            | Resource::CompleteSubtask(id)
+            | Resource::SkipSubtask(id) => id,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resource::Task(_) => "task",
+            Resource::CompleteTask(_) => "completed task",
+            Resource::SkipTask(_) => "skipped task",
+            Resource::Subtask(_) => "subtask",
+            Resource::CompleteSubtask(_) => "completed subtask",
+            Resource::SkipSubtask(_) => "skipped subtask",
+        }
+    }
+
+    ///Selects the owning user id of the row with id ?1.
+    fn owner_query(&self) -> &'static str {
+        match self {
+            Resource::Task(_) => "SELECT user_id FROM tasks WHERE id = ?1;",
+            Resource::CompleteTask(_) => {
+                "SELECT tasks.user_id FROM complete_tasks JOIN tasks ON complete_tasks.task_id = tasks.id WHERE complete_tasks.id = ?1;"
+            }
+            Resource::SkipTask(_) => {
+                "SELECT tasks.user_id FROM skip_tasks JOIN tasks ON skip_tasks.task_id = tasks.id WHERE skip_tasks.id = ?1;"
+            }
+            Resource::Subtask(_) => {
+                "SELECT tasks.user_id FROM subtasks JOIN tasks ON subtasks.task_id = tasks.id WHERE subtasks.id = ?1;"
+            }
+            Resource::CompleteSubtask(_) => {
+                "SELECT tasks.user_id FROM complete_subtasks JOIN subtasks ON complete_subtasks.subtask_id = subtasks.id JOIN tasks ON subtasks.task_id = tasks.id WHERE complete_subtasks.id = ?1;"
+            }
+            Resource::SkipSubtask(_) => {
+                "SELECT tasks.user_id FROM skip_subtasks JOIN subtasks ON skip_subtasks.subtask_id = subtasks.id JOIN tasks ON subtasks.task_id = tasks.id WHERE skip_subtasks.id = ?1;"
+            }
+        }
+    }
+}
+
+pub fn authorize(
+    conn: &Connection,
+    user_id: &str,
+    resource: &Resource,
+) -> rusqlite::Result<Access> {
+    let owner: Option<String> = conn
+        .query_row(resource.owner_query(), [resource.id()], |row| row.get(0))
+        .optional()?;
+
+    Ok(match owner {
+        Some(owner) if owner == user_id => Access::Granted,
+        Some(_) => Access::Forbidden,
+        None => Access::NotFound,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Ann and bob with a task, a subtask and one of each completion and
+    ///skip. Every row of ann's is named "ann-" and of bob's "bob-" followed
+    ///by its table.
+    fn connection() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../init.sql")).unwrap();
+        for user in ["ann", "bob"] {
+            conn.execute_batch(&format!(
+                "INSERT INTO users (id, username, password, salt) VALUES ('{user}', '{user}', '', 0);
+                INSERT INTO tasks (id, assign_date, title, description, recurring_month, recurring_n, recurring_stop, user_id)
+                VALUES ('{user}-task', '2024-01-01', '', '', 0, 0, '2024-01-01', '{user}');
+                INSERT INTO complete_tasks (id, completed, task_id) VALUES ('{user}-complete_task', '2024-01-01', '{user}-task');
+                INSERT INTO skip_tasks (id, completed, task_id) VALUES ('{user}-skip_task', '2024-01-01', '{user}-task');
+                INSERT INTO subtasks (id, description, task_id) VALUES ('{user}-subtask', '', '{user}-task');
+                INSERT INTO complete_subtasks (id, completed, subtask_id) VALUES ('{user}-complete_subtask', '2024-01-01', '{user}-subtask');
+                INSERT INTO skip_subtasks (id, completed, subtask_id) VALUES ('{user}-skip_subtask', '2024-01-01', '{user}-subtask');"
+            ))
+            .unwrap();
+        }
+        conn
+    }
+
+    const TABLES: [&str; 6] = [
+        "task",
+        "complete_task",
+        "skip_task",
+        "subtask",
+        "complete_subtask",
+        "skip_subtask",
+    ];
+
+    ///The resource for row `id` of `table`.
+    fn resource<'a>(table: &str, id: &'a str) -> Resource<'a> {
+        match table {
+            "task" => Resource::Task(id),
+            "complete_task" => Resource::CompleteTask(id),
+            "skip_task" => Resource::SkipTask(id),
+            "subtask" => Resource::Subtask(id),
+            "complete_subtask" => Resource::CompleteSubtask(id),
+            _ => Resource::SkipSubtask(id),
+        }
+    }
+
+    fn access(conn: &Connection, user_id: &str, resource: Resource) -> Access {
+        authorize(conn, user_id, &resource).unwrap()
+    }
+
+    #[test]
+    fn owners_are_granted() {
+        let conn = connection();
+        for table in TABLES {
+            for user in ["ann", "bob"] {
+                let id = format!("{user}-{table}");
+                assert_eq!(
+                    access(&conn, user, resource(table, &id)),
+                    Access::Granted,
+                    "{id}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn other_users_are_forbidden() {
+        let conn = connection();
+        for table in TABLES {
+            let id = format!("ann-{table}");
+            assert_eq!(
+                access(&conn, "bob", resource(table, &id)),
+                Access::Forbidden,
+                "{id}"
+            );
+            assert_eq!(
+                access(&conn, "nobody", resource(table, &id)),
+                Access::Forbidden,
+                "{id}"
+            );
+        }
+    }
+
+    #[test]
+    fn rows_of_other_tables_are_not_found() {
+        let conn = connection();
+        for table in TABLES {
+            for other in TABLES {
+                let id = format!("ann-{other}");
+                let expected = if other == table {
+                    Access::Granted
+                } else {
+                    Access::NotFound
+                };
+                assert_eq!(
+                    access(&conn, "ann", resource(table, &id)),
+                    expected,
+                    "{table} {id}"
+                );
+            }
+            assert_eq!(
+                access(&conn, "ann", resource(table, "missing")),
+                Access::NotFound
+            );
+            assert_eq!(access(&conn, "ann", resource(table, "")), Access::NotFound);
+        }
+    }
+
+    #[test]
+    fn ids_are_not_sql() {
+        let conn = connection();
+        for table in TABLES {
+            let id = "' OR '1'='1";
+            assert_eq!(access(&conn, "ann", resource(table, id)), Access::NotFound);
+        }
+    }
+
+    #[test]
+    fn deleting_the_task_takes_everything_along() {
+        let conn = connection();
+        conn.execute_batch("PRAGMA foreign_keys = ON; DELETE FROM tasks WHERE id = 'ann-task';")
+            .unwrap();
+        for table in TABLES {
+            let id = format!("ann-{table}");
+            assert_eq!(
+                access(&conn, "ann", resource(table, &id)),
+                Access::NotFound,
+                "{id}"
+            );
+            let id = format!("bob-{table}");
+            assert_eq!(
+                access(&conn, "bob", resource(table, &id)),
+                Access::Granted,
+                "{id}"
+            );
+        }
+    }
+}
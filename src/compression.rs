@@ -0,0 +1,177 @@
+use std::io::{self, Write};
+
+use brotli::CompressorWriter;
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+///Brotli at its default quality of 11 is far too slow to run per request.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER: usize = 4096;
+
+///Content codings the server can produce, most preferred first.
+pub const ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Encoding {
+    ///Name of the coding in Accept-Encoding and Content-Encoding.
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    ///File name suffix of a precompressed sibling, if the coding has one.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Gzip => Some(".gz"),
+            Encoding::Brotli => Some(".br"),
+            Encoding::Identity | Encoding::Deflate => None,
+        }
+    }
+}
+
+///Picks the coding out of `available` the client rates highest in its
+///Accept-Encoding header, earlier entries winning ties.
+///Identity when the client accepts none of them.
+pub fn negotiate(accept: Option<&str>, available: &[Encoding]) -> Encoding {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return Encoding::Identity,
+    };
+
+    let mut ratings: Vec<(&str, f32)> = Vec::new();
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut q = 1.0;
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value.trim().parse().unwrap_or(0.0);
+                }
+            }
+        }
+        ratings.push((coding, q));
+    }
+
+    let rating = |encoding: &Encoding| {
+        let named = ratings
+            .iter()
+            .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.token()));
+        let wildcard = ratings.iter().find(|(coding, _)| *coding == "*");
+        match named.or(wildcard) {
+            Some((_, q)) => *q,
+            None => 0.0,
+        }
+    };
+
+    let mut best = Encoding::Identity;
+    let mut best_q = 0.0;
+    for encoding in available {
+        let q = rating(encoding);
+        if q > best_q {
+            best = *encoding;
+            best_q = q;
+        }
+    }
+    best
+}
+
+///Whether a response of this MIME type is worth compressing.
+///Images, video and archives are compressed already.
+pub fn is_compressible(mime: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime,
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+///A writer compressing everything written to it into `W`.
+pub enum Encoder<W: Write> {
+    Identity(W),
+    Gzip(GzEncoder<W>),
+    Deflate(ZlibEncoder<W>),
+    Brotli(Box<CompressorWriter<W>>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W, encoding: Encoding) -> Encoder<W> {
+        match encoding {
+            Encoding::Identity => Encoder::Identity(inner),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(inner, Compression::default())),
+            //"deflate" in HTTP means the zlib format, not a raw deflate stream
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(inner, Compression::default())),
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                inner,
+                BROTLI_BUFFER,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+        }
+    }
+
+    ///Writes whatever the compressor still holds and hands back the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Identity(inner) => Ok(inner),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => {
+                let mut inner = encoder.into_inner();
+                inner.flush()?;
+                Ok(inner)
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Identity(inner) => inner.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Identity(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+///Compresses all of `data` at once.
+pub fn compress(data: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    let mut encoder = Encoder::new(Vec::with_capacity(data.len() / 2), encoding);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
@@ -0,0 +1,682 @@
+use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
+use rustls::ServerConnection;
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::{IpAddr, TcpStream},
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::compression::{self, Encoder, Encoding};
+use crate::http::Version;
+use crate::http2;
+
+///A client's socket, with TLS on top when the server has it configured.
+///Reading and writing both go through `&Stream`, so the reader of a
+///`Client` and a `Responder` can share it.
+pub struct Stream {
+    socket: TcpStream,
+    tls: Option<Mutex<ServerConnection>>,
+}
+
+impl Stream {
+    pub fn plain(socket: TcpStream) -> Stream {
+        Stream { socket, tls: None }
+    }
+
+    ///The handshake happens on the first read.
+    pub fn tls(socket: TcpStream, connection: ServerConnection) -> Stream {
+        Stream {
+            socket,
+            tls: Some(Mutex::new(connection)),
+        }
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.socket.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    ///The protocol agreed on through ALPN during the TLS handshake.
+    pub fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        let connection = self.tls.as_ref()?.lock().unwrap();
+        connection.alpn_protocol().map(|protocol| protocol.to_vec())
+    }
+
+    ///Whether TLS decrypted more than has been read so far. Such bytes
+    ///are past the socket already, so waiting on it for them would hang.
+    pub fn has_buffered(&self) -> bool {
+        match &self.tls {
+            Some(connection) => connection
+                .lock()
+                .unwrap()
+                .process_new_packets()
+                .is_ok_and(|state| state.plaintext_bytes_to_read() > 0),
+            None => false,
+        }
+    }
+
+    ///Writes what the socket takes of `buf` right away, for a nonblocking
+    ///socket. TLS records the socket had no room for are kept and go out
+    ///first on the next call, see `wants_write`.
+    pub fn write_nonblocking(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut socket = &self.socket;
+        let connection = match &self.tls {
+            Some(connection) => connection,
+            None => return socket.write(buf),
+        };
+        let mut connection = connection.lock().unwrap();
+        while connection.wants_write() {
+            connection.write_tls(&mut socket)?;
+        }
+        let written = connection.writer().write(buf)?;
+        //The plaintext is taken already, failing now would have it sent twice
+        while connection.wants_write() {
+            match connection.write_tls(&mut socket) {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    ///Whether TLS records are waiting for `write_nonblocking`.
+    pub fn wants_write(&self) -> bool {
+        self.tls
+            .as_ref()
+            .is_some_and(|connection| connection.lock().unwrap().wants_write())
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut socket = &self.socket;
+        match &self.tls {
+            Some(connection) => {
+                let mut connection = connection.lock().unwrap();
+                rustls::Stream::new(&mut *connection, &mut socket).read(buf)
+            }
+            None => socket.read(buf),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut socket = &self.socket;
+        match &self.tls {
+            Some(connection) => {
+                let mut connection = connection.lock().unwrap();
+                let written = connection.writer().write(buf)?;
+                while connection.wants_write() {
+                    connection.write_tls(&mut socket)?;
+                }
+                Ok(written)
+            }
+            None => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut socket = &self.socket;
+        socket.flush()
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        //Tell the client the connection ends on purpose, not truncated by an attacker
+        if let Some(connection) = &self.tls {
+            if let Ok(mut connection) = connection.lock() {
+                connection.send_close_notify();
+                let _ = connection.write_tls(&mut &self.socket);
+            }
+        }
+    }
+}
+
+///Bytes waiting for a nonblocking socket to take them, for connections
+///that are written to from a thread shared with others.
+#[derive(Default)]
+pub struct Outgoing {
+    buffer: Vec<u8>,
+    ///Since when the client took none of what is waiting.
+    since: Option<Instant>,
+}
+
+impl Outgoing {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    ///When the client last took something while more is waiting, None
+    ///once everything is written.
+    pub fn stuck_since(&self) -> Option<Instant> {
+        self.since
+    }
+
+    ///Writes what `stream` takes without blocking.
+    pub fn flush(&mut self, stream: &Stream) -> io::Result<()> {
+        while !self.buffer.is_empty() || stream.wants_write() {
+            match stream.write_nonblocking(&self.buffer) {
+                Ok(0) if !self.buffer.is_empty() => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.buffer.drain(..n);
+                    self.since = Some(Instant::now());
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        self.since = None;
+        Ok(())
+    }
+}
+
+///A connection between requests. The reader is kept so that bytes of a
+///pipelined request that were already buffered are not lost.
+pub struct Client {
+    pub reader: BufReader<Stream>,
+    pub served: usize,
+    ///Set once the client switched to HTTP/2.
+    pub http2: Option<Box<http2::Connection>>,
+}
+
+impl Client {
+    pub fn new(stream: Stream) -> Client {
+        Client {
+            reader: BufReader::new(stream),
+            served: 0,
+            http2: None,
+        }
+    }
+
+    ///Whether the next request was already received.
+    pub fn has_buffered(&self) -> bool {
+        !self.reader.buffer().is_empty() || self.reader.get_ref().has_buffered()
+    }
+}
+
+///Where responses are written. Knows whether the connection stays open
+///after this response so every response head can say so, and which
+///content codings the client accepts.
+pub struct Responder<'a> {
+    output: Output<'a>,
+    version: Version,
+    keep_alive: Cell<bool>,
+    accept_encoding: Option<&'a str>,
+    compression_threshold: usize,
+    ///Header lines every response carries.
+    headers: String,
+    discard_body: bool,
+    ///How much of the blank line ending the head has been written so far.
+    head_end: Cell<usize>,
+    ///Where the connection goes after the response, see `hand_over`.
+    hand_over: Cell<Option<HandOver>>,
+}
+
+type HandOver = Box<dyn FnOnce(Client)>;
+
+enum Output<'a> {
+    Stream(&'a Stream),
+    Sink(&'a dyn Sink),
+}
+
+///Takes responses for a protocol other than HTTP/1.x, written to it the
+///same as to a socket, and takes care of translating them.
+pub trait Sink {
+    fn send(&self, buf: &[u8]) -> io::Result<()>;
+    fn flush(&self) -> io::Result<()>;
+}
+
+impl<'a> Responder<'a> {
+    pub fn new(stream: &Stream, version: Version, keep_alive: bool) -> Responder<'_> {
+        Responder {
+            output: Output::Stream(stream),
+            version,
+            keep_alive: Cell::new(keep_alive),
+            accept_encoding: None,
+            compression_threshold: usize::MAX,
+            headers: String::new(),
+            discard_body: false,
+            head_end: Cell::new(0),
+            hand_over: Cell::new(None),
+        }
+    }
+
+    ///A responder writing to `sink` rather than a socket.
+    pub fn with_sink(sink: &'a dyn Sink, version: Version) -> Responder<'a> {
+        Responder {
+            output: Output::Sink(sink),
+            version,
+            keep_alive: Cell::new(true),
+            accept_encoding: None,
+            compression_threshold: usize::MAX,
+            headers: String::new(),
+            discard_body: false,
+            head_end: Cell::new(0),
+            hand_over: Cell::new(None),
+        }
+    }
+
+    ///Adds `headers`, lines each ending in \r\n, to every response.
+    pub fn with_headers(mut self, headers: String) -> Responder<'a> {
+        self.headers = headers;
+        self
+    }
+
+    ///Sends only the head of what is written, for HEAD requests. Handlers
+    ///write responses the same as for GET, with the real Content-Length.
+    pub fn discard_body(mut self, discard: bool) -> Responder<'a> {
+        self.discard_body = discard;
+        self
+    }
+
+    ///Lets responses be compressed for a client sending `accept_encoding`.
+    ///Bodies shorter than `threshold` bytes are sent as they are.
+    pub fn with_compression(
+        mut self,
+        accept_encoding: Option<&'a str>,
+        threshold: usize,
+    ) -> Responder<'a> {
+        self.accept_encoding = accept_encoding;
+        self.compression_threshold = threshold;
+        self
+    }
+
+    ///Header lines every response head starts with. Announces when the
+    ///connection closes and carries those given to `with_headers`.
+    ///HTTP/1.0 clients expect a close unless told the connection stays open.
+    pub fn common_headers(&self) -> String {
+        if !self.keep_alive.get() {
+            format!("Connection: close\r\n{}", self.headers)
+        } else if self.version == Version::Http10 {
+            format!("Connection: keep-alive\r\n{}", self.headers)
+        } else {
+            self.headers.clone()
+        }
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive.get()
+    }
+
+    ///Closes the connection after this response, for responses that broke
+    ///off halfway and left the client unable to find where the next one starts.
+    pub fn abort(&self) {
+        self.keep_alive.set(false);
+    }
+
+    ///Takes the connection out of request handling once the handler returns
+    ///and passes it to `take`, for responses that go on indefinitely.
+    ///Only HTTP/1.x connections can be handed over.
+    pub fn hand_over(&self, take: impl FnOnce(Client) + 'static) {
+        self.abort();
+        self.hand_over.set(Some(Box::new(take)));
+    }
+
+    pub fn take_hand_over(&self) -> Option<HandOver> {
+        self.hand_over.take()
+    }
+
+    ///The coding out of `available` the client prefers.
+    pub fn negotiate(&self, available: &[Encoding]) -> Encoding {
+        compression::negotiate(self.accept_encoding, available)
+    }
+
+    ///The coding a body of `mime` type and `len` bytes should be sent with.
+    ///None when the type is never compressed, so the response doesn't vary
+    ///with Accept-Encoding at all.
+    pub fn encoding_for(&self, mime: &str, len: usize) -> Option<Encoding> {
+        if !compression::is_compressible(mime) {
+            return None;
+        }
+        if len < self.compression_threshold {
+            return Some(Encoding::Identity);
+        }
+        Some(self.negotiate(&compression::ENCODINGS))
+    }
+
+    ///Compresses `body` when its type and size make it worthwhile and the
+    ///client accepts it. Returns the bytes to send together with the header
+    ///lines describing them.
+    pub fn encode<'b>(&self, mime: &str, body: &'b [u8]) -> (Cow<'b, [u8]>, String) {
+        let encoding = match self.encoding_for(mime, body.len()) {
+            Some(encoding) => encoding,
+            None => return (Cow::Borrowed(body), String::new()),
+        };
+        //The response depends on Accept-Encoding even when it ends up uncompressed
+        let mut headers = String::from("Vary: Accept-Encoding\r\n");
+        if encoding == Encoding::Identity {
+            return (Cow::Borrowed(body), headers);
+        }
+
+        match compression::compress(body, encoding) {
+            Ok(compressed) => {
+                headers.push_str(&format!("Content-Encoding: {}\r\n", encoding.token()));
+                (Cow::Owned(compressed), headers)
+            }
+            Err(err) => {
+                println!("Could not compress response");
+                println!("{err}");
+                (Cow::Borrowed(body), headers)
+            }
+        }
+    }
+
+    ///Writes a response head without a Content-Length and returns a writer
+    ///for the body, so it can be sent while it is still being produced.
+    ///HTTP/1.0 clients don't know chunks and get the body unframed, the
+    ///connection closing marks its end. HTTP/2 frames bodies itself.
+    pub fn chunked(&self, status: &str, content_type: &str) -> io::Result<Chunked<'_>> {
+        let framed = self.version == Version::Http11;
+        if self.version == Version::Http10 {
+            self.abort();
+        }
+
+        let mut head = format!(
+            "HTTP/1.1 {status}\r\n{}Content-Type: {content_type}\r\n",
+            self.common_headers()
+        );
+        //The length is unknown up front so the threshold can't apply
+        let mut encoding = Encoding::Identity;
+        if compression::is_compressible(content_type) {
+            head.push_str("Vary: Accept-Encoding\r\n");
+            if self.compression_threshold != usize::MAX {
+                encoding = self.negotiate(&compression::ENCODINGS);
+            }
+        }
+        if encoding != Encoding::Identity {
+            head.push_str(&format!("Content-Encoding: {}\r\n", encoding.token()));
+        }
+        if framed {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+        head.push_str("\r\n");
+
+        let mut stream = self;
+        stream.write_all(head.as_bytes())?;
+        Ok(Chunked {
+            body: Encoder::new(
+                Frames {
+                    responder: self,
+                    framed,
+                },
+                encoding,
+            ),
+        })
+    }
+}
+
+impl Responder<'_> {
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        match self.output {
+            Output::Stream(mut stream) => stream.write_all(buf),
+            Output::Sink(sink) => sink.send(buf),
+        }
+    }
+}
+
+impl Write for &Responder<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.discard_body {
+            self.send(buf)?;
+            return Ok(buf.len());
+        }
+
+        //Let the head through and swallow everything after the blank line ending it
+        const HEAD_END: &[u8] = b"\r\n\r\n";
+        let mut matched = self.head_end.get();
+        if matched == HEAD_END.len() {
+            return Ok(buf.len());
+        }
+        let mut head = buf.len();
+        for (i, byte) in buf.iter().enumerate() {
+            matched = match *byte {
+                byte if byte == HEAD_END[matched] => matched + 1,
+                b'\r' => 1,
+                _ => 0,
+            };
+            if matched == HEAD_END.len() {
+                head = i + 1;
+                break;
+            }
+        }
+        self.send(&buf[..head])?;
+        self.head_end.set(matched);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.output {
+            Output::Stream(mut stream) => stream.flush(),
+            Output::Sink(sink) => sink.flush(),
+        }
+    }
+}
+
+///Body writer returned by `Responder::chunked`. Writes are compressed when
+///the client accepts it and otherwise become one chunk each, so callers
+///should write whole pieces rather than single bytes.
+///The body is only complete once `finish` is called.
+pub struct Chunked<'a> {
+    body: Encoder<Frames<'a>>,
+}
+
+impl Chunked<'_> {
+    ///Flushes the compressor and sends the last chunk.
+    pub fn finish(self) -> io::Result<()> {
+        let frames = self.body.finish()?;
+        if frames.framed {
+            let mut stream = frames.responder;
+            stream.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Chunked<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.body.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.body.flush()
+    }
+}
+
+///Frames everything written to it as chunks.
+struct Frames<'a> {
+    responder: &'a Responder<'a>,
+    framed: bool,
+}
+
+impl Write for Frames<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        //An empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut stream = self.responder;
+        if self.framed {
+            let mut chunk = Vec::with_capacity(buf.len() + 12);
+            chunk.extend_from_slice(format!("{:X}\r\n", buf.len()).as_bytes());
+            chunk.extend_from_slice(buf);
+            chunk.extend_from_slice(b"\r\n");
+            stream.write_all(&chunk)?;
+        } else {
+            stream.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut stream = self.responder;
+        stream.flush()
+    }
+}
+
+///Hands idle connections to the watcher thread started by `watch_idle`.
+#[derive(Clone)]
+pub struct Parking {
+    sender: Sender<(Client, Instant)>,
+    waker: Arc<Waker>,
+}
+
+///The receiving end of `Parking`.
+pub struct ParkingLot {
+    receiver: Receiver<(Client, Instant)>,
+    poll: Poll,
+}
+
+///Token the watcher is woken with when a connection gets parked.
+const WAKE: Token = Token(usize::MAX);
+
+pub fn parking() -> io::Result<(Parking, ParkingLot)> {
+    let (sender, receiver) = mpsc::channel();
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKE)?);
+    Ok((Parking { sender, waker }, ParkingLot { receiver, poll }))
+}
+
+impl Parking {
+    pub fn park(&self, client: Client) {
+        if let Err(err) = client.reader.get_ref().socket().set_nonblocking(true) {
+            println!("Could not park connection");
+            println!("{err}");
+            return;
+        }
+        //Only fails once the watcher is gone, dropping the client closes it
+        if self.sender.send((client, Instant::now())).is_ok() {
+            if let Err(err) = self.waker.wake() {
+                println!("Could not wake the idle watcher");
+                println!("{err}");
+            }
+        }
+    }
+}
+
+///Starts a thread holding parked connections so they don't occupy a worker
+///while waiting. As soon as a client sends something it is passed to `wake`,
+///clients quiet for longer than `timeout` are closed.
+pub fn watch_idle<F>(lot: ParkingLot, timeout: Duration, wake: F)
+where
+    F: Fn(Client) + Send + 'static,
+{
+    thread::spawn(move || idle_loop(lot, timeout, wake));
+}
+
+///Sleeps until a parked socket turns readable or the oldest one times out.
+fn idle_loop<F: Fn(Client)>(lot: ParkingLot, timeout: Duration, wake: F) {
+    let ParkingLot { receiver, mut poll } = lot;
+    let mut idle: HashMap<Token, (Client, Instant)> = HashMap::new();
+    let mut events = Events::with_capacity(256);
+    let mut next_token = 0;
+
+    loop {
+        let wait = idle
+            .values()
+            .map(|(_, since)| (*since + timeout).saturating_duration_since(Instant::now()))
+            .min();
+        if let Err(err) = poll.poll(&mut events, wait) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                println!("Could not wait for parked connections");
+                println!("{err}");
+                return;
+            }
+            continue;
+        }
+
+        for event in events.iter() {
+            if event.token() == WAKE {
+                for parked in receiver.try_iter() {
+                    let token = Token(next_token);
+                    next_token = (next_token + 1) % WAKE.0;
+                    let fd = parked.0.reader.get_ref().socket().as_raw_fd();
+                    //Data that arrived before this is reported by the next poll
+                    match poll
+                        .registry()
+                        .register(&mut SourceFd(&fd), token, Interest::READABLE)
+                    {
+                        Ok(_) => {
+                            idle.insert(token, parked);
+                        }
+                        Err(err) => {
+                            println!("Could not park connection");
+                            println!("{err}");
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let Some((client, _)) = idle.get(&event.token()) else {
+                continue;
+            };
+            let stream = client.reader.get_ref().socket();
+            let ready = match stream.peek(&mut [0; 1]) {
+                Ok(0) => false,
+                Ok(_) => true,
+                //Nothing there after all, keep waiting
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(_) => false,
+            };
+
+            let (client, _) = idle.remove(&event.token()).unwrap();
+            unpark(&poll, &client);
+            //Otherwise the peer hung up and dropping the client closes it
+            if ready {
+                match client.reader.get_ref().socket().set_nonblocking(false) {
+                    Ok(_) => wake(client),
+                    Err(err) => println!("{err}"),
+                }
+            }
+        }
+
+        idle.retain(|_, (client, since)| {
+            let waiting = since.elapsed() < timeout;
+            if !waiting {
+                unpark(&poll, client);
+            }
+            waiting
+        });
+    }
+}
+
+fn unpark(poll: &Poll, client: &Client) {
+    let fd = client.reader.get_ref().socket().as_raw_fd();
+    let _ = poll.registry().deregister(&mut SourceFd(&fd));
+}
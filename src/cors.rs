@@ -0,0 +1,223 @@
+use crate::data_structs::CorsSettings;
+use crate::http::Request;
+
+///Refuses settings letting any site read responses meant for a logged in
+///user: credentials only go along to origins listed one by one.
+pub fn check(settings: &CorsSettings) -> Result<(), String> {
+    if settings.allow_credentials
+        && settings
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*")
+    {
+        return Err(String::from(
+            "allow_credentials can't be combined with the origin \"*\", list the origins instead",
+        ));
+    }
+    Ok(())
+}
+
+///The value for Access-Control-Allow-Origin, None when the request is not
+///from an allowed origin.
+fn allowed_origin<'a>(settings: &CorsSettings, request: &'a Request) -> Option<&'a str> {
+    let origin = request.headers.get("origin")?;
+    //Never reflected, browsers don't send credentials along to a literal *
+    if settings
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*")
+    {
+        return Some("*");
+    }
+
+    settings
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+///Whether `request` comes from the server's own site or an origin allowed
+///to send credentials. Requests without an Origin aren't from a browser
+///page another site could have made.
+pub fn trusted_origin(settings: &CorsSettings, request: &Request) -> bool {
+    let origin = match request.headers.get("origin") {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let host = origin.split_once("://").map_or(origin, |(_, host)| host);
+    if request
+        .headers
+        .get("host")
+        .is_some_and(|own| own.eq_ignore_ascii_case(host))
+    {
+        return true;
+    }
+    settings.allow_credentials
+        && settings
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+}
+
+///Header lines letting the origin of `request` read the response.
+pub fn headers(settings: &CorsSettings, request: &Request) -> String {
+    if settings.allowed_origins.is_empty() {
+        return String::new();
+    }
+
+    //Caches must not hand a response allowed for one origin to another
+    let mut headers = String::from("Vary: Origin\r\n");
+    if let Some(origin) = allowed_origin(settings, request) {
+        headers.push_str(&format!("Access-Control-Allow-Origin: {origin}\r\n"));
+        if settings.allow_credentials && origin != "*" {
+            headers.push_str("Access-Control-Allow-Credentials: true\r\n");
+        }
+    }
+    headers
+}
+
+///Header lines answering a preflight, on top of those from `headers`.
+///Empty unless `request` is a preflight from an allowed origin.
+pub fn preflight_headers(settings: &CorsSettings, request: &Request, methods: &[&str]) -> String {
+    if !request.headers.contains("access-control-request-method")
+        || allowed_origin(settings, request).is_none()
+    {
+        return String::new();
+    }
+
+    format!(
+        "Access-Control-Allow-Methods: {}\r\nAccess-Control-Allow-Headers: {}\r\nAccess-Control-Max-Age: {}\r\n",
+        methods.join(", "),
+        settings.allowed_headers.join(", "),
+        settings.max_age
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::read_request;
+
+    fn settings(origins: &[&str], allow_credentials: bool) -> CorsSettings {
+        CorsSettings {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allow_credentials,
+            ..CorsSettings::default()
+        }
+    }
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{method} /api/task HTTP/1.1\r\nHost: api.test\r\n{headers}\r\n");
+        read_request(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn headers_for(settings: &CorsSettings, request_headers: &str) -> String {
+        headers(settings, &request("GET", request_headers))
+    }
+
+    #[test]
+    fn exact_match() {
+        let settings = settings(&["http://app.test"], false);
+        let headers = headers_for(&settings, "Origin: http://APP.test\r\n");
+        assert!(headers.contains("Access-Control-Allow-Origin: http://APP.test\r\n"));
+        assert!(headers.contains("Vary: Origin\r\n"));
+
+        let headers = headers_for(&settings, "Origin: http://evil.test\r\n");
+        assert_eq!(headers, "Vary: Origin\r\n");
+        assert_eq!(headers_for(&settings, ""), "Vary: Origin\r\n");
+
+        //Nothing at all while cross origin requests are off
+        assert_eq!(
+            headers_for(&CorsSettings::default(), "Origin: http://app.test\r\n"),
+            ""
+        );
+    }
+
+    #[test]
+    fn wildcard_is_never_reflected() {
+        let settings = settings(&["*"], false);
+        let headers = headers_for(&settings, "Origin: http://any.test\r\n");
+        assert!(headers.contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(!headers.contains("Credentials"));
+
+        //Refused when loading, and without credentials should it get through anyway
+        let settings = self::settings(&["*"], true);
+        assert!(check(&settings).is_err());
+        let headers = headers_for(&settings, "Origin: http://any.test\r\n");
+        assert!(headers.contains("Access-Control-Allow-Origin: *\r\n"));
+        assert!(!headers.contains("Credentials"));
+    }
+
+    #[test]
+    fn credentials() {
+        let settings = settings(&["http://app.test"], true);
+        assert!(check(&settings).is_ok());
+        let headers = headers_for(&settings, "Origin: http://app.test\r\n");
+        assert!(headers.contains("Access-Control-Allow-Origin: http://app.test\r\n"));
+        assert!(headers.contains("Access-Control-Allow-Credentials: true\r\n"));
+
+        let headers = headers_for(&settings, "Origin: http://evil.test\r\n");
+        assert!(!headers.contains("Credentials"));
+    }
+
+    #[test]
+    fn preflight() {
+        let settings = settings(&["http://app.test"], false);
+        let methods = ["GET", "POST"];
+        let answer = preflight_headers(
+            &settings,
+            &request(
+                "OPTIONS",
+                "Origin: http://app.test\r\nAccess-Control-Request-Method: POST\r\n",
+            ),
+            &methods,
+        );
+        assert!(answer.contains("Access-Control-Allow-Methods: GET, POST\r\n"));
+        assert!(answer.contains(&format!(
+            "Access-Control-Allow-Headers: {}\r\n",
+            settings.allowed_headers.join(", ")
+        )));
+        assert!(answer.contains(&format!("Access-Control-Max-Age: {}\r\n", settings.max_age)));
+
+        //Not a preflight, or from an origin not allowed
+        let plain = request("OPTIONS", "Origin: http://app.test\r\n");
+        assert_eq!(preflight_headers(&settings, &plain, &methods), "");
+        let other = request(
+            "OPTIONS",
+            "Origin: http://evil.test\r\nAccess-Control-Request-Method: POST\r\n",
+        );
+        assert_eq!(preflight_headers(&settings, &other, &methods), "");
+    }
+
+    #[test]
+    fn trusted_origins() {
+        let settings = settings(&["http://app.test"], true);
+        //Not from a browser page, or from the server's own site
+        assert!(trusted_origin(&settings, &request("GET", "")));
+        assert!(trusted_origin(
+            &settings,
+            &request("GET", "Origin: https://api.test\r\n")
+        ));
+        assert!(trusted_origin(
+            &settings,
+            &request("GET", "Origin: http://app.test\r\n")
+        ));
+        assert!(!trusted_origin(
+            &settings,
+            &request("GET", "Origin: http://evil.test\r\n")
+        ));
+
+        //Allowed origins are only trusted with credentials
+        let settings = self::settings(&["http://app.test"], false);
+        assert!(!trusted_origin(
+            &settings,
+            &request("GET", "Origin: http://app.test\r\n")
+        ));
+        let settings = self::settings(&["*"], false);
+        assert!(!trusted_origin(
+            &settings,
+            &request("GET", "Origin: http://evil.test\r\n")
+        ));
+    }
+}
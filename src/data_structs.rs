@@ -1,15 +1,55 @@
 use chrono::{DateTime, NaiveDate, Utc};
-use rand::random;
-use rusqlite;
+use rusqlite::{types::ToSql, Connection};
 use serde::{Deserialize, Serialize};
-use serde_json;
 use uuid::Uuid;
 
+use crate::recurrence::{self, Recurrence};
+
 pub trait Sql {
-    fn to_sql_insert(&self) -> String;
+    const TABLE: &'static str;
+    ///Columns written by `insert`, in the same order as `sql_params`.
+    const COLUMNS: &'static [&'static str];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql>;
     fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error>;
     fn to_json(&self) -> String;
     fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error>;
+
+    ///Inserts self with every value bound as a parameter, never spliced into the statement.
+    fn insert(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let placeholders: Vec<String> =
+            (1..=Self::COLUMNS.len()).map(|i| format!("?{i}")).collect();
+        conn.execute(
+            format!(
+                "INSERT INTO {} ({}) VALUES ({});",
+                Self::TABLE,
+                Self::COLUMNS.join(", "),
+                placeholders.join(", ")
+            )
+            .as_str(),
+            self.sql_params().as_slice(),
+        )
+    }
+
+    ///Writes every column except the leading id column over the row with the same id.
+    fn update(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let assignments: Vec<String> = Self::COLUMNS
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(i, column)| format!("{column} = ?{}", i + 1))
+            .collect();
+        conn.execute(
+            format!(
+                "UPDATE {} SET {} WHERE {} = ?1;",
+                Self::TABLE,
+                assignments.join(", "),
+                Self::COLUMNS[0]
+            )
+            .as_str(),
+            self.sql_params().as_slice(),
+        )
+    }
 }
 
 #[derive(Serialize)]
@@ -26,12 +66,279 @@ pub struct Settings {
     pub bind_port: String,
     pub n_threads: usize,
     pub data_path: String,
+    ///Seconds an idle keep-alive connection is held open.
+    #[serde(default = "default_keep_alive_timeout")]
+    pub keep_alive_timeout: u64,
+    #[serde(default = "default_max_requests_per_connection")]
+    pub max_requests_per_connection: usize,
+    ///Bodies shorter than this many bytes are sent uncompressed.
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: usize,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    ///Answer unknown paths without a file extension with index.html,
+    ///so client side routes of a single page app can be linked to.
+    #[serde(default)]
+    pub spa_fallback: bool,
+    #[serde(default)]
+    pub cors: CorsSettings,
+    #[serde(default)]
+    pub events: EventSettings,
+    #[serde(default)]
+    pub websocket: WebSocketSettings,
+    #[serde(default)]
+    pub password: PasswordSettings,
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    ///Offer HTTP/2 to TLS clients, which pick it during the handshake.
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    ///Accept HTTP/2 without TLS from clients that open with its preface
+    ///right away, knowing the server speaks it.
+    #[serde(default)]
+    pub h2c: bool,
+    ///Serves HTTPS on bind_port when present.
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TlsSettings {
+    ///The first certificate also goes to clients asking for a name none of them is for.
+    pub certificates: Vec<CertificateSettings>,
+    ///Port answering plain HTTP with a redirect to HTTPS.
+    #[serde(default)]
+    pub redirect_port: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CertificateSettings {
+    ///PEM file with the certificate followed by its intermediates.
+    pub cert_chain: String,
+    ///PEM file with the private key.
+    pub key: String,
+    ///Host names the certificate is sent for, "*.example.com" covers one label.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+///Which other origins may call the API from a browser.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct CorsSettings {
+    ///Origins such as "http://localhost:5173", or "*" for any.
+    ///Cross origin requests are refused while this is empty.
+    pub allowed_origins: Vec<String>,
+    ///Request headers a cross origin request may set.
+    pub allowed_headers: Vec<String>,
+    ///Lets browsers send cookies and HTTP authentication along.
+    pub allow_credentials: bool,
+    ///Seconds a browser may reuse a preflight response.
+    pub max_age: u64,
+}
+
+impl Default for CorsSettings {
+    fn default() -> CorsSettings {
+        CorsSettings {
+            allowed_origins: Vec::new(),
+            allowed_headers: vec![
+                String::from("authority"),
+                String::from("content-type"),
+                String::from("x-csrf-token"),
+            ],
+            allow_credentials: false,
+            max_age: 600,
+        }
+    }
+}
+
+///The stream of changes at /api/events.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct EventSettings {
+    ///Seconds a stream may go without events before a heartbeat is sent.
+    pub heartbeat_interval: u64,
+    ///Events kept across all users for clients reconnecting with Last-Event-ID.
+    pub backlog: usize,
+}
+
+impl Default for EventSettings {
+    fn default() -> EventSettings {
+        EventSettings {
+            heartbeat_interval: 15,
+            backlog: 1000,
+        }
+    }
+}
+
+///The WebSockets at /api/ws.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct WebSocketSettings {
+    ///Seconds a socket may be quiet before it is pinged. One not heard
+    ///from for twice as long is closed.
+    pub ping_interval: u64,
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> WebSocketSettings {
+        WebSocketSettings { ping_interval: 30 }
+    }
+}
+
+///Argon2id costs for hashing passwords. Changing them rehashes each
+///password on its user's next login.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct PasswordSettings {
+    ///KiB of memory per hash.
+    pub memory_cost: u32,
+    ///Passes over that memory.
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordSettings {
+    fn default() -> PasswordSettings {
+        PasswordSettings {
+            memory_cost: 19456,
+            time_cost: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+///Logged in sessions.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct SessionSettings {
+    ///Seconds an authority may go unused before it stops working.
+    pub idle_lifetime: u64,
+    ///Seconds after logging in a session ends however much it is used.
+    ///Until then its refresh token gets a new authority.
+    pub absolute_lifetime: u64,
+    ///Seconds between purges of expired sessions.
+    pub sweep_interval: u64,
+    pub cookie: CookieSettings,
+}
+
+///Sessions kept in a cookie rather than handed to scripts, for clients
+///logging in with "cookie": true.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct CookieSettings {
+    ///Whether clients may ask for a cookie at all. Cookies need the CSRF
+    ///token on every request changing something.
+    pub enabled: bool,
+    ///The refresh token goes in a cookie named this plus "_refresh".
+    pub name: String,
+    ///Only send the cookie over HTTPS.
+    pub secure: bool,
+    ///"Strict" or "Lax".
+    pub same_site: String,
+}
+
+impl Default for CookieSettings {
+    fn default() -> CookieSettings {
+        CookieSettings {
+            enabled: false,
+            name: String::from("session"),
+            secure: true,
+            same_site: String::from("Strict"),
+        }
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> SessionSettings {
+        SessionSettings {
+            idle_lifetime: 30 * 60,
+            absolute_lifetime: 7 * 24 * 60 * 60,
+            sweep_interval: 5 * 60,
+            cookie: CookieSettings::default(),
+        }
+    }
+}
+
+///How static files are cached, by clients and in memory.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct CacheSettings {
+    ///Paths starting with any of these hold build output with content hashes
+    ///in their names, so they never change and get `immutable_cache_control`.
+    pub immutable_prefixes: Vec<String>,
+    pub immutable_cache_control: String,
+    ///Cache-Control for every other file, index.html included.
+    pub cache_control: String,
+    ///Files larger than this many bytes are never held in memory.
+    pub max_file_size: u64,
+    ///Bytes held in memory across all files before the least recently used are dropped.
+    pub max_size: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> CacheSettings {
+        CacheSettings {
+            immutable_prefixes: vec![String::from("/_app/immutable/"), String::from("/assets/")],
+            immutable_cache_control: String::from("public, max-age=31536000, immutable"),
+            cache_control: String::from("no-cache"),
+            max_file_size: 1024 * 1024,
+            max_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+fn default_keep_alive_timeout() -> u64 {
+    5
+}
+
+fn default_max_requests_per_connection() -> usize {
+    100
+}
+
+fn default_compression_threshold() -> usize {
+    1024
+}
+
+fn default_http2() -> bool {
+    true
 }
 
-#[derive(Clone)]
+///A row of the sessions table, as cached by `Sessions` and listed at
+///GET /api/sessions.
+#[derive(Clone, Serialize)]
 pub struct SessionUser {
+    pub id: String,
+    #[serde(skip)]
     pub user_id: String,
+    pub created: DateTime<Utc>,
+    ///When the session ends, the authority goes idle before that when unused.
+    #[serde(rename = "expires")]
     pub expire: DateTime<Utc>,
+    #[serde(rename = "lastUsed")]
+    pub last_seen: DateTime<Utc>,
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+    ///Where the session logged in or was last refreshed from.
+    pub ip: Option<String>,
+}
+
+///Body of POST /api/login.
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    ///Keep the session in a cookie scripts can't read, see `CookieSettings`.
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+///Body of POST /api/refresh. Clients with a session cookie send the
+///refresh token as a cookie too and may leave it out here.
+#[derive(Deserialize, Default)]
+pub struct RefreshRequest {
+    #[serde(default)]
+    pub refresh: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -53,21 +360,128 @@ pub struct Task {
     recurring_n: u32,
     #[serde(rename = "recurringStop")]
     recurring_stop: NaiveDate,
+    #[serde(skip)]
+    pub user_id: String,
     #[serde(rename = "completeTasks", skip_deserializing)]
-    pub complete_tasks: Vec<Box<CompleteTask>>,
+    pub complete_tasks: Vec<CompleteTask>,
+    #[serde(rename = "skipTasks", skip_deserializing)]
+    pub skip_tasks: Vec<SkipTask>,
+    #[serde(skip_deserializing)]
+    pub subtasks: Vec<Subtask>,
+    #[serde(rename = "nextDue", skip_deserializing)]
+    pub next_due: Option<NaiveDate>,
+    #[serde(rename = "pendingDates", skip_deserializing)]
+    pub pending_dates: Vec<NaiveDate>,
 }
 
-impl Sql for Task {
-    fn to_sql_insert(&self) -> String {
-        format!("INSERT INTO tasks (id, assign_date, title, description, recurring_month, recurring_n, recurring_stop, user_id) VALUES ('{}', '{}', '{}', '{}', '{}', '{}', '{}', '{{}}');",
-            self.id,
+impl Task {
+    pub fn recurrence(&self) -> Recurrence {
+        Recurrence::new(
             self.assign_date,
-            self.title,
-            self.description,
-            if self.recurring_month {1} else {0},
+            self.recurring_month,
             self.recurring_n,
             self.recurring_stop,
-       )
+        )
+    }
+
+    ///Fills in `next_due` and `pending_dates` from the recurrence fields
+    ///and whatever is currently in `complete_tasks` and `skip_tasks`.
+    ///A skipped occurrence counts as dealt with just like a completed one.
+    pub fn schedule(&mut self, today: NaiveDate) {
+        let recurrence = self.recurrence();
+        let completed: Vec<NaiveDate> = self
+            .complete_tasks
+            .iter()
+            .map(|ct| ct.completed)
+            .chain(self.skip_tasks.iter().map(|st| st.completed))
+            .collect();
+        self.next_due = recurrence::next_due(&recurrence, &completed);
+        self.pending_dates = recurrence::pending(&recurrence, &completed, today);
+    }
+
+    ///Overwrites the fields present in `patch`.
+    pub fn apply(&mut self, patch: TaskPatch) {
+        if let Some(assign_date) = patch.assign_date {
+            self.assign_date = assign_date;
+        }
+        if let Some(title) = patch.title {
+            self.title = title;
+        }
+        if let Some(description) = patch.description {
+            self.description = description;
+        }
+        if let Some(recurring_month) = patch.recurring_month {
+            self.recurring_month = recurring_month;
+        }
+        if let Some(recurring_n) = patch.recurring_n {
+            self.recurring_n = recurring_n;
+        }
+        if let Some(recurring_stop) = patch.recurring_stop {
+            self.recurring_stop = recurring_stop;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.title.trim().is_empty() {
+            return Err(String::from("title can not be empty"));
+        }
+        if self.recurring_n > 0 && self.recurring_stop < self.assign_date {
+            return Err(String::from("recurringStop can not be before assignDate"));
+        }
+        Ok(())
+    }
+
+    ///Occurrences within `from..=to`, marked against `complete_tasks` and `skip_tasks`.
+    ///Anything before `today` that is neither done nor skipped is overdue.
+    pub fn agenda(&self, from: NaiveDate, to: NaiveDate, today: NaiveDate) -> Vec<Occurrence> {
+        self.recurrence()
+            .between(from, to)
+            .map(|date| {
+                let status = if self.complete_tasks.iter().any(|ct| ct.completed == date) {
+                    OccurrenceStatus::Done
+                } else if self.skip_tasks.iter().any(|st| st.completed == date) {
+                    OccurrenceStatus::Skipped
+                } else if date < today {
+                    OccurrenceStatus::Overdue
+                } else {
+                    OccurrenceStatus::Upcoming
+                };
+
+                Occurrence {
+                    task_id: self.id.clone(),
+                    title: self.title.clone(),
+                    date,
+                    status,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Sql for Task {
+    const TABLE: &'static str = "tasks";
+    const COLUMNS: &'static [&'static str] = &[
+        "id",
+        "assign_date",
+        "title",
+        "description",
+        "recurring_month",
+        "recurring_n",
+        "recurring_stop",
+        "user_id",
+    ];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![
+            &self.id,
+            &self.assign_date,
+            &self.title,
+            &self.description,
+            &self.recurring_month,
+            &self.recurring_n,
+            &self.recurring_stop,
+            &self.user_id,
+        ]
     }
 
     fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
@@ -79,7 +493,12 @@ impl Sql for Task {
             recurring_month: row.get("recurring_month")?,
             recurring_n: row.get("recurring_n")?,
             recurring_stop: row.get("recurring_stop")?,
-            complete_tasks: Vec::<Box<CompleteTask>>::new(),
+            user_id: row.get("user_id")?,
+            complete_tasks: Vec::new(),
+            skip_tasks: Vec::new(),
+            subtasks: Vec::new(),
+            next_due: None,
+            pending_dates: Vec::new(),
         };
         Ok(Box::new(t))
     }
@@ -95,21 +514,61 @@ impl Sql for Task {
     }
 }
 
+///Partial update of a `Task`, every field but `id` is optional.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TaskPatch {
+    #[serde(default)]
+    pub id: String,
+    #[serde(rename = "assignDate")]
+    assign_date: Option<NaiveDate>,
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(rename = "recurringMonth")]
+    recurring_month: Option<bool>,
+    #[serde(rename = "recurringN")]
+    recurring_n: Option<u32>,
+    #[serde(rename = "recurringStop")]
+    recurring_stop: Option<NaiveDate>,
+}
+
+///Rows belonging to a task or subtask. Their JSON leaves out which, the
+///REST caller named it in the request already.
+pub trait Child: Serialize {
+    ///JSON key and value of the parent's id.
+    fn parent(&self) -> (&'static str, &str);
+
+    ///The JSON with the parent's id added, for events, whose receivers
+    ///can't tell it from anywhere else.
+    fn event_json(&self) -> String {
+        let mut json = serde_json::to_value(self).unwrap();
+        let (key, id) = self.parent();
+        json[key] = serde_json::Value::from(id);
+        json.to_string()
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CompleteTask {
     #[serde(skip_deserializing)]
     id: String,
     completed: NaiveDate,
-    #[serde(skip_serializing)]
-    task_id: String,
+    #[serde(skip_serializing, default)]
+    pub task_id: String,
+}
+
+impl Child for CompleteTask {
+    fn parent(&self) -> (&'static str, &str) {
+        ("taskId", &self.task_id)
+    }
 }
 
 impl Sql for CompleteTask {
-    fn to_sql_insert(&self) -> String {
-        format!(
-            "INSERT INTO complete_tasks (id, completed, task_id) VALUES ('{}', '{}', '{}');",
-            self.id, self.completed, self.task_id,
-        )
+    const TABLE: &'static str = "complete_tasks";
+    const COLUMNS: &'static [&'static str] = &["id", "completed", "task_id"];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![&self.id, &self.completed, &self.task_id]
     }
 
     fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
@@ -132,23 +591,221 @@ impl Sql for CompleteTask {
     }
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SkipTask {
+    #[serde(skip_deserializing)]
+    id: String,
+    completed: NaiveDate,
+    #[serde(skip_serializing, default)]
+    pub task_id: String,
+}
+
+impl Child for SkipTask {
+    fn parent(&self) -> (&'static str, &str) {
+        ("taskId", &self.task_id)
+    }
+}
+
+impl Sql for SkipTask {
+    const TABLE: &'static str = "skip_tasks";
+    const COLUMNS: &'static [&'static str] = &["id", "completed", "task_id"];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![&self.id, &self.completed, &self.task_id]
+    }
+
+    fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
+        let st = Self {
+            id: row.get("id")?,
+            completed: row.get("completed")?,
+            task_id: row.get("task_id")?,
+        };
+        Ok(Box::new(st))
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error> {
+        let mut st = serde_json::from_str::<SkipTask>(json)?;
+        st.id = Uuid::now_v7().to_string();
+        Ok(Box::new(st))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Subtask {
+    #[serde(skip_deserializing)]
+    pub id: String,
+    description: String,
+    #[serde(skip_serializing, default)]
+    pub task_id: String,
+    #[serde(rename = "completeSubtasks", skip_deserializing)]
+    pub complete_subtasks: Vec<CompleteSubtask>,
+    #[serde(rename = "skipSubtasks", skip_deserializing)]
+    pub skip_subtasks: Vec<SkipSubtask>,
+}
+
+impl Child for Subtask {
+    fn parent(&self) -> (&'static str, &str) {
+        ("taskId", &self.task_id)
+    }
+}
+
+impl Sql for Subtask {
+    const TABLE: &'static str = "subtasks";
+    const COLUMNS: &'static [&'static str] = &["id", "description", "task_id"];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![&self.id, &self.description, &self.task_id]
+    }
+
+    fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
+        let s = Self {
+            id: row.get("id")?,
+            description: row.get("description")?,
+            task_id: row.get("task_id")?,
+            complete_subtasks: Vec::new(),
+            skip_subtasks: Vec::new(),
+        };
+        Ok(Box::new(s))
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error> {
+        let mut s = serde_json::from_str::<Subtask>(json)?;
+        s.id = Uuid::now_v7().to_string();
+        Ok(Box::new(s))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompleteSubtask {
+    #[serde(skip_deserializing)]
+    id: String,
+    completed: NaiveDate,
+    #[serde(skip_serializing, default)]
+    pub subtask_id: String,
+}
+
+impl Child for CompleteSubtask {
+    fn parent(&self) -> (&'static str, &str) {
+        ("subtaskId", &self.subtask_id)
+    }
+}
+
+impl Sql for CompleteSubtask {
+    const TABLE: &'static str = "complete_subtasks";
+    const COLUMNS: &'static [&'static str] = &["id", "completed", "subtask_id"];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![&self.id, &self.completed, &self.subtask_id]
+    }
+
+    fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
+        let cs = Self {
+            id: row.get("id")?,
+            completed: row.get("completed")?,
+            subtask_id: row.get("subtask_id")?,
+        };
+        Ok(Box::new(cs))
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error> {
+        let mut cs = serde_json::from_str::<CompleteSubtask>(json)?;
+        cs.id = Uuid::now_v7().to_string();
+        Ok(Box::new(cs))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SkipSubtask {
+    #[serde(skip_deserializing)]
+    id: String,
+    completed: NaiveDate,
+    #[serde(skip_serializing, default)]
+    pub subtask_id: String,
+}
+
+impl Child for SkipSubtask {
+    fn parent(&self) -> (&'static str, &str) {
+        ("subtaskId", &self.subtask_id)
+    }
+}
+
+impl Sql for SkipSubtask {
+    const TABLE: &'static str = "skip_subtasks";
+    const COLUMNS: &'static [&'static str] = &["id", "completed", "subtask_id"];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![&self.id, &self.completed, &self.subtask_id]
+    }
+
+    fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
+        let ss = Self {
+            id: row.get("id")?,
+            completed: row.get("completed")?,
+            subtask_id: row.get("subtask_id")?,
+        };
+        Ok(Box::new(ss))
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error> {
+        let mut ss = serde_json::from_str::<SkipSubtask>(json)?;
+        ss.id = Uuid::now_v7().to_string();
+        Ok(Box::new(ss))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OccurrenceStatus {
+    Done,
+    Skipped,
+    Overdue,
+    Upcoming,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Occurrence {
+    #[serde(rename = "taskId")]
+    pub task_id: String,
+    pub title: String,
+    pub date: NaiveDate,
+    pub status: OccurrenceStatus,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct User {
     #[serde(skip_deserializing)]
     pub id: String,
     pub username: String,
+    ///An Argon2id PHC string, or a SHA-256 digest in rows from before it.
     #[serde(skip_serializing)]
     pub password: String,
+    ///Only used by SHA-256 rows, Argon2 keeps the salt in `password`.
     #[serde(skip_serializing, skip_deserializing)]
     pub salt: u8,
 }
 
 impl Sql for User {
-    fn to_sql_insert(&self) -> String {
-        format!(
-            "INSERT INTO users (id, username, password, salt) VALUES ('{}', '{}', '{}', {})",
-            self.id, self.username, self.password, self.salt
-        )
+    const TABLE: &'static str = "users";
+    const COLUMNS: &'static [&'static str] = &["id", "username", "password", "salt"];
+
+    fn sql_params(&self) -> Vec<&dyn ToSql> {
+        vec![&self.id, &self.username, &self.password, &self.salt]
     }
 
     fn from_sql_row(row: &rusqlite::Row) -> Result<Box<Self>, rusqlite::Error> {
@@ -168,7 +825,137 @@ impl Sql for User {
     fn from_json(json: &str) -> Result<Box<Self>, serde_json::Error> {
         let mut user: User = serde_json::de::from_str(json)?;
         user.id = Uuid::now_v7().to_string();
-        user.salt = random();
         Ok(Box::new(user))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_name_the_parent() {
+        let mut complete_task = CompleteTask::from_json("{\"completed\":\"2025-01-01\"}").unwrap();
+        complete_task.task_id = String::from("task");
+        assert!(!complete_task.to_json().contains("taskId"));
+        let event: serde_json::Value = serde_json::from_str(&complete_task.event_json()).unwrap();
+        assert_eq!(event["taskId"], "task");
+        assert_eq!(event["completed"], "2025-01-01");
+
+        let mut skip_subtask = SkipSubtask::from_json("{\"completed\":\"2025-01-01\"}").unwrap();
+        skip_subtask.subtask_id = String::from("subtask");
+        let event: serde_json::Value = serde_json::from_str(&skip_subtask.event_json()).unwrap();
+        assert_eq!(event["subtaskId"], "subtask");
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    ///A task from its recurrence fields, done on `done` and skipped on `skipped`.
+    fn task(recurrence: &str, done: &[&str], skipped: &[&str]) -> Task {
+        let mut task = Task::from_json(&format!(
+            "{{\"title\":\"Water plants\",\"description\":\"\",{recurrence}}}"
+        ))
+        .unwrap();
+        for completed in done {
+            task.complete_tasks.push(
+                *CompleteTask::from_json(&format!("{{\"completed\":\"{completed}\"}}")).unwrap(),
+            );
+        }
+        for completed in skipped {
+            task.skip_tasks
+                .push(*SkipTask::from_json(&format!("{{\"completed\":\"{completed}\"}}")).unwrap());
+        }
+        *task
+    }
+
+    const DAILY: &str = "\"assignDate\":\"2025-01-01\",\"recurringMonth\":false,\"recurringN\":1,\"recurringStop\":\"2025-01-10\"";
+
+    fn statuses(occurrences: &[Occurrence]) -> Vec<(NaiveDate, &OccurrenceStatus)> {
+        occurrences
+            .iter()
+            .map(|occurrence| (occurrence.date, &occurrence.status))
+            .collect()
+    }
+
+    #[test]
+    fn agenda_marks_each_occurrence() {
+        use OccurrenceStatus::*;
+        let task = task(DAILY, &["2025-01-02", "2025-01-06"], &["2025-01-03"]);
+        let agenda = task.agenda(date(2025, 1, 1), date(2025, 1, 7), date(2025, 1, 5));
+        assert_eq!(
+            statuses(&agenda),
+            [
+                (date(2025, 1, 1), &Overdue),
+                (date(2025, 1, 2), &Done),
+                (date(2025, 1, 3), &Skipped),
+                (date(2025, 1, 4), &Overdue),
+                //Due today is not overdue yet
+                (date(2025, 1, 5), &Upcoming),
+                //Done ahead of time
+                (date(2025, 1, 6), &Done),
+                (date(2025, 1, 7), &Upcoming),
+            ]
+        );
+        assert!(agenda
+            .iter()
+            .all(|occurrence| occurrence.task_id == task.id && occurrence.title == "Water plants"));
+    }
+
+    #[test]
+    fn agenda_stays_in_the_range() {
+        let task = task(DAILY, &["2025-01-01"], &[]);
+        let today = date(2025, 1, 5);
+        let dates = |from, to| -> Vec<NaiveDate> {
+            task.agenda(from, to, today)
+                .iter()
+                .map(|occurrence| occurrence.date)
+                .collect()
+        };
+        assert_eq!(
+            dates(date(2025, 1, 9), date(2025, 2, 1)),
+            [date(2025, 1, 9), date(2025, 1, 10)]
+        );
+        assert_eq!(
+            dates(date(2025, 1, 4), date(2025, 1, 4)),
+            [date(2025, 1, 4)]
+        );
+        assert!(dates(date(2024, 12, 1), date(2024, 12, 31)).is_empty());
+        assert!(dates(date(2025, 1, 11), date(2025, 3, 1)).is_empty());
+    }
+
+    #[test]
+    fn agenda_of_single_and_monthly_tasks() {
+        use OccurrenceStatus::*;
+        let once = task(
+            "\"assignDate\":\"2025-03-15\",\"recurringMonth\":false,\"recurringN\":0,\"recurringStop\":\"2025-03-15\"",
+            &[],
+            &[],
+        );
+        let range = (date(2025, 1, 1), date(2025, 12, 31));
+        assert_eq!(
+            statuses(&once.agenda(range.0, range.1, date(2025, 3, 1))),
+            [(date(2025, 3, 15), &Upcoming)]
+        );
+        assert_eq!(
+            statuses(&once.agenda(range.0, range.1, date(2025, 3, 16))),
+            [(date(2025, 3, 15), &Overdue)]
+        );
+
+        let monthly = task(
+            "\"assignDate\":\"2025-01-15\",\"recurringMonth\":true,\"recurringN\":1,\"recurringStop\":\"2025-04-15\"",
+            &[],
+            &["2025-02-15"],
+        );
+        assert_eq!(
+            statuses(&monthly.agenda(range.0, range.1, date(2025, 3, 20))),
+            [
+                (date(2025, 1, 15), &Overdue),
+                (date(2025, 2, 15), &Skipped),
+                (date(2025, 3, 15), &Overdue),
+                (date(2025, 4, 15), &Upcoming),
+            ]
+        );
+    }
+}
@@ -0,0 +1,523 @@
+use mio::{unix::SourceFd, Interest, Poll, Token, Waker};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::connection::{Client, Outgoing};
+use crate::data_structs::EventSettings;
+
+///Milliseconds a client waits before reconnecting to a stream that ended.
+pub const RETRY_MS: u64 = 3000;
+///A subscriber taking none of the events waiting for it for this long is
+///dropped. Events are written without blocking, so a stuck client never
+///holds up anyone else's.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+///A subscriber with more than this waiting to be written is dropped too.
+///It gets what it missed when it reconnects.
+const MAX_OUTGOING: usize = 1024 * 1024;
+///Token of the waker, subscribers are registered under their id.
+const WAKE: Token = Token(usize::MAX);
+
+///Changes to users' data, sent to every event stream the user has open.
+///Recent events are kept so a client reconnecting with Last-Event-ID gets
+///what it missed.
+pub struct Events {
+    ///Event ids restart with the process, the epoch tells them apart.
+    epoch: u64,
+    backlog_size: usize,
+    inner: Mutex<Inner>,
+    ///To the thread started by `deliver`.
+    sender: Sender<Command>,
+    waker: Waker,
+}
+
+struct Inner {
+    last_id: u64,
+    ///Highest id dropped from the backlog.
+    evicted: u64,
+    backlog: VecDeque<Arc<Event>>,
+    ///Get every event as it is published, see `listen`.
+    listeners: Vec<Sender<Arc<Event>>>,
+}
+
+pub struct Event {
+    id: u64,
+    pub user_id: String,
+    ///The WebSocket whose operation caused the event.
+    pub origin: Option<u64>,
+    pub kind: &'static str,
+    pub data: String,
+}
+
+///The receiving end of `Events`, every stream is written from one thread.
+pub struct Delivery {
+    receiver: Receiver<Command>,
+    poll: Poll,
+    heartbeat: Duration,
+}
+
+enum Command {
+    Subscribe(Box<Subscriber>),
+    Publish {
+        user_id: String,
+        formatted: Arc<str>,
+    },
+    Close {
+        session_ids: Vec<String>,
+    },
+}
+
+///An open event stream, its response head already sent and its socket
+///nonblocking.
+struct Subscriber {
+    user_id: String,
+    ///The session whose authority opened the stream.
+    session_id: String,
+    client: Client,
+    ///Formatted events the socket had no room for yet.
+    outgoing: Outgoing,
+    last_sent: Instant,
+}
+
+impl Events {
+    pub fn new(settings: &EventSettings) -> io::Result<(Events, Delivery)> {
+        let (sender, receiver) = mpsc::channel();
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKE)?;
+        let events = Events {
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            backlog_size: settings.backlog,
+            inner: Mutex::new(Inner {
+                last_id: 0,
+                evicted: 0,
+                backlog: VecDeque::new(),
+                listeners: Vec::new(),
+            }),
+            sender,
+            waker,
+        };
+        let delivery = Delivery {
+            receiver,
+            poll,
+            heartbeat: Duration::from_secs(settings.heartbeat_interval.max(1)),
+        };
+        Ok((events, delivery))
+    }
+
+    ///Records that something of `user_id` changed. `data` is the JSON the
+    ///REST endpoint answered with, `origin` the WebSocket the request came
+    ///in on if any.
+    pub fn publish(&self, user_id: &str, origin: Option<u64>, kind: &'static str, data: String) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_id += 1;
+        let event = Arc::new(Event {
+            id: inner.last_id,
+            user_id: user_id.to_string(),
+            origin,
+            kind,
+            data,
+        });
+        inner
+            .listeners
+            .retain(|listener| listener.send(event.clone()).is_ok());
+
+        //Sent under the lock, so subscribers get events in order
+        self.send(Command::Publish {
+            user_id: event.user_id.clone(),
+            formatted: Arc::from(self.format(&event)),
+        });
+
+        inner.backlog.push_back(event);
+        while inner.backlog.len() > self.backlog_size {
+            if let Some(evicted) = inner.backlog.pop_front() {
+                inner.evicted = evicted.id;
+            }
+        }
+    }
+
+    ///Sends events to `client` from now on, starting with those after
+    ///`last_event_id` the client already got on an earlier connection.
+    ///The stream lasts until session `session_id` ends, see `close_sessions`.
+    pub fn subscribe(
+        &self,
+        client: Client,
+        user_id: String,
+        session_id: String,
+        last_event_id: Option<&str>,
+    ) {
+        if let Err(err) = client.reader.get_ref().socket().set_nonblocking(true) {
+            println!("Could not subscribe to events");
+            println!("{err}");
+            return;
+        }
+
+        let inner = self.inner.lock().unwrap();
+        let mut outgoing = Outgoing::default();
+        match last_event_id.map(|id| self.resume_from(&inner, id)) {
+            Some(Some(seen)) => {
+                for event in inner.backlog.iter().filter(|event| event.id > seen) {
+                    if event.user_id == user_id {
+                        outgoing.push(self.format(event).as_bytes());
+                    }
+                }
+            }
+            //The client asked to resume from events no longer kept and has to reload
+            Some(None) => outgoing.push(b"event: reset\ndata: {}\n\n"),
+            None => (),
+        }
+        self.send(Command::Subscribe(Box::new(Subscriber {
+            user_id,
+            session_id,
+            client,
+            outgoing,
+            last_sent: Instant::now(),
+        })));
+    }
+
+    ///Ends the streams opened with any of the sessions `ids`.
+    pub fn close_sessions(&self, ids: &[String]) {
+        self.send(Command::Close {
+            session_ids: ids.to_vec(),
+        });
+    }
+
+    fn send(&self, command: Command) {
+        //Only fails once the delivery thread is gone
+        if self.sender.send(command).is_ok() {
+            if let Err(err) = self.waker.wake() {
+                println!("Could not wake event delivery");
+                println!("{err}");
+            }
+        }
+    }
+
+    ///Passes every event published from now on to the returned receiver.
+    pub fn listen(&self) -> Receiver<Arc<Event>> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner.lock().unwrap().listeners.push(sender);
+        receiver
+    }
+
+    ///Every event of `user_id` after `last_event_id`, formatted for an event
+    ///stream. For clients that can't hold a stream open and poll instead.
+    pub fn replay(&self, user_id: &str, last_event_id: Option<&str>) -> String {
+        let inner = self.inner.lock().unwrap();
+        let seen = match last_event_id.map(|id| self.resume_from(&inner, id)) {
+            Some(Some(seen)) => seen,
+            Some(None) => return String::from("event: reset\ndata: {}\n\n"),
+            None => inner.last_id,
+        };
+        let mut replayed = String::new();
+        for event in inner.backlog.iter().filter(|event| event.id > seen) {
+            if event.user_id == user_id {
+                replayed.push_str(&self.format(event));
+            }
+        }
+        if replayed.is_empty() {
+            //Lets the client resume from here next time
+            replayed = format!("id: {}-{}\n\n", self.epoch, inner.last_id);
+        }
+        replayed
+    }
+
+    ///The id a client may resume after, None when events it missed are gone.
+    fn resume_from(&self, inner: &Inner, last_event_id: &str) -> Option<u64> {
+        let (epoch, id) = last_event_id.split_once('-')?;
+        let id: u64 = id.parse().ok()?;
+        if epoch.parse() != Ok(self.epoch) || id < inner.evicted || id > inner.last_id {
+            return None;
+        }
+        Some(id)
+    }
+
+    fn format(&self, event: &Event) -> String {
+        let mut formatted = format!("id: {}-{}\nevent: {}\n", self.epoch, event.id, event.kind);
+        for line in event.data.lines() {
+            formatted.push_str(&format!("data: {line}\n"));
+        }
+        formatted.push('\n');
+        formatted
+    }
+}
+
+///Starts the thread writing events and heartbeats to every subscriber, so
+///none of them occupies a worker or a thread of its own.
+pub fn deliver(delivery: Delivery) {
+    thread::spawn(move || delivery_loop(delivery));
+}
+
+fn delivery_loop(delivery: Delivery) {
+    let Delivery {
+        receiver,
+        mut poll,
+        heartbeat,
+    } = delivery;
+    let mut subscribers: HashMap<u64, Subscriber> = HashMap::new();
+    let mut events = mio::Events::with_capacity(256);
+    let mut next_id = 0;
+    loop {
+        let now = Instant::now();
+        let wait = subscribers
+            .values()
+            .map(|subscriber| {
+                subscriber
+                    .deadline(heartbeat)
+                    .saturating_duration_since(now)
+            })
+            .min();
+        if let Err(err) = poll.poll(&mut events, wait) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                println!("Could not wait for event streams");
+                println!("{err}");
+                return;
+            }
+            continue;
+        }
+
+        //Subscribers to write to or check on
+        let mut ready: Vec<u64> = events
+            .iter()
+            .filter(|event| event.token() != WAKE)
+            .map(|event| event.token().0 as u64)
+            .collect();
+        for command in receiver.try_iter() {
+            match command {
+                Command::Subscribe(subscriber) => {
+                    next_id += 1;
+                    let fd = subscriber.client.reader.get_ref().socket().as_raw_fd();
+                    //Readable only once the client hangs up, it sends nothing else
+                    if let Err(err) = poll.registry().register(
+                        &mut SourceFd(&fd),
+                        Token(next_id as usize),
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        println!("Could not subscribe to events");
+                        println!("{err}");
+                        continue;
+                    }
+                    subscribers.insert(next_id, *subscriber);
+                    ready.push(next_id);
+                }
+                Command::Publish { user_id, formatted } => {
+                    for (id, subscriber) in subscribers.iter_mut() {
+                        if subscriber.user_id == user_id {
+                            subscriber.send(formatted.as_bytes());
+                            ready.push(*id);
+                        }
+                    }
+                }
+                Command::Close { session_ids } => {
+                    subscribers.retain(|_, subscriber| {
+                        let open = !session_ids.contains(&subscriber.session_id);
+                        if !open {
+                            subscriber.unregister(&poll);
+                        }
+                        open
+                    });
+                }
+            }
+        }
+        let now = Instant::now();
+        ready.extend(
+            subscribers
+                .iter()
+                .filter(|(_, subscriber)| subscriber.deadline(heartbeat) <= now)
+                .map(|(id, _)| *id),
+        );
+        ready.sort_unstable();
+        ready.dedup();
+
+        for id in ready {
+            let Some(subscriber) = subscribers.get_mut(&id) else {
+                continue;
+            };
+            if !subscriber.service(heartbeat) {
+                subscriber.unregister(&poll);
+                //Dropping the client closes the connection
+                subscribers.remove(&id);
+            }
+        }
+    }
+}
+
+impl Subscriber {
+    fn send(&mut self, bytes: &[u8]) {
+        self.outgoing.push(bytes);
+        self.last_sent = Instant::now();
+    }
+
+    ///Writes what is waiting, and a heartbeat when due. Returns whether the
+    ///stream stays open.
+    fn service(&mut self, heartbeat: Duration) -> bool {
+        let mut buf = [0; 256];
+        loop {
+            match self.client.reader.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return false,
+            }
+        }
+
+        if self.outgoing.is_empty() && self.last_sent.elapsed() >= heartbeat {
+            //A comment, ignored by clients, keeps proxies from timing the stream out
+            self.send(b": heartbeat\n\n");
+        }
+        let stream = self.client.reader.get_ref();
+        if self.outgoing.flush(stream).is_err() || self.outgoing.len() > MAX_OUTGOING {
+            return false;
+        }
+        self.outgoing
+            .stuck_since()
+            .is_none_or(|since| since.elapsed() < WRITE_TIMEOUT)
+    }
+
+    ///When a heartbeat is due, or the events waiting have been stuck too long.
+    fn deadline(&self, heartbeat: Duration) -> Instant {
+        let deadline = self.last_sent + heartbeat;
+        match self.outgoing.stuck_since() {
+            Some(since) => deadline.min(since + WRITE_TIMEOUT),
+            None => deadline,
+        }
+    }
+
+    fn unregister(&self, poll: &Poll) {
+        let fd = self.client.reader.get_ref().socket().as_raw_fd();
+        let _ = poll.registry().deregister(&mut SourceFd(&fd));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Stream;
+    use std::net::{TcpListener, TcpStream};
+
+    fn events(backlog: usize) -> Events {
+        let settings = EventSettings {
+            heartbeat_interval: 60,
+            backlog,
+        };
+        Events::new(&settings).unwrap().0
+    }
+
+    fn publish(events: &Events, user_id: &str, n: u64) {
+        events.publish(user_id, None, "task_created", format!("{{\"n\":{n}}}"));
+    }
+
+    ///The `data` lines of a replay, in order.
+    fn data(replayed: &str) -> Vec<&str> {
+        replayed
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect()
+    }
+
+    #[test]
+    fn replays_the_users_own_events() {
+        let events = events(10);
+        let start = format!("{}-0", events.epoch);
+        publish(&events, "ann", 1);
+        publish(&events, "bob", 2);
+        publish(&events, "ann", 3);
+
+        let replayed = events.replay("ann", Some(&start));
+        assert_eq!(data(&replayed), ["{\"n\":1}", "{\"n\":3}"]);
+        assert!(replayed.starts_with(&format!("id: {}-1\nevent: task_created\n", events.epoch)));
+        assert_eq!(
+            data(&events.replay("ann", Some(&format!("{}-1", events.epoch)))),
+            ["{\"n\":3}"]
+        );
+
+        //Nothing new, the client gets the id to resume from next time
+        let replayed = events.replay("ann", Some(&format!("{}-3", events.epoch)));
+        assert_eq!(replayed, format!("id: {}-3\n\n", events.epoch));
+        //Nor without a Last-Event-ID
+        assert_eq!(
+            events.replay("bob", None),
+            format!("id: {}-3\n\n", events.epoch)
+        );
+    }
+
+    #[test]
+    fn reset_when_events_are_gone() {
+        let events = events(2);
+        for n in 1..=4 {
+            publish(&events, "ann", n);
+        }
+        assert_eq!(events.inner.lock().unwrap().backlog.len(), 2);
+        assert_eq!(events.inner.lock().unwrap().evicted, 2);
+
+        let reset = "event: reset\ndata: {}\n\n";
+        //Event 2 is gone, but a client that saw it misses nothing
+        assert_eq!(
+            events.replay("ann", Some(&format!("{}-1", events.epoch))),
+            reset
+        );
+        assert_eq!(
+            data(&events.replay("ann", Some(&format!("{}-2", events.epoch)))),
+            ["{\"n\":3}", "{\"n\":4}"]
+        );
+
+        //From before a restart, from the future, or not an id at all
+        for id in [
+            format!("{}-3", events.epoch - 1),
+            format!("{}-5", events.epoch),
+            String::from("3"),
+            format!("{}-x", events.epoch),
+        ] {
+            assert_eq!(events.replay("ann", Some(&id)), reset, "{id}");
+        }
+    }
+
+    ///Reads `len` bytes the server sent.
+    fn received(peer: &mut TcpStream, len: usize) -> String {
+        let mut received = vec![0; len];
+        peer.read_exact(&mut received).unwrap();
+        String::from_utf8(received).unwrap()
+    }
+
+    #[test]
+    fn delivers_to_subscribers() {
+        let (events, delivery) = Events::new(&EventSettings::default()).unwrap();
+        deliver(delivery);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let subscribe = |session_id: &str, last_event_id: Option<&str>| {
+            let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            let client = Client::new(Stream::plain(server));
+            let user_id = String::from("ann");
+            events.subscribe(client, user_id, String::from(session_id), last_event_id);
+            peer
+        };
+
+        publish(&events, "ann", 1);
+        let mut first = subscribe("a", Some(&format!("{}-0", events.epoch)));
+        let mut second = subscribe("b", None);
+        publish(&events, "bob", 2);
+        publish(&events, "ann", 3);
+        let one = events.format(&events.inner.lock().unwrap().backlog[0]);
+        let three = events.format(&events.inner.lock().unwrap().backlog[2]);
+        //What the first missed comes first
+        assert_eq!(received(&mut first, one.len() + three.len()), one + &three);
+        assert_eq!(received(&mut second, three.len()), three);
+
+        events.close_sessions(&[String::from("a")]);
+        assert_eq!(first.read(&mut [0; 1]).unwrap(), 0);
+        publish(&events, "ann", 4);
+        let four = events.format(&events.inner.lock().unwrap().backlog[3]);
+        assert_eq!(received(&mut second, four.len()), four);
+    }
+}
@@ -0,0 +1,974 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use uuid::Uuid;
+
+use crate::compression::{self, Encoding};
+use crate::connection::Responder;
+use crate::data_structs::{CacheSettings, Settings};
+use crate::http::{self, Request};
+use crate::router::percent_decode;
+use crate::{serve_404_html, serve_options, write_error_json, HttpError};
+
+const FILE_METHODS: [&str; 3] = ["GET", "HEAD", "OPTIONS"];
+
+///Recently served files kept in memory together with the compressed
+///variants made from them. An entry is dropped as soon as the file's
+///modification time or size no longer match what is on disk.
+pub struct FileCache {
+    entries: Mutex<Entries>,
+    max_file_size: u64,
+    max_size: u64,
+}
+
+struct Entries {
+    by_path: HashMap<PathBuf, Entry>,
+    ///Sum of the sizes of all entries, kept up to date on every change.
+    size: u64,
+}
+
+struct Entry {
+    modified: SystemTime,
+    len: u64,
+    variants: HashMap<Encoding, Arc<Vec<u8>>>,
+    last_used: Instant,
+}
+
+impl Entry {
+    fn size(&self) -> u64 {
+        self.variants.values().map(|data| data.len() as u64).sum()
+    }
+}
+
+impl FileCache {
+    pub fn new(settings: &CacheSettings) -> FileCache {
+        FileCache {
+            entries: Mutex::new(Entries {
+                by_path: HashMap::new(),
+                size: 0,
+            }),
+            max_file_size: settings.max_file_size,
+            max_size: settings.max_size,
+        }
+    }
+
+    ///Whether a file of `len` bytes may be held in memory.
+    pub fn fits(&self, len: u64) -> bool {
+        len <= self.max_file_size
+    }
+
+    ///Contents of the file at `path` in `encoding`. `metadata` is what the
+    ///file looks like on disk right now. Only for files that `fits`.
+    pub fn get(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        encoding: Encoding,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        let modified = metadata.modified()?;
+        let len = metadata.len();
+
+        if let Some(data) = self.lookup(path, modified, len, encoding) {
+            return Ok(data);
+        }
+        //Read and compress without holding the lock
+        let identity = match self.lookup(path, modified, len, Encoding::Identity) {
+            Some(identity) => identity,
+            None => Arc::new(fs::read(path)?),
+        };
+        //The file changed between looking at it and reading it
+        if identity.len() as u64 != len {
+            return Ok(identity);
+        }
+        self.store(path, modified, len, Encoding::Identity, identity.clone());
+        if encoding == Encoding::Identity {
+            return Ok(identity);
+        }
+
+        let data = Arc::new(compression::compress(&identity, encoding)?);
+        self.store(path, modified, len, encoding, data.clone());
+        Ok(data)
+    }
+
+    fn lookup(
+        &self,
+        path: &Path,
+        modified: SystemTime,
+        len: u64,
+        encoding: Encoding,
+    ) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.by_path.get_mut(path)?;
+        if entry.modified != modified || entry.len != len {
+            let size = entry.size();
+            entries.by_path.remove(path);
+            entries.size -= size;
+            return None;
+        }
+
+        entry.last_used = Instant::now();
+        entry.variants.get(&encoding).cloned()
+    }
+
+    fn store(
+        &self,
+        path: &Path,
+        modified: SystemTime,
+        len: u64,
+        encoding: Encoding,
+        data: Arc<Vec<u8>>,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .by_path
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Entry {
+                modified,
+                len,
+                variants: HashMap::new(),
+                last_used: Instant::now(),
+            });
+        let before = entry.size();
+        if entry.modified != modified || entry.len != len {
+            entry.modified = modified;
+            entry.len = len;
+            entry.variants.clear();
+        }
+        entry.variants.insert(encoding, data);
+        entry.last_used = Instant::now();
+        let after = entry.size();
+        entries.size = entries.size - before + after;
+
+        while entries.size > self.max_size {
+            let oldest = entries
+                .by_path
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone());
+            match oldest.and_then(|oldest| entries.by_path.remove(&oldest)) {
+                Some(evicted) => entries.size -= evicted.size(),
+                None => break,
+            }
+        }
+    }
+}
+
+pub fn handle_file_request(
+    mut stream: &Responder,
+    settings: &Settings,
+    cache: &FileCache,
+    request: &Request,
+) {
+    match request.method.as_str() {
+        "GET" | "HEAD" => (),
+        "OPTIONS" => {
+            serve_options(stream, request, settings, &FILE_METHODS);
+            return;
+        }
+        method => {
+            let allow = format!("Allow: {}\r\n", FILE_METHODS.join(", "));
+            write_error_json(
+                stream,
+                HttpError::MethodNotAllowed,
+                format!("Static files can't be sent {method}"),
+                &allow,
+            );
+            return;
+        }
+    }
+
+    //Browsers never send the fragment, but nothing stops other clients
+    let raw_path = request.path();
+    let raw_path = raw_path.split_once('#').map_or(raw_path, |(path, _)| path);
+    let request_path = percent_decode(raw_path);
+
+    let file_path = match resolve(&settings.root_path, settings.spa_fallback, &request_path) {
+        Lookup::File(file_path) => file_path,
+        Lookup::Directory => {
+            let header = format!(
+                "HTTP/1.1 301 Moved Permanently\r\n{}Location: {}\r\nContent-Length: 0\r\n\r\n",
+                stream.common_headers(),
+                directory_location(raw_path, &request.target)
+            );
+            if let Err(err) = stream.write_all(header.as_bytes()) {
+                println!("Could not write header to stream");
+                println!("{err}");
+            }
+            return;
+        }
+        Lookup::NotFound(reason) => {
+            serve_404_html(stream, reason);
+            return;
+        }
+    };
+
+    let metadata = match fs::metadata(&file_path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            serve_404_html(stream, err.to_string());
+            return;
+        }
+    };
+    let mime = mime_guess::from_path(&file_path)
+        .first_or_octet_stream()
+        .to_string();
+
+    //A sibling compressed at build time beats compressing on every request
+    let mut siblings: Vec<(Encoding, PathBuf, Metadata)> = compression::ENCODINGS
+        .into_iter()
+        .filter_map(|encoding| {
+            let mut path = file_path.clone().into_os_string();
+            path.push(encoding.extension()?);
+            let path = PathBuf::from(path);
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => Some((encoding, path, metadata)),
+                _ => None,
+            }
+        })
+        .collect();
+    let available: Vec<Encoding> = siblings.iter().map(|(encoding, ..)| *encoding).collect();
+    let chosen = stream.negotiate(&available);
+
+    let mut vary = !siblings.is_empty();
+    let (path, metadata, encoding, precompressed) = match siblings
+        .iter()
+        .position(|(encoding, ..)| *encoding == chosen)
+    {
+        Some(i) => {
+            let (encoding, path, metadata) = siblings.swap_remove(i);
+            (path, metadata, encoding, true)
+        }
+        //Files too large for the cache are never compressed on the fly
+        None if !cache.fits(metadata.len()) => (file_path, metadata, Encoding::Identity, false),
+        None => match stream.encoding_for(&mime, metadata.len() as usize) {
+            Some(encoding) => {
+                vary = true;
+                (file_path, metadata, encoding, false)
+            }
+            None => (file_path, metadata, Encoding::Identity, false),
+        },
+    };
+
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let etag = etag(&metadata, encoding);
+    let cache_control = if settings
+        .cache
+        .immutable_prefixes
+        .iter()
+        .any(|prefix| request_path.starts_with(prefix.as_str()))
+    {
+        &settings.cache.immutable_cache_control
+    } else {
+        &settings.cache.cache_control
+    };
+    let mut validators = format!(
+        "Accept-Ranges: bytes\r\nETag: {etag}\r\nLast-Modified: {}\r\nCache-Control: {cache_control}\r\n",
+        http::format_date(modified)
+    );
+    if vary {
+        validators.push_str("Vary: Accept-Encoding\r\n");
+    }
+
+    if not_modified(request, &etag, modified) {
+        let header = format!(
+            "HTTP/1.1 304 Not Modified\r\n{}{validators}\r\n",
+            stream.common_headers()
+        );
+        if let Err(err) = stream.write_all(header.as_bytes()) {
+            println!("Could not write header to stream");
+            println!("{err}");
+        }
+        return;
+    }
+
+    //A precompressed sibling is sent exactly as it is stored
+    let stored = if precompressed {
+        Encoding::Identity
+    } else {
+        encoding
+    };
+    let mut body = if cache.fits(metadata.len()) {
+        cache.get(&path, &metadata, stored).map(Body::Memory)
+    } else {
+        File::open(&path).map(Body::File)
+    };
+    let body = match &mut body {
+        Ok(body) => body,
+        Err(err) => {
+            serve_404_html(stream, err.to_string());
+            return;
+        }
+    };
+    if encoding != Encoding::Identity {
+        validators.push_str(&format!("Content-Encoding: {}\r\n", encoding.token()));
+    }
+
+    let len = body.len();
+    let ranges = match request.headers.get("range") {
+        Some(range) if request.method == "GET" && if_range(request, &etag, modified) => {
+            parse_ranges(range, len)
+        }
+        _ => None,
+    };
+    if ranges.as_ref().is_some_and(Vec::is_empty) {
+        let header = format!(
+            "HTTP/1.1 416 Range Not Satisfiable\r\n{}{validators}Content-Range: bytes */{len}\r\nContent-Length: 0\r\n\r\n",
+            stream.common_headers()
+        );
+        if let Err(err) = stream.write_all(header.as_bytes()) {
+            println!("Could not write header to stream");
+            println!("{err}");
+        }
+        return;
+    }
+
+    let result = match ranges.as_deref() {
+        None | Some([]) => {
+            let header = format!(
+                "HTTP/1.1 200 OK\r\n{}Content-Type: {}\r\n{}Content-Length: {}\r\n\r\n",
+                stream.common_headers(),
+                mime,
+                validators,
+                len
+            );
+            stream
+                .write_all(header.as_bytes())
+                .and_then(|_| body.write_range(stream, 0, len))
+        }
+        Some([(start, end)]) => {
+            let header = format!(
+                "HTTP/1.1 206 Partial Content\r\n{}Content-Type: {}\r\n{}Content-Range: bytes {}-{}/{len}\r\nContent-Length: {}\r\n\r\n",
+                stream.common_headers(),
+                mime,
+                validators,
+                start,
+                end - 1,
+                end - start
+            );
+            stream
+                .write_all(header.as_bytes())
+                .and_then(|_| body.write_range(stream, *start, *end))
+        }
+        Some(ranges) => write_multipart(stream, body, &mime, &validators, ranges),
+    };
+    if let Err(err) = result {
+        println!("Could not write content to stream");
+        println!("{err}");
+        //The client can't tell where a body that broke off ends
+        stream.abort();
+    }
+}
+
+///Where the path of a request leads inside the web root.
+enum Lookup {
+    File(PathBuf),
+    ///A directory asked for without the trailing slash, relative links in
+    ///its index only work once the client is redirected to add it.
+    Directory,
+    NotFound(String),
+}
+
+///Resolves a decoded request path against `root`. Every path is
+///canonicalized and has to stay inside the root, so neither `..` segments
+///nor symlinks lead anywhere else. Directories resolve to their index.html.
+fn lookup(root: &str, path: &str) -> Lookup {
+    let root = match fs::canonicalize(root) {
+        Ok(root) => root,
+        Err(err) => return Lookup::NotFound(err.to_string()),
+    };
+
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => return Lookup::NotFound(String::from("Path leaves the web root")),
+            segment if segment.contains(['\\', '\0']) => {
+                return Lookup::NotFound(String::from("Invalid path"))
+            }
+            segment => relative.push(segment),
+        }
+    }
+
+    let resolved = match fs::canonicalize(root.join(relative)) {
+        Ok(resolved) => resolved,
+        Err(err) => return Lookup::NotFound(err.to_string()),
+    };
+    if !resolved.starts_with(&root) {
+        return Lookup::NotFound(String::from("Path leaves the web root"));
+    }
+
+    if !resolved.is_dir() {
+        return Lookup::File(resolved);
+    }
+    if !path.ends_with('/') {
+        return Lookup::Directory;
+    }
+    let index = resolved.join("index.html");
+    if index.is_file() {
+        Lookup::File(index)
+    } else {
+        Lookup::NotFound(format!("{path} has no index.html"))
+    }
+}
+
+///Like `lookup`, but with `spa_fallback` the client side routes of the
+///single page app all load index.html.
+fn resolve(root: &str, spa_fallback: bool, path: &str) -> Lookup {
+    match lookup(root, path) {
+        Lookup::NotFound(_) if spa_fallback && !is_asset(path) => {
+            match lookup(root, "/index.html") {
+                Lookup::File(file_path) => Lookup::File(file_path),
+                _ => Lookup::NotFound(String::from("No index.html to fall back to")),
+            }
+        }
+        found => found,
+    }
+}
+
+///Where a directory asked for without the trailing slash is redirected to.
+///Leading slashes are collapsed, `//example.com/` would send the client to
+///another host.
+fn directory_location(raw_path: &str, target: &str) -> String {
+    let mut location = format!("/{}/", raw_path.trim_start_matches('/'));
+    if let Some((_, query)) = target.split_once('?') {
+        location.push('?');
+        location.push_str(query);
+    }
+    location
+}
+
+///Whether the path names a file rather than a client side route,
+///going by whether its last segment has an extension.
+fn is_asset(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .is_some_and(|name| name.contains('.'))
+}
+
+///Longest list of ranges served, clients asking for more get the whole file.
+const MAX_RANGES: usize = 32;
+
+///What a static response is sent from. Files too large for the cache are
+///read straight off the disk while being written.
+enum Body {
+    Memory(Arc<Vec<u8>>),
+    File(File),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Memory(data) => data.len() as u64,
+            Body::File(file) => file.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+        }
+    }
+
+    ///Writes bytes `start..end`.
+    fn write_range(&mut self, mut out: &Responder, start: u64, end: u64) -> io::Result<()> {
+        match self {
+            Body::Memory(data) => out.write_all(&data[start as usize..end as usize]),
+            Body::File(file) => {
+                file.seek(SeekFrom::Start(start))?;
+                let copied = io::copy(&mut file.take(end - start), &mut out)?;
+                if copied != end - start {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "File shrank while being sent",
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+///Sends several ranges as one multipart/byteranges body.
+fn write_multipart(
+    mut stream: &Responder,
+    body: &mut Body,
+    mime: &str,
+    validators: &str,
+    ranges: &[(u64, u64)],
+) -> io::Result<()> {
+    let len = body.len();
+    let boundary = Uuid::new_v4().simple().to_string();
+    let part_heads: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| {
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: {mime}\r\nContent-Range: bytes {start}-{}/{len}\r\n\r\n",
+                end - 1
+            )
+        })
+        .collect();
+    let closing = format!("\r\n--{boundary}--\r\n");
+
+    let content_length = part_heads.iter().map(|head| head.len() as u64).sum::<u64>()
+        + ranges.iter().map(|(start, end)| end - start).sum::<u64>()
+        + closing.len() as u64;
+    let header = format!(
+        "HTTP/1.1 206 Partial Content\r\n{}Content-Type: multipart/byteranges; boundary={boundary}\r\n{validators}Content-Length: {content_length}\r\n\r\n",
+        stream.common_headers()
+    );
+
+    stream.write_all(header.as_bytes())?;
+    for (head, (start, end)) in part_heads.iter().zip(ranges) {
+        stream.write_all(head.as_bytes())?;
+        body.write_range(stream, *start, *end)?;
+    }
+    stream.write_all(closing.as_bytes())
+}
+
+///Parses a Range header into half open `start..end` ranges within `len`,
+///sorted with overlapping and adjacent ranges merged.
+///None when the header should be ignored and the whole file sent,
+///an empty list when none of the ranges can be satisfied.
+fn parse_ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = spec.trim().split_once('-')?;
+        let digits = |value: &str| value.bytes().all(|b| b.is_ascii_digit());
+        if !digits(first) || !digits(last) {
+            return None;
+        }
+
+        let range = if first.is_empty() {
+            //A suffix, the last n bytes
+            let suffix: u64 = last.parse().ok()?;
+            //An empty file has no last bytes to send
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            (len.saturating_sub(suffix), len)
+        } else {
+            let start: u64 = first.parse().ok()?;
+            let end = match last {
+                "" => len,
+                last => {
+                    let last: u64 = last.parse().ok()?;
+                    if last < start {
+                        return None;
+                    }
+                    last.saturating_add(1).min(len)
+                }
+            };
+            if start >= len {
+                continue;
+            }
+            (start, end)
+        };
+        //A range must cover at least one byte, Content-Range can't describe less
+        if range.0 >= range.1 {
+            continue;
+        }
+        ranges.push(range);
+    }
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(merged)
+}
+
+///Whether a Range header may be honoured. If-Range holds either an ETag,
+///compared strongly, or the Last-Modified date the client saw.
+fn if_range(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    let condition = match request.headers.get("if-range") {
+        Some(condition) => condition.trim(),
+        None => return true,
+    };
+    if condition.starts_with('"') || condition.starts_with("W/") {
+        return condition == etag;
+    }
+    http::parse_date(condition).is_some_and(|date| seconds(date) == seconds(modified))
+}
+
+///Strong validator built from size and modification time, which is enough
+///to tell versions of a file apart without reading it. Each coding is a
+///different representation and so gets its own tag.
+fn etag(metadata: &Metadata, encoding: Encoding) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_nanos())
+        .unwrap_or(0);
+    match encoding {
+        Encoding::Identity => format!("\"{:x}-{:x}\"", metadata.len(), modified),
+        encoding => format!(
+            "\"{:x}-{:x}-{}\"",
+            metadata.len(),
+            modified,
+            encoding.token()
+        ),
+    }
+}
+
+///Whether the copy the client already holds is current, following the
+///precedence of RFC 9110 section 13.2.2.
+fn not_modified(request: &Request, etag: &str, modified: SystemTime) -> bool {
+    if request.headers.contains("if-none-match") {
+        //If-None-Match uses the weak comparison
+        return request
+            .headers
+            .get_all("if-none-match")
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    let since = match request
+        .headers
+        .get("if-modified-since")
+        .and_then(http::parse_date)
+    {
+        Some(since) => since,
+        None => return false,
+    };
+    seconds(modified) <= seconds(since)
+}
+
+///Dates in headers only carry whole seconds.
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_ranges("bytes=0-9", 100), Some(vec![(0, 10)]));
+        assert_eq!(parse_ranges("bytes=90-", 100), Some(vec![(90, 100)]));
+        assert_eq!(parse_ranges("bytes=90-200", 100), Some(vec![(90, 100)]));
+        assert_eq!(parse_ranges("bytes=-5", 100), Some(vec![(95, 100)]));
+        assert_eq!(parse_ranges("bytes=-500", 100), Some(vec![(0, 100)]));
+        assert_eq!(
+            parse_ranges("bytes=20-29, 0-9,10-14", 100),
+            Some(vec![(0, 15), (20, 30)])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_ranges("bytes=100-", 100), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=-0", 100), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=100-200, -0", 100), Some(vec![]));
+        assert_eq!(parse_ranges("bytes=100-, 0-0", 100), Some(vec![(0, 1)]));
+    }
+
+    #[test]
+    fn ranges_of_an_empty_file() {
+        for header in ["bytes=-5", "bytes=0-", "bytes=0-0", "bytes=-5, 0-"] {
+            assert_eq!(parse_ranges(header, 0), Some(vec![]), "{header}");
+        }
+    }
+
+    #[test]
+    fn ignored_ranges() {
+        assert_eq!(parse_ranges("items=0-9", 100), None);
+        assert_eq!(parse_ranges("bytes=9-0", 100), None);
+        assert_eq!(parse_ranges("bytes=a-9", 100), None);
+        assert_eq!(parse_ranges("bytes=0-9;x", 100), None);
+    }
+
+    ///A web root with a sibling directory it must not leak, removed on drop.
+    struct Site {
+        dir: PathBuf,
+    }
+
+    impl Site {
+        fn new() -> Site {
+            let dir = std::env::temp_dir().join(format!("webber-files-{}", Uuid::new_v4()));
+            fs::create_dir_all(dir.join("root/docs")).unwrap();
+            fs::create_dir_all(dir.join("root/empty")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            fs::write(dir.join("root/index.html"), "index").unwrap();
+            fs::write(dir.join("root/app.js"), "app").unwrap();
+            fs::write(dir.join("root/docs/index.html"), "docs").unwrap();
+            fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+            std::os::unix::fs::symlink(dir.join("outside"), dir.join("root/escape")).unwrap();
+            std::os::unix::fs::symlink(dir.join("outside/secret.txt"), dir.join("root/leak.txt"))
+                .unwrap();
+            Site { dir }
+        }
+
+        fn root(&self) -> String {
+            self.dir.join("root").to_string_lossy().into_owned()
+        }
+
+        fn file(&self, path: &str) -> PathBuf {
+            fs::canonicalize(self.dir.join(path)).unwrap()
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn leaves_root(found: Lookup) -> bool {
+        matches!(found, Lookup::NotFound(_))
+    }
+
+    #[test]
+    fn lookups_inside_the_root() {
+        let site = Site::new();
+        let root = site.root();
+        assert!(
+            matches!(lookup(&root, "/app.js"), Lookup::File(path) if path == site.file("root/app.js"))
+        );
+        assert!(
+            matches!(lookup(&root, "/"), Lookup::File(path) if path == site.file("root/index.html"))
+        );
+        assert!(
+            matches!(lookup(&root, "/docs/"), Lookup::File(path) if path == site.file("root/docs/index.html"))
+        );
+        assert!(
+            matches!(lookup(&root, "/./docs/./"), Lookup::File(path) if path == site.file("root/docs/index.html"))
+        );
+        assert!(matches!(lookup(&root, "/docs"), Lookup::Directory));
+        assert!(matches!(lookup(&root, "/empty/"), Lookup::NotFound(_)));
+        assert!(matches!(lookup(&root, "/missing.js"), Lookup::NotFound(_)));
+    }
+
+    #[test]
+    fn lookups_stay_in_the_root() {
+        let site = Site::new();
+        let root = site.root();
+        for raw in [
+            "/../outside/secret.txt",
+            "/docs/../../outside/secret.txt",
+            "/%2e%2e/outside/secret.txt",
+            "/docs/%2E%2E/%2e%2e/outside/secret.txt",
+            "/%2e%2e%2foutside%2fsecret.txt",
+            "/escape/secret.txt",
+            "/leak.txt",
+            "/app.js%00.html",
+            "/..%5coutside%5csecret.txt",
+            "/docs\\..\\..\\outside\\secret.txt",
+        ] {
+            let path = percent_decode(raw);
+            assert!(leaves_root(lookup(&root, &path)), "{raw}");
+            assert!(leaves_root(resolve(&root, false, &path)), "{raw}");
+        }
+    }
+
+    #[test]
+    fn spa_fallback() {
+        let site = Site::new();
+        let root = site.root();
+        let index = site.file("root/index.html");
+        assert!(matches!(resolve(&root, true, "/tasks/12"), Lookup::File(path) if path == index));
+        assert!(matches!(
+            resolve(&root, false, "/tasks/12"),
+            Lookup::NotFound(_)
+        ));
+        //Missing assets stay missing rather than turning into html
+        assert!(matches!(
+            resolve(&root, true, "/missing.js"),
+            Lookup::NotFound(_)
+        ));
+        //The fallback doesn't reopen escapes either
+        assert!(matches!(
+            resolve(&root, true, "/escape/secret.txt"),
+            Lookup::NotFound(_)
+        ));
+        assert!(
+            matches!(resolve(&root, true, "/../outside/secret"), Lookup::File(path) if path == index)
+        );
+        assert!(
+            matches!(resolve(&root, true, "/app.js"), Lookup::File(path) if path == site.file("root/app.js"))
+        );
+
+        fs::remove_file(site.dir.join("root/index.html")).unwrap();
+        assert!(matches!(
+            resolve(&root, true, "/tasks/12"),
+            Lookup::NotFound(_)
+        ));
+    }
+
+    #[test]
+    fn directory_redirects_stay_on_the_host() {
+        assert_eq!(directory_location("/docs", "/docs"), "/docs/");
+        assert_eq!(directory_location("/docs", "/docs?page=2"), "/docs/?page=2");
+        assert_eq!(
+            directory_location("//example.com", "//example.com"),
+            "/example.com/"
+        );
+        assert_eq!(
+            directory_location("///example.com/docs", "///example.com/docs?a"),
+            "/example.com/docs/?a"
+        );
+    }
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /app.js HTTP/1.1\r\nHost: files.test\r\n{headers}\r\n");
+        http::read_request(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn etags() {
+        let site = Site::new();
+        let path = site.dir.join("root/app.js");
+        let metadata = fs::metadata(&path).unwrap();
+        let tag = etag(&metadata, Encoding::Identity);
+        assert!(tag.starts_with('"') && tag.ends_with('"'), "{tag}");
+        assert_eq!(tag, etag(&fs::metadata(&path).unwrap(), Encoding::Identity));
+        assert_ne!(tag, etag(&metadata, Encoding::Gzip));
+        assert_ne!(
+            etag(&metadata, Encoding::Gzip),
+            etag(&metadata, Encoding::Brotli)
+        );
+
+        fs::write(&path, "a longer app").unwrap();
+        assert_ne!(tag, etag(&fs::metadata(&path).unwrap(), Encoding::Identity));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let tag = "\"64-1\"";
+        let fresh = |headers: &str| not_modified(&request(headers), tag, modified);
+
+        assert!(!fresh(""));
+        assert!(fresh("If-None-Match: \"64-1\"\r\n"));
+        assert!(fresh("If-None-Match: \"a\", \"64-1\" ,\"b\"\r\n"));
+        assert!(fresh("If-None-Match: \"a\"\r\nIf-None-Match: \"64-1\"\r\n"));
+        assert!(fresh("If-None-Match: *\r\n"));
+        //Weak comparison, the W/ prefix doesn't matter
+        assert!(fresh("If-None-Match: W/\"64-1\"\r\n"));
+        assert!(!fresh("If-None-Match: \"64-2\", W/\"64\"\r\n"));
+        assert!(!fresh("If-None-Match: 64-1\r\n"));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let tag = "\"64-1\"";
+        let fresh = |headers: &str| not_modified(&request(headers), tag, modified);
+        let date = |secs: u64| http::format_date(UNIX_EPOCH + Duration::from_secs(secs));
+
+        //Dates only carry whole seconds, so the half second is ignored
+        assert!(fresh(&format!(
+            "If-Modified-Since: {}\r\n",
+            date(1_700_000_000)
+        )));
+        assert!(fresh(&format!(
+            "If-Modified-Since: {}\r\n",
+            date(1_700_000_100)
+        )));
+        assert!(!fresh(&format!(
+            "If-Modified-Since: {}\r\n",
+            date(1_699_999_999)
+        )));
+        assert!(!fresh("If-Modified-Since: yesterday\r\n"));
+
+        //If-None-Match takes precedence whichever way it goes
+        let current = date(1_700_000_000);
+        assert!(!fresh(&format!(
+            "If-None-Match: \"other\"\r\nIf-Modified-Since: {current}\r\n"
+        )));
+        assert!(fresh(&format!(
+            "If-None-Match: \"64-1\"\r\nIf-Modified-Since: {}\r\n",
+            date(1_600_000_000)
+        )));
+    }
+
+    #[test]
+    fn if_range_validators() {
+        let modified = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let tag = "\"64-1\"";
+        let honoured = |headers: &str| if_range(&request(headers), tag, modified);
+        let date = |secs: u64| http::format_date(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert!(honoured(""));
+        assert!(honoured("If-Range: \"64-1\"\r\n"));
+        assert!(!honoured("If-Range: \"64-2\"\r\n"));
+        //If-Range uses the strong comparison, weak tags never match
+        assert!(!honoured("If-Range: W/\"64-1\"\r\n"));
+
+        //A date has to be exactly the modification time, not just later
+        assert!(honoured(&format!("If-Range: {}\r\n", date(1_700_000_000))));
+        assert!(!honoured(&format!("If-Range: {}\r\n", date(1_700_000_100))));
+        assert!(!honoured(&format!("If-Range: {}\r\n", date(1_699_999_999))));
+        assert!(!honoured("If-Range: yesterday\r\n"));
+    }
+
+    #[test]
+    fn cache_drops_the_least_recently_used() {
+        let site = Site::new();
+        let cache = FileCache {
+            entries: Mutex::new(Entries {
+                by_path: HashMap::new(),
+                size: 0,
+            }),
+            max_file_size: 1000,
+            max_size: 250,
+        };
+        let file = |name: &str, len: usize| {
+            let path = site.dir.join("root").join(name);
+            fs::write(&path, vec![b'x'; len]).unwrap();
+            path
+        };
+        let get = |path: &Path| {
+            let data = cache
+                .get(path, &fs::metadata(path).unwrap(), Encoding::Identity)
+                .unwrap();
+            //Entries are told apart by when they were last used
+            thread::sleep(Duration::from_millis(2));
+            data.len()
+        };
+        let cached = || {
+            let entries = cache.entries.lock().unwrap();
+            let total: u64 = entries.by_path.values().map(Entry::size).sum();
+            assert_eq!(entries.size, total);
+            let mut names: Vec<String> = entries
+                .by_path
+                .keys()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            (names, total)
+        };
+
+        let (a, b, c) = (file("a", 100), file("b", 100), file("c", 100));
+        get(&a);
+        get(&b);
+        assert_eq!(cached(), (vec![String::from("a"), String::from("b")], 200));
+        get(&a);
+        get(&c);
+        assert_eq!(cached(), (vec![String::from("a"), String::from("c")], 200));
+
+        //A changed file replaces its entry instead of adding to it
+        fs::write(&a, vec![b'y'; 40]).unwrap();
+        assert_eq!(get(&a), 40);
+        assert_eq!(cached(), (vec![String::from("a"), String::from("c")], 140));
+
+        //A file larger than the whole cache pushes everything else out
+        let d = file("d", 240);
+        get(&d);
+        assert_eq!(cached(), (vec![String::from("d")], 240));
+    }
+}
@@ -0,0 +1,1033 @@
+//!Header compression for HTTP/2, RFC 7541.
+//!Responses are encoded without the dynamic table, only decoding has to
+//!keep up with whatever the client chooses to index.
+
+use std::{collections::VecDeque, fmt, sync::OnceLock};
+
+///Dynamic table size the decoder allows, the default of RFC 9113.
+pub const TABLE_SIZE: usize = 4096;
+///Each table entry counts its name and value plus this much overhead.
+const ENTRY_OVERHEAD: usize = 32;
+///Integers longer than this are no sizes or indexes we could ever accept.
+const MAX_INTEGER: usize = 1 << 24;
+const EOS: u16 = 256;
+
+#[derive(Debug)]
+pub struct DecodeError(&'static str);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+///Decoder state of one connection. Every header block the client sends
+///has to pass through it in order, even those of rejected streams.
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+
+    ///Decodes a complete header block into name value pairs.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, DecodeError> {
+        let mut fields = Vec::new();
+        while let Some(&byte) = block.first() {
+            if byte & 0x80 != 0 {
+                let index = read_integer(&mut block, 7)?;
+                fields.push(self.entry(index)?);
+            } else if byte & 0x40 != 0 {
+                let field = self.read_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if byte & 0x20 != 0 {
+                //Size updates may only open a block
+                if !fields.is_empty() {
+                    return Err(DecodeError("Table size update after a header field"));
+                }
+                let size = read_integer(&mut block, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(DecodeError("Table size update above the limit"));
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                //Without indexing and never indexed only differ for proxies
+                fields.push(self.read_literal(&mut block, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn entry(&self, index: usize) -> Result<(String, String), DecodeError> {
+        if index == 0 {
+            return Err(DecodeError("Index 0"));
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.to_string(), value.to_string()));
+        }
+        match self.table.get(index - STATIC_TABLE.len() - 1) {
+            Some(field) => Ok(field.clone()),
+            None => Err(DecodeError("Index past the end of the table")),
+        }
+    }
+
+    fn read_literal(&self, block: &mut &[u8], prefix: u8) -> Result<(String, String), DecodeError> {
+        let name = match read_integer(block, prefix)? {
+            0 => read_string(block)?,
+            index => self.entry(index)?.0,
+        };
+        let value = read_string(block)?;
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        //An entry larger than the whole table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    ///Drops the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+///Encodes `fields` as one header block. Fields in the static table become
+///an index, everything else a literal the client won't index.
+pub fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for &(name, value) in fields {
+        if let Some(i) = STATIC_TABLE
+            .iter()
+            .position(|entry| *entry == (name, value))
+        {
+            write_integer(&mut block, 0x80, 7, i + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|entry| entry.0 == name) {
+            Some(i) => write_integer(&mut block, 0x00, 4, i + 1),
+            None => {
+                block.push(0x00);
+                write_string(&mut block, name);
+            }
+        }
+        write_string(&mut block, value);
+    }
+    block
+}
+
+///Reads an integer whose first byte keeps its flags above the low `prefix` bits.
+fn read_integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let max = (1 << prefix) - 1;
+    let (&first, rest) = block
+        .split_first()
+        .ok_or(DecodeError("Truncated integer"))?;
+    *block = rest;
+    let mut value = (first & max) as usize;
+    if value < max as usize {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block
+            .split_first()
+            .ok_or(DecodeError("Truncated integer"))?;
+        *block = rest;
+        value += ((byte & 0x7f) as usize) << shift;
+        if value > MAX_INTEGER {
+            return Err(DecodeError("Integer too large"));
+        }
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift > 28 {
+            return Err(DecodeError("Integer too large"));
+        }
+    }
+}
+
+fn write_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn read_string(block: &mut &[u8]) -> Result<String, DecodeError> {
+    let huffman = block.first().is_some_and(|byte| byte & 0x80 != 0);
+    let len = read_integer(block, 7)?;
+    if len > block.len() {
+        return Err(DecodeError("Truncated string"));
+    }
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| DecodeError("Header is not valid UTF-8"))
+}
+
+///Strings are always sent as they are, Huffman coding them is optional.
+fn write_string(block: &mut Vec<u8>, string: &str) {
+    write_integer(block, 0x00, 7, string.len());
+    block.extend_from_slice(string.as_bytes());
+}
+
+///A node of the tree built from the Huffman code, either a symbol or the
+///indexes of the nodes reached by a 0 and a 1 bit.
+enum Node {
+    Branch([usize; 2]),
+    Symbol(u16),
+}
+
+fn huffman_tree() -> &'static [Node] {
+    static TREE: OnceLock<Vec<Node>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![Node::Branch([0, 0])];
+        for (symbol, &(code, bits)) in HUFFMAN_CODE.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..bits).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                let next = match &tree[node] {
+                    Node::Branch(children) => children[bit],
+                    Node::Symbol(_) => unreachable!("Huffman code is not prefix free"),
+                };
+                node = if next != 0 {
+                    next
+                } else {
+                    tree.push(if i == 0 {
+                        Node::Symbol(symbol as u16)
+                    } else {
+                        Node::Branch([0, 0])
+                    });
+                    let added = tree.len() - 1;
+                    if let Node::Branch(children) = &mut tree[node] {
+                        children[bit] = added;
+                    }
+                    added
+                };
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut node = 0;
+    //Bits read since the last symbol, all of which were 1 so far
+    let mut padding = 0;
+    let mut all_ones = true;
+
+    for byte in bytes {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            padding += 1;
+            all_ones &= bit == 1;
+            node = match &tree[node] {
+                Node::Branch(children) => children[bit as usize],
+                Node::Symbol(_) => unreachable!(),
+            };
+            if let Node::Symbol(symbol) = tree[node] {
+                if symbol == EOS {
+                    return Err(DecodeError("EOS inside a Huffman string"));
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                padding = 0;
+                all_ones = true;
+            }
+        }
+    }
+
+    //What is left has to be a prefix of EOS, which is all ones, shorter than a byte
+    if padding > 7 || !all_ones {
+        return Err(DecodeError("Invalid Huffman padding"));
+    }
+    Ok(decoded)
+}
+
+///Appendix A of RFC 7541.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+///Appendix B of RFC 7541, the code and its length in bits for every byte and EOS.
+const HUFFMAN_CODE: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Appendix A of RFC 7541 as printed there.
+    const RFC_STATIC_TABLE: &str = "
+        | 1     | :authority                  |               |
+        | 2     | :method                     | GET           |
+        | 3     | :method                     | POST          |
+        | 4     | :path                       | /             |
+        | 5     | :path                       | /index.html   |
+        | 6     | :scheme                     | http          |
+        | 7     | :scheme                     | https         |
+        | 8     | :status                     | 200           |
+        | 9     | :status                     | 204           |
+        | 10    | :status                     | 206           |
+        | 11    | :status                     | 304           |
+        | 12    | :status                     | 400           |
+        | 13    | :status                     | 404           |
+        | 14    | :status                     | 500           |
+        | 15    | accept-charset              |               |
+        | 16    | accept-encoding             | gzip, deflate |
+        | 17    | accept-language             |               |
+        | 18    | accept-ranges               |               |
+        | 19    | accept                      |               |
+        | 20    | access-control-allow-origin |               |
+        | 21    | age                         |               |
+        | 22    | allow                       |               |
+        | 23    | authorization               |               |
+        | 24    | cache-control               |               |
+        | 25    | content-disposition         |               |
+        | 26    | content-encoding            |               |
+        | 27    | content-language            |               |
+        | 28    | content-length              |               |
+        | 29    | content-location            |               |
+        | 30    | content-range               |               |
+        | 31    | content-type                |               |
+        | 32    | cookie                      |               |
+        | 33    | date                        |               |
+        | 34    | etag                        |               |
+        | 35    | expect                      |               |
+        | 36    | expires                     |               |
+        | 37    | from                        |               |
+        | 38    | host                        |               |
+        | 39    | if-match                    |               |
+        | 40    | if-modified-since           |               |
+        | 41    | if-none-match               |               |
+        | 42    | if-range                    |               |
+        | 43    | if-unmodified-since         |               |
+        | 44    | last-modified               |               |
+        | 45    | link                        |               |
+        | 46    | location                    |               |
+        | 47    | max-forwards                |               |
+        | 48    | proxy-authenticate          |               |
+        | 49    | proxy-authorization         |               |
+        | 50    | range                       |               |
+        | 51    | referer                     |               |
+        | 52    | refresh                     |               |
+        | 53    | retry-after                 |               |
+        | 54    | server                      |               |
+        | 55    | set-cookie                  |               |
+        | 56    | strict-transport-security   |               |
+        | 57    | transfer-encoding           |               |
+        | 58    | user-agent                  |               |
+        | 59    | vary                        |               |
+        | 60    | via                         |               |
+        | 61    | www-authenticate            |               |";
+
+    #[test]
+    fn static_table_matches_rfc() {
+        let rows: Vec<(usize, &str, &str)> = RFC_STATIC_TABLE
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let cells: Vec<&str> = line.split('|').map(str::trim).collect();
+                (cells[1].parse().unwrap(), cells[2], cells[3])
+            })
+            .collect();
+        assert_eq!(rows.len(), STATIC_TABLE.len());
+        for (index, name, value) in rows {
+            assert_eq!(STATIC_TABLE[index - 1], (name, value), "entry {index}");
+        }
+    }
+
+    #[test]
+    fn decodes_indexed_static_fields() {
+        let mut decoder = Decoder::new();
+        //Indexed fields 2 and 15, then 15 as a name with a literal value
+        let fields = decoder
+            .decode(&[0x82, 0x8f, 0x0f, 0x00, 0x05, b'u', b't', b'f', b'-', b'8'])
+            .unwrap();
+        assert_eq!(
+            fields,
+            [
+                (String::from(":method"), String::from("GET")),
+                (String::from("accept-charset"), String::new()),
+                (String::from("accept-charset"), String::from("utf-8")),
+            ]
+        );
+    }
+
+    ///Bytes from a hex dump as RFC 7541 prints them.
+    fn hex(dump: &str) -> Vec<u8> {
+        let digits: Vec<u8> = dump.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    ///Decodes `block` and checks the fields and what the table holds afterwards.
+    fn decodes(
+        decoder: &mut Decoder,
+        block: &str,
+        expected: &[(&str, &str)],
+        table: &[(&str, &str)],
+        size: usize,
+    ) {
+        assert_eq!(decoder.decode(&hex(block)).unwrap(), fields(expected));
+        assert_eq!(Vec::from(decoder.table.clone()), fields(table));
+        assert_eq!(decoder.size, size);
+    }
+
+    #[test]
+    fn huffman_strings() {
+        //The strings of RFC 7541 C.4 and C.6
+        for (code, text) in [
+            ("f1e3 c2e5 f23a 6ba0 ab90 f4ff", "www.example.com"),
+            ("a8eb 1064 9cbf", "no-cache"),
+            ("25a8 49e9 5ba9 7d7f", "custom-key"),
+            ("25a8 49e9 5bb8 e8b4 bf", "custom-value"),
+            ("6402", "302"),
+            ("aec3 771a 4b", "private"),
+            (
+                "d07a be94 1054 d444 a820 0595 040b 8166 e082 a62d 1bff",
+                "Mon, 21 Oct 2013 20:13:21 GMT",
+            ),
+            (
+                "9d29 ad17 1863 c78f 0b97 c8e9 ae82 ae43 d3",
+                "https://www.example.com",
+            ),
+            ("9bd9 ab", "gzip"),
+        ] {
+            assert_eq!(huffman_decode(&hex(code)).unwrap(), text.as_bytes());
+        }
+        assert_eq!(huffman_decode(&[]).unwrap(), b"");
+    }
+
+    #[test]
+    fn invalid_huffman_strings() {
+        //'a' is 00011, padded with zeros instead of ones
+        assert!(huffman_decode(&[0b0001_1000]).is_err());
+        //A whole byte of padding is longer than any prefix of EOS may be
+        assert!(huffman_decode(&[0b0001_1111, 0xff]).is_err());
+        //EOS itself, 30 ones, padded to 32 bits
+        assert!(huffman_decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn rfc_field_examples() {
+        //C.2.1, literal with indexing
+        let mut decoder = Decoder::new();
+        decodes(
+            &mut decoder,
+            "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+            &[("custom-key", "custom-header")],
+            &[("custom-key", "custom-header")],
+            55,
+        );
+
+        //C.2.2, literal without indexing
+        let mut decoder = Decoder::new();
+        decodes(
+            &mut decoder,
+            "040c 2f73 616d 706c 652f 7061 7468",
+            &[(":path", "/sample/path")],
+            &[],
+            0,
+        );
+
+        //C.2.3, literal never indexed
+        let mut decoder = Decoder::new();
+        decodes(
+            &mut decoder,
+            "1008 7061 7373 776f 7264 0673 6563 7265 74",
+            &[("password", "secret")],
+            &[],
+            0,
+        );
+
+        //C.2.4, indexed
+        let mut decoder = Decoder::new();
+        decodes(&mut decoder, "82", &[(":method", "GET")], &[], 0);
+    }
+
+    ///The requests of C.3 and C.4, the same fields with and without Huffman coding.
+    fn rfc_requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new();
+        decodes(
+            &mut decoder,
+            blocks[0],
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ],
+            &[(":authority", "www.example.com")],
+            57,
+        );
+        decodes(
+            &mut decoder,
+            blocks[1],
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ],
+            &[
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ],
+            110,
+        );
+        decodes(
+            &mut decoder,
+            blocks[2],
+            &[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ],
+            &[
+                ("custom-key", "custom-value"),
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ],
+            164,
+        );
+    }
+
+    #[test]
+    fn rfc_request_examples() {
+        rfc_requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+        rfc_requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    ///The responses of C.5 and C.6, decoded with a table of 256 bytes so
+    ///that entries get evicted.
+    fn rfc_responses(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new();
+        decoder.max_size = 256;
+        let date = "Mon, 21 Oct 2013 20:13:21 GMT";
+        let location = "https://www.example.com";
+        decodes(
+            &mut decoder,
+            blocks[0],
+            &[
+                (":status", "302"),
+                ("cache-control", "private"),
+                ("date", date),
+                ("location", location),
+            ],
+            &[
+                ("location", location),
+                ("date", date),
+                ("cache-control", "private"),
+                (":status", "302"),
+            ],
+            222,
+        );
+        decodes(
+            &mut decoder,
+            blocks[1],
+            &[
+                (":status", "307"),
+                ("cache-control", "private"),
+                ("date", date),
+                ("location", location),
+            ],
+            &[
+                (":status", "307"),
+                ("location", location),
+                ("date", date),
+                ("cache-control", "private"),
+            ],
+            222,
+        );
+        let cookie = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+        let date = "Mon, 21 Oct 2013 20:13:22 GMT";
+        decodes(
+            &mut decoder,
+            blocks[2],
+            &[
+                (":status", "200"),
+                ("cache-control", "private"),
+                ("date", date),
+                ("location", location),
+                ("content-encoding", "gzip"),
+                ("set-cookie", cookie),
+            ],
+            &[
+                ("set-cookie", cookie),
+                ("content-encoding", "gzip"),
+                ("date", date),
+            ],
+            215,
+        );
+    }
+
+    #[test]
+    fn rfc_response_examples() {
+        rfc_responses([
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230
+             3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65
+             7861 6d70 6c65 2e63 6f6d",
+            "4803 3330 37c1 c0bf",
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220
+             474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157
+             454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076
+             6572 7369 6f6e 3d31",
+        ]);
+        rfc_responses([
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0
+             82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+            "4883 640e ffc1 c0bf",
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b
+             d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27
+             0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        ]);
+    }
+
+    #[test]
+    fn dynamic_table_eviction() {
+        let mut decoder = Decoder::new();
+        //Each entry takes 32 + 1 + 1023 bytes, so only three fit in 4096
+        let value = "v".repeat(1023);
+        for name in ["a", "b", "c", "d"] {
+            let mut block = vec![0x40];
+            write_string(&mut block, name);
+            write_string(&mut block, &value);
+            decoder.decode(&block).unwrap();
+        }
+        assert_eq!(decoder.size, 3 * 1056);
+        let names: Vec<&str> = decoder
+            .table
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["d", "c", "b"]);
+        //Index 62 is the newest entry, 64 the oldest still there
+        assert_eq!(decoder.decode(&[0xbe, 0xc0]).unwrap()[1].0, "b");
+        assert!(decoder.decode(&[0xc1]).is_err());
+
+        //An entry larger than the whole table leaves it empty
+        let mut block = vec![0x40];
+        write_string(&mut block, "huge");
+        write_string(&mut block, &"v".repeat(TABLE_SIZE));
+        assert_eq!(decoder.decode(&block).unwrap().len(), 1);
+        assert!(decoder.table.is_empty());
+        assert_eq!(decoder.size, 0);
+    }
+
+    #[test]
+    fn table_size_updates() {
+        let mut decoder = Decoder::new();
+        decoder
+            .decode(&hex(
+                "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+            ))
+            .unwrap();
+        decoder
+            .decode(&hex("4004 6e61 6d65 0576 616c 7565"))
+            .unwrap();
+        assert_eq!(decoder.size, 55 + 41);
+
+        //Shrinking evicts the oldest entries, 0 empties the table
+        assert_eq!(decoder.decode(&[0x3f, 0x1a, 0xbe]).unwrap().len(), 1);
+        assert_eq!(decoder.max_size, 57);
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.decode(&[0x20]).unwrap(), []);
+        assert!(decoder.table.is_empty());
+
+        //Several updates may open a block, growing again up to the limit
+        decoder.decode(&[0x20, 0x3f, 0xe1, 0x1f]).unwrap();
+        assert_eq!(decoder.max_size, TABLE_SIZE);
+        assert!(decoder.decode(&[0x3f, 0xe2, 0x1f]).is_err());
+        //But not follow a field
+        assert!(decoder.decode(&[0x82, 0x20]).is_err());
+    }
+
+    #[test]
+    fn malformed_blocks() {
+        let mut decoder = Decoder::new();
+        for block in [
+            &[0x80][..],
+            &[0xbe],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0xff],
+            &[0x40, 0x05, b'a'],
+            &[0x00, 0x01, b'a', 0x01, 0xff],
+        ] {
+            assert!(decoder.decode(block).is_err(), "{block:x?}");
+        }
+    }
+}
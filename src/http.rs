@@ -0,0 +1,733 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    net::IpAddr,
+    time::SystemTime,
+};
+
+///Longest request line accepted, anything longer is answered with 414.
+pub const MAX_REQUEST_LINE: usize = 8 * 1024;
+///Combined size of all header lines, anything more is answered with 431.
+pub const MAX_HEADER_BYTES: usize = 16 * 1024;
+pub const MAX_HEADERS: usize = 100;
+//lets have a limit of one mibibyte as for now
+pub const MAX_BODY: usize = 1024 * 1024;
+
+///Fields a client may not smuggle in after the body has been read.
+pub const FORBIDDEN_TRAILERS: &[&str] = &[
+    "authority",
+    "connection",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "cookie",
+    "host",
+    "transfer-encoding",
+    "trailer",
+    "x-csrf-token",
+];
+
+pub enum HttpError {
+    BadRequest = 400,
+    Forbidden = 403,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    LengthRequired = 411,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UpgradeRequired = 426,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    HttpVersionNotSupported = 505,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    ///Requests carried by `http2`, and the preface its clients open with.
+    Http2,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Version::Http10 => write!(f, "HTTP/1.0"),
+            Version::Http11 => write!(f, "HTTP/1.1"),
+            Version::Http2 => write!(f, "HTTP/2"),
+        }
+    }
+}
+
+///Header fields in the order they arrived. Names are stored lowercased
+///and the same name may appear more than once.
+#[derive(Debug, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    ///First value of the header named `name`, case insensitive.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_ascii_lowercase(), value.to_string()));
+    }
+
+    ///Replaces every header named `name` with a single one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.push(name, value);
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    ///Path and query exactly as sent. An absolute-form target is cut down
+    ///to these, with its authority moved into the Host header.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    ///Address of the client, filled in by whoever knows the connection.
+    pub peer: Option<IpAddr>,
+}
+
+impl Request {
+    ///The target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => self.target.as_str(),
+        }
+    }
+
+    ///Whether the client wants the connection kept open after the response.
+    ///HTTP/1.0 connections are closed unless the client asks otherwise.
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |wanted: &str| {
+            self.headers
+                .get_all("connection")
+                .flat_map(|value| value.split(','))
+                .any(|option| option.trim().eq_ignore_ascii_case(wanted))
+        };
+        match self.version {
+            Version::Http10 => has_option("keep-alive"),
+            _ => !has_option("close"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    BadRequest(String),
+    UriTooLong,
+    HeadersTooLarge,
+    PayloadTooLarge,
+    VersionNotSupported(String),
+    NotImplemented(String),
+    Io(io::Error),
+}
+
+impl ParseError {
+    pub fn status(&self) -> HttpError {
+        match self {
+            ParseError::BadRequest(_) | ParseError::Io(_) => HttpError::BadRequest,
+            ParseError::UriTooLong => HttpError::UriTooLong,
+            ParseError::HeadersTooLarge => HttpError::RequestHeaderFieldsTooLarge,
+            ParseError::PayloadTooLarge => HttpError::PayloadTooLarge,
+            ParseError::VersionNotSupported(_) => HttpError::HttpVersionNotSupported,
+            ParseError::NotImplemented(_) => HttpError::NotImplemented,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(reason) => write!(f, "{reason}"),
+            ParseError::UriTooLong => write!(f, "Request line exceeds {MAX_REQUEST_LINE} bytes"),
+            ParseError::HeadersTooLarge => write!(
+                f,
+                "Headers exceed {MAX_HEADER_BYTES} bytes or {MAX_HEADERS} fields"
+            ),
+            ParseError::PayloadTooLarge => write!(f, "Body exceeds {MAX_BODY} bytes"),
+            ParseError::VersionNotSupported(version) => write!(f, "{version} is not supported"),
+            ParseError::NotImplemented(reason) => write!(f, "{reason}"),
+            ParseError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
+///Reads one request off `reader`.
+///Returns Ok(None) when the peer closed the connection before sending anything.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, ParseError> {
+    //RFC 9112 asks servers to skip empty lines in front of the request line
+    let mut request_line = Vec::new();
+    loop {
+        match read_line(reader, MAX_REQUEST_LINE, &mut request_line)? {
+            LineEnd::Eof if request_line.is_empty() => return Ok(None),
+            LineEnd::Eof => return Err(bad_request("Connection closed mid request line")),
+            LineEnd::TooLong => return Err(ParseError::UriTooLong),
+            LineEnd::Line if request_line.is_empty() => continue,
+            LineEnd::Line => break,
+        }
+    }
+    let (method, target, version, authority) = parse_request_line(&request_line)?;
+
+    let mut headers = Headers::default();
+    let mut header_bytes = 0;
+    let mut line = Vec::new();
+    loop {
+        let remaining = MAX_HEADER_BYTES.saturating_sub(header_bytes);
+        match read_line(reader, remaining, &mut line)? {
+            LineEnd::Eof => return Err(bad_request("Connection closed mid headers")),
+            LineEnd::TooLong => return Err(ParseError::HeadersTooLarge),
+            LineEnd::Line if line.is_empty() => break,
+            LineEnd::Line => (),
+        }
+
+        header_bytes += line.len() + 2;
+        if headers.0.len() == MAX_HEADERS {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_header(&line)?;
+        headers.push(name, value);
+    }
+
+    //RFC 9112 has the authority of an absolute-form target win over Host
+    match authority {
+        Some(authority) => headers.set("host", &authority),
+        None if version == Version::Http11 && headers.get_all("host").count() != 1 => {
+            return Err(bad_request("HTTP/1.1 requires exactly one Host header"));
+        }
+        None => (),
+    }
+
+    let body = read_body(reader, &mut headers)?;
+
+    Ok(Some(Request {
+        method,
+        target,
+        version,
+        headers,
+        body,
+        peer: None,
+    }))
+}
+
+enum LineEnd {
+    Line,
+    TooLong,
+    Eof,
+}
+
+///Reads up to and including the next LF into `line`, without the line ending.
+///A lone LF is accepted as a line ending just like CRLF.
+fn read_line<R: BufRead>(reader: &mut R, max: usize, line: &mut Vec<u8>) -> io::Result<LineEnd> {
+    line.clear();
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            return Ok(LineEnd::Eof);
+        }
+
+        let (chunk, found) = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => (&available[..i + 1], true),
+            None => (available, false),
+        };
+        let used = chunk.len();
+        line.extend_from_slice(chunk);
+        reader.consume(used);
+
+        if found {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Ok(if line.len() > max {
+                LineEnd::TooLong
+            } else {
+                LineEnd::Line
+            });
+        }
+        if line.len() > max {
+            return Ok(LineEnd::TooLong);
+        }
+    }
+}
+
+///Method, target in origin-form, version and the authority of an
+///absolute-form target.
+fn parse_request_line(
+    line: &[u8],
+) -> Result<(String, String, Version, Option<String>), ParseError> {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return Err(bad_request("Request line is not valid UTF-8")),
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    let [method, target, version] = parts[..] else {
+        return Err(bad_request("Request line must be METHOD TARGET VERSION"));
+    };
+
+    if method.is_empty() || !method.bytes().all(is_token) {
+        return Err(bad_request("Invalid method"));
+    }
+    if target.bytes().any(|b| b.is_ascii_control() || b == b' ') {
+        return Err(bad_request("Invalid request target"));
+    }
+    let (target, authority) = match absolute_form(target) {
+        Some((authority, path)) => (path, Some(authority)),
+        None if target.starts_with('/') || target == "*" => (target.to_string(), None),
+        None => return Err(bad_request("Invalid request target")),
+    };
+    //Userinfo in the authority is deprecated and only good for phishing
+    if authority
+        .as_ref()
+        .is_some_and(|authority| authority.is_empty() || authority.contains('@'))
+    {
+        return Err(bad_request("Invalid request target"));
+    }
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        //The HTTP/2 connection preface starts out looking like this request
+        "HTTP/2.0" if method == "PRI" && target == "*" => Version::Http2,
+        other => match other.strip_prefix("HTTP/") {
+            Some(number)
+                if number.len() == 3
+                    && number.as_bytes()[0].is_ascii_digit()
+                    && number.as_bytes()[1] == b'.'
+                    && number.as_bytes()[2].is_ascii_digit() =>
+            {
+                return Err(ParseError::VersionNotSupported(other.to_string()));
+            }
+            _ => return Err(bad_request("Invalid HTTP version")),
+        },
+    };
+
+    Ok((method.to_string(), target, version, authority))
+}
+
+///Splits `http://authority/path?query` into the authority and the path
+///with its query. An empty path becomes `/`.
+fn absolute_form(target: &str) -> Option<(String, String)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{path}")
+    };
+    Some((authority.to_string(), path))
+}
+
+fn parse_header(line: &[u8]) -> Result<(&str, &str), ParseError> {
+    if line[0] == b' ' || line[0] == b'\t' {
+        return Err(bad_request("Obsolete header line folding"));
+    }
+
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => return Err(bad_request("Header is not valid UTF-8")),
+    };
+    let (name, value) = match line.split_once(':') {
+        Some(pair) => pair,
+        None => return Err(bad_request("Header without colon")),
+    };
+
+    if name.is_empty() || !name.bytes().all(is_token) {
+        return Err(bad_request("Invalid header name"));
+    }
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(bad_request("Invalid header value"));
+    }
+
+    Ok((name, value))
+}
+
+fn read_body<R: BufRead>(reader: &mut R, headers: &mut Headers) -> Result<Vec<u8>, ParseError> {
+    if headers.contains("transfer-encoding") {
+        //Both framings at once is how requests get smuggled past proxies
+        if headers.contains("content-length") {
+            return Err(bad_request(
+                "Transfer-Encoding and Content-Length are mutually exclusive",
+            ));
+        }
+        let codings: Vec<&str> = headers
+            .get_all("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim())
+            .collect();
+        if codings.len() != 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::NotImplemented(String::from(
+                "Only Transfer-Encoding: chunked is supported",
+            )));
+        }
+        return read_chunked(reader, headers);
+    }
+
+    let mut length: Option<usize> = None;
+    for value in headers.get_all("content-length") {
+        let parsed = match value.parse::<usize>() {
+            Ok(parsed) if value.bytes().all(|b| b.is_ascii_digit()) => parsed,
+            _ => return Err(bad_request("Invalid Content-Length")),
+        };
+        if length.is_some_and(|length| length != parsed) {
+            return Err(bad_request("Conflicting Content-Length headers"));
+        }
+        length = Some(parsed);
+    }
+
+    let length = length.unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(ParseError::PayloadTooLarge);
+    }
+
+    let mut body = Vec::with_capacity(length);
+    reader.take(length as u64).read_to_end(&mut body)?;
+    if body.len() != length {
+        return Err(bad_request("Body shorter than Content-Length"));
+    }
+
+    Ok(body)
+}
+
+///Decodes a chunked body. Trailer fields are appended to `headers` unless
+///they could change how the request is framed, routed or authorized.
+fn read_chunked<R: BufRead>(reader: &mut R, headers: &mut Headers) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        match read_line(reader, MAX_REQUEST_LINE, &mut line)? {
+            LineEnd::Eof => return Err(bad_request("Connection closed mid chunk size")),
+            LineEnd::TooLong => return Err(bad_request("Chunk size line too long")),
+            LineEnd::Line => (),
+        }
+
+        //Chunk extensions after ';' carry nothing we use
+        let size = match line.iter().position(|b| *b == b';') {
+            Some(i) => &line[..i],
+            None => &line[..],
+        };
+        let size = size.trim_ascii_end();
+        if size.is_empty() || size.len() > 8 || !size.iter().all(u8::is_ascii_hexdigit) {
+            return Err(bad_request("Invalid chunk size"));
+        }
+        let size = usize::from_str_radix(std::str::from_utf8(size).unwrap(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > MAX_BODY {
+            return Err(ParseError::PayloadTooLarge);
+        }
+
+        let before = body.len();
+        reader.take(size as u64).read_to_end(&mut body)?;
+        if body.len() - before != size {
+            return Err(bad_request("Connection closed mid chunk"));
+        }
+        match read_line(reader, 0, &mut line)? {
+            LineEnd::Line => (),
+            _ => return Err(bad_request("Chunk not followed by CRLF")),
+        }
+    }
+
+    let mut trailer_bytes = 0;
+    loop {
+        let remaining = MAX_HEADER_BYTES.saturating_sub(trailer_bytes);
+        match read_line(reader, remaining, &mut line)? {
+            LineEnd::Eof => return Err(bad_request("Connection closed mid trailers")),
+            LineEnd::TooLong => return Err(ParseError::HeadersTooLarge),
+            LineEnd::Line if line.is_empty() => break,
+            LineEnd::Line => (),
+        }
+
+        trailer_bytes += line.len() + 2;
+        if headers.0.len() == MAX_HEADERS {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_header(&line)?;
+        if !FORBIDDEN_TRAILERS
+            .iter()
+            .any(|forbidden| forbidden.eq_ignore_ascii_case(name))
+        {
+            headers.push(name, value);
+        }
+    }
+
+    Ok(body)
+}
+
+///IMF-fixdate from RFC 9110, the only date format servers may send.
+const DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+pub fn format_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format(DATE_FORMAT).to_string()
+}
+
+///Parses an IMF-fixdate. The obsolete RFC 850 and asctime formats are not
+///understood, a header using them is treated as if it was never sent.
+pub fn parse_date(date: &str) -> Option<SystemTime> {
+    NaiveDateTime::parse_from_str(date.trim(), DATE_FORMAT)
+        .ok()
+        .map(|date| date.and_utc().into())
+}
+
+///tchar from RFC 9110
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn bad_request(reason: &str) -> ParseError {
+    ParseError::BadRequest(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<Request>, ParseError> {
+        read_request(&mut raw.as_bytes())
+    }
+
+    fn parse_ok(raw: &str) -> Request {
+        parse(raw).unwrap().unwrap()
+    }
+
+    fn bad(raw: &str) -> bool {
+        matches!(parse(raw), Err(ParseError::BadRequest(_)))
+    }
+
+    #[test]
+    fn parses_a_request() {
+        let request = parse_ok(
+            "\r\nPOST /api/task?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\nX-A:  b \r\n\r\n{}",
+        );
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/api/task?x=1");
+        assert_eq!(request.path(), "/api/task");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("x-a"), Some("b"));
+        assert_eq!(request.body, b"{}");
+        assert!(parse("").unwrap().is_none());
+    }
+
+    #[test]
+    fn request_line_limit() {
+        let target = "a".repeat(MAX_REQUEST_LINE);
+        let raw = format!("GET /{target} HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(matches!(parse(&raw), Err(ParseError::UriTooLong)));
+    }
+
+    #[test]
+    fn header_count_limit() {
+        let fields = "X-A: b\r\n".repeat(MAX_HEADERS - 1);
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\n{fields}\r\n");
+        assert!(parse(&raw).is_ok());
+
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\nX-A: b\r\n{fields}\r\n");
+        assert!(matches!(parse(&raw), Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn header_size_limit() {
+        let value = "b".repeat(MAX_HEADER_BYTES);
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\nX-A: {value}\r\n\r\n");
+        assert!(matches!(parse(&raw), Err(ParseError::HeadersTooLarge)));
+
+        //Many small fields count towards the same limit
+        let value = "b".repeat(MAX_HEADER_BYTES / 4);
+        let fields = format!("X-A: {value}\r\n").repeat(4);
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\n{fields}\r\n");
+        assert!(matches!(parse(&raw), Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn body_size_limit() {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert!(matches!(parse(&raw), Err(ParseError::PayloadTooLarge)));
+
+        let chunk = "a".repeat(MAX_BODY / 2 + 1);
+        let raw = format!(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            {size:x}\r\n{chunk}\r\n{size:x}\r\n{chunk}\r\n0\r\n\r\n",
+            size = chunk.len()
+        );
+        assert!(matches!(parse(&raw), Err(ParseError::PayloadTooLarge)));
+    }
+
+    #[test]
+    fn short_body() {
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nabc"
+        ));
+    }
+
+    #[test]
+    fn chunked_body() {
+        let request = parse_ok(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3;ext=1\r\nabc\r\nA\r\n0123456789\r\n0\r\nX-Trailer: t\r\nHost: evil\r\n\r\n",
+        );
+        assert_eq!(request.body, b"abc0123456789");
+        assert_eq!(request.headers.get("x-trailer"), Some("t"));
+        //Forbidden trailers are dropped
+        assert_eq!(request.headers.get_all("host").collect::<Vec<_>>(), ["a"]);
+
+        //Credentials included
+        let request = parse_ok(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\
+            Authority: t\r\nCookie: session=t\r\nX-CSRF-Token: c\r\n\r\n",
+        );
+        for name in ["authority", "cookie", "x-csrf-token"] {
+            assert!(!request.headers.contains(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn bad_chunk_sizes() {
+        for size in ["", "zz", "-1", "0x3", "123456789", "3 3"] {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{size}\r\nabc\r\n0\r\n\r\n"
+            );
+            assert!(bad(&raw), "{size:?}");
+        }
+
+        //Chunk longer than its size says
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n"
+        ));
+        //Connection closed before the last chunk
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n"
+        ));
+    }
+
+    #[test]
+    fn content_length() {
+        let request = parse_ok(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc",
+        );
+        assert_eq!(request.body, b"abc");
+
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd"
+        ));
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3, 3\r\n\r\nabc"
+        ));
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +3\r\n\r\nabc"
+        ));
+        assert!(bad(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            Err(ParseError::NotImplemented(_))
+        ));
+    }
+
+    #[test]
+    fn keep_alive() {
+        assert!(parse_ok("GET / HTTP/1.1\r\nHost: a\r\n\r\n").keep_alive());
+        assert!(
+            !parse_ok("GET / HTTP/1.1\r\nHost: a\r\nConnection: TE, close\r\n\r\n").keep_alive()
+        );
+        assert!(!parse_ok("GET / HTTP/1.0\r\n\r\n").keep_alive());
+        assert!(parse_ok("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").keep_alive());
+    }
+
+    #[test]
+    fn host_header() {
+        assert!(bad("GET / HTTP/1.1\r\n\r\n"));
+        assert!(bad("GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"));
+        //HTTP/1.0 had no Host
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn absolute_form() {
+        let request =
+            parse_ok("GET http://example.com:8080/api/task?x=1 HTTP/1.1\r\nHost: other\r\n\r\n");
+        assert_eq!(request.target, "/api/task?x=1");
+        assert_eq!(
+            request.headers.get_all("host").collect::<Vec<_>>(),
+            ["example.com:8080"]
+        );
+
+        let request = parse_ok("GET HTTPS://example.com?x=1 HTTP/1.1\r\n\r\n");
+        assert_eq!(request.target, "/?x=1");
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+
+        assert!(bad("GET http:///path HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(bad(
+            "GET http://user@example.com/ HTTP/1.1\r\nHost: a\r\n\r\n"
+        ));
+        assert!(bad("GET ftp://example.com/ HTTP/1.1\r\nHost: a\r\n\r\n"));
+    }
+
+    #[test]
+    fn bad_request_lines() {
+        assert!(bad("GET /\r\n\r\n"));
+        assert!(bad("GET  / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(bad("G(T / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(bad("GET path HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(bad("GET / HTTP/x\r\nHost: a\r\n\r\n"));
+        assert!(bad("GET /\u{1} HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(bad("GET / HTTP/1.1"));
+        assert!(matches!(
+            parse("GET / HTTP/3.0\r\nHost: a\r\n\r\n"),
+            Err(ParseError::VersionNotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert!(bad("GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n"));
+        assert!(bad("GET / HTTP/1.1\r\nHost: a\r\nNo colon\r\n\r\n"));
+        assert!(bad("GET / HTTP/1.1\r\nHost: a\r\nBad name: b\r\n\r\n"));
+        assert!(bad("GET / HTTP/1.1\r\nHost: a\r\nX-A: b\u{0}\r\n\r\n"));
+        assert!(bad("GET / HTTP/1.1\r\nHost: a\r\n"));
+    }
+}
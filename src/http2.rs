@@ -0,0 +1,1594 @@
+//!HTTP/2, RFC 9113, served by the same workers and handlers as HTTP/1.1.
+//!Frames are read off the connection as long as the client has sent any,
+//!complete requests are answered one after another. Handlers write their
+//!response as HTTP/1.1, which `ResponseWriter` turns into HEADERS and DATA
+//!frames while it is being written.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io::{self, BufReader, Read, Write},
+};
+
+use crate::connection::{Client, Parking, Responder, Sink, Stream};
+use crate::hpack::{self, Decoder};
+use crate::http::{self, Headers, ParseError, Request, Version};
+use crate::Shared;
+
+///What is left of the client preface once its first line and the blank
+///line after it were read as an HTTP/1.1 request line, see `http::read_request`.
+const PREFACE_REST: &[u8] = b"SM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+///Largest frame payload either side may send until told otherwise.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+const MAX_MAX_FRAME_SIZE: usize = 16777215;
+const DEFAULT_WINDOW: i64 = 65535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+///Streams a client may have open at once, counting those waiting for a response.
+const MAX_CONCURRENT_STREAMS: usize = 100;
+///Header blocks split over CONTINUATION frames are cut off here, long
+///before they could fit through MAX_HEADER_BYTES once decoded.
+const MAX_HEADER_BLOCK: usize = 4 * http::MAX_HEADER_BYTES;
+///Decoded size of a field counts this much on top of its name and value.
+const FIELD_OVERHEAD: usize = 32;
+
+//Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+//Flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+//Settings
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+//Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+///HTTP/1.1 fields about the connection rather than the message, HTTP/2 has no place for them.
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+///HTTP/2 state of a connection, kept in its `Client` while it is parked.
+pub struct Connection {
+    ///Part of the client preface still to be read.
+    preface: &'static [u8],
+    settings_sent: bool,
+    decoder: Decoder,
+    ///Streams whose request is still arriving.
+    incoming: HashMap<u32, Incoming>,
+    ///Requests received in full, in the order they completed.
+    ready: VecDeque<(u32, Result<Request, ParseError>)>,
+    ///A header block waiting for the rest of its CONTINUATION frames.
+    continuation: Option<Continuation>,
+    ///Highest stream id the client has opened.
+    last_stream: u32,
+    ///How much more DATA the client takes on the whole connection.
+    send_window: i64,
+    ///How much more DATA the client takes on each stream it has open.
+    stream_windows: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    ///The stream a response is being written to, and whether the client cancelled it.
+    responding: Option<u32>,
+    cancelled: bool,
+    ///Set once the client sent GOAWAY.
+    going_away: bool,
+}
+
+struct Incoming {
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    error: Option<ParseError>,
+}
+
+struct Continuation {
+    stream: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+enum Error {
+    ///Ends the connection with GOAWAY carrying the code.
+    Connection(u32, &'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl Connection {
+    ///State for a client that sent the first line of its preface as a request.
+    pub fn new() -> Connection {
+        Connection {
+            preface: PREFACE_REST,
+            settings_sent: false,
+            decoder: Decoder::new(),
+            incoming: HashMap::new(),
+            ready: VecDeque::new(),
+            continuation: None,
+            last_stream: 0,
+            send_window: DEFAULT_WINDOW,
+            stream_windows: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            responding: None,
+            cancelled: false,
+            going_away: false,
+        }
+    }
+
+    ///Reads and handles the next frame, or the rest of the preface before the first.
+    fn receive(&mut self, reader: &mut BufReader<Stream>) -> Result<(), Error> {
+        if !self.preface.is_empty() {
+            let mut preface = vec![0; self.preface.len()];
+            reader.read_exact(&mut preface)?;
+            if preface != self.preface {
+                return Err(Error::Connection(
+                    PROTOCOL_ERROR,
+                    "Invalid connection preface",
+                ));
+            }
+            self.preface = b"";
+            return Ok(());
+        }
+
+        let frame = read_frame(reader)?;
+        let out = reader.get_ref();
+        if let Some(continuation) = &self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != continuation.stream {
+                return Err(Error::Connection(
+                    PROTOCOL_ERROR,
+                    "Header block interrupted",
+                ));
+            }
+        }
+
+        match frame.kind {
+            DATA => self.on_data(out, frame),
+            HEADERS => self.on_headers(out, frame),
+            PRIORITY => match (frame.stream, frame.payload.len()) {
+                (0, _) => Err(Error::Connection(PROTOCOL_ERROR, "PRIORITY on stream 0")),
+                (_, 5) => Ok(()),
+                _ => Err(Error::Connection(
+                    FRAME_SIZE_ERROR,
+                    "PRIORITY of wrong size",
+                )),
+            },
+            RST_STREAM => self.on_rst_stream(frame),
+            SETTINGS => self.on_settings(out, frame),
+            PUSH_PROMISE => Err(Error::Connection(PROTOCOL_ERROR, "Clients can't push")),
+            PING => on_ping(out, frame),
+            GOAWAY if frame.stream != 0 => {
+                Err(Error::Connection(PROTOCOL_ERROR, "GOAWAY on a stream"))
+            }
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(out, frame),
+            CONTINUATION => self.on_continuation(out, frame),
+            //Unknown frame types are to be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, out: &Stream, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 || frame.stream.is_multiple_of(2) {
+            return Err(Error::Connection(
+                PROTOCOL_ERROR,
+                "HEADERS on a stream id clients can't use",
+            ));
+        }
+        let mut block = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            block = block.get(5..).ok_or(Error::Connection(
+                FRAME_SIZE_ERROR,
+                "HEADERS too short for its priority",
+            ))?;
+        }
+
+        let end_stream = frame.flags & END_STREAM != 0;
+        if frame.flags & END_HEADERS != 0 {
+            return self.on_header_block(out, frame.stream, end_stream, block);
+        }
+        self.continuation = Some(Continuation {
+            stream: frame.stream,
+            end_stream,
+            block: block.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn on_continuation(&mut self, out: &Stream, frame: Frame) -> Result<(), Error> {
+        let mut continuation = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => {
+                return Err(Error::Connection(
+                    PROTOCOL_ERROR,
+                    "CONTINUATION without HEADERS",
+                ))
+            }
+        };
+        continuation.block.extend_from_slice(&frame.payload);
+        if continuation.block.len() > MAX_HEADER_BLOCK {
+            return Err(Error::Connection(
+                ENHANCE_YOUR_CALM,
+                "Header block too large",
+            ));
+        }
+
+        if frame.flags & END_HEADERS != 0 {
+            return self.on_header_block(
+                out,
+                continuation.stream,
+                continuation.end_stream,
+                &continuation.block,
+            );
+        }
+        self.continuation = Some(continuation);
+        Ok(())
+    }
+
+    fn on_header_block(
+        &mut self,
+        out: &Stream,
+        stream: u32,
+        end_stream: bool,
+        block: &[u8],
+    ) -> Result<(), Error> {
+        //Decoded even for streams that get refused, to keep the table in step with the client
+        let fields = match self.decoder.decode(block) {
+            Ok(fields) => fields,
+            Err(_) => return Err(Error::Connection(COMPRESSION_ERROR, "Invalid header block")),
+        };
+
+        //A second block on an open stream is its trailers
+        if let Some(incoming) = self.incoming.get_mut(&stream) {
+            if !end_stream {
+                return self.reset(out, stream, PROTOCOL_ERROR);
+            }
+            incoming.add_trailers(fields);
+            self.complete(stream);
+            return Ok(());
+        }
+        if stream <= self.last_stream {
+            return Err(Error::Connection(
+                STREAM_CLOSED,
+                "HEADERS on a closed stream",
+            ));
+        }
+        self.last_stream = stream;
+
+        if self.stream_windows.len() >= MAX_CONCURRENT_STREAMS {
+            write_frame(out, RST_STREAM, 0, stream, &REFUSED_STREAM.to_be_bytes())?;
+            return Ok(());
+        }
+        self.stream_windows.insert(stream, self.initial_window);
+        self.incoming.insert(
+            stream,
+            Incoming {
+                fields,
+                body: Vec::new(),
+                error: None,
+            },
+        );
+        if end_stream {
+            self.complete(stream);
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, out: &Stream, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        let data = unpad(&frame)?;
+
+        //Padding counts against the window too. The body is taken right away,
+        //so the client gets its window back as soon as a frame arrives.
+        let len = frame.payload.len() as u32;
+        if len > 0 {
+            write_frame(out, WINDOW_UPDATE, 0, 0, &len.to_be_bytes())?;
+        }
+        let incoming = match self.incoming.get_mut(&frame.stream) {
+            Some(incoming) => incoming,
+            None if frame.stream > self.last_stream => {
+                return Err(Error::Connection(
+                    PROTOCOL_ERROR,
+                    "DATA on a stream never opened",
+                ));
+            }
+            None => return self.reset(out, frame.stream, STREAM_CLOSED),
+        };
+
+        if incoming.error.is_none() {
+            if incoming.body.len() + data.len() > http::MAX_BODY {
+                incoming.error = Some(ParseError::PayloadTooLarge);
+                incoming.body = Vec::new();
+            } else {
+                incoming.body.extend_from_slice(data);
+            }
+        }
+
+        if frame.flags & END_STREAM != 0 {
+            self.complete(frame.stream);
+        } else if len > 0 {
+            write_frame(out, WINDOW_UPDATE, 0, frame.stream, &len.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn on_rst_stream(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.stream == 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR, "RST_STREAM on stream 0"));
+        }
+        if frame.payload.len() != 4 {
+            return Err(Error::Connection(
+                FRAME_SIZE_ERROR,
+                "RST_STREAM of wrong size",
+            ));
+        }
+        self.close(frame.stream);
+        Ok(())
+    }
+
+    fn on_settings(&mut self, out: &Stream, frame: Frame) -> Result<(), Error> {
+        if frame.stream != 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if frame.flags & ACK != 0 {
+            return match frame.payload.len() {
+                0 => Ok(()),
+                _ => Err(Error::Connection(
+                    FRAME_SIZE_ERROR,
+                    "SETTINGS ACK with a payload",
+                )),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(
+                FRAME_SIZE_ERROR,
+                "SETTINGS of wrong size",
+            ));
+        }
+
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR, "Window too large"));
+                    }
+                    //Applies to streams already open as well
+                    let delta = value - self.initial_window;
+                    for window in self.stream_windows.values_mut() {
+                        *window += delta;
+                    }
+                    self.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(&value) {
+                        return Err(Error::Connection(PROTOCOL_ERROR, "Invalid frame size"));
+                    }
+                    self.max_frame_size = value;
+                }
+                //The encoder never uses the dynamic table and nothing is pushed,
+                //so the remaining settings don't change what is sent
+                _ => (),
+            }
+        }
+        write_frame(out, SETTINGS, ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, out: &Stream, frame: Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return Err(Error::Connection(
+                FRAME_SIZE_ERROR,
+                "WINDOW_UPDATE of wrong size",
+            ));
+        }
+        let bytes = [
+            frame.payload[0],
+            frame.payload[1],
+            frame.payload[2],
+            frame.payload[3],
+        ];
+        let increment = (u32::from_be_bytes(bytes) & 0x7fff_ffff) as i64;
+
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(Error::Connection(PROTOCOL_ERROR, "WINDOW_UPDATE of 0"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(Error::Connection(FLOW_CONTROL_ERROR, "Window too large"));
+            }
+            return Ok(());
+        }
+
+        if increment == 0 {
+            return self.reset(out, frame.stream, PROTOCOL_ERROR);
+        }
+        //Updates for streams that are done already are fine and ignored
+        if let Some(window) = self.stream_windows.get_mut(&frame.stream) {
+            *window += increment;
+            if *window > MAX_WINDOW {
+                return self.reset(out, frame.stream, FLOW_CONTROL_ERROR);
+            }
+        }
+        Ok(())
+    }
+
+    ///Moves a stream whose request is complete to the queue of those waiting for a response.
+    fn complete(&mut self, stream: u32) {
+        if let Some(incoming) = self.incoming.remove(&stream) {
+            self.ready.push_back((stream, incoming.into_request()));
+        }
+    }
+
+    ///Forgets a stream, stopping its response if one is being sent.
+    fn close(&mut self, stream: u32) {
+        self.incoming.remove(&stream);
+        self.ready.retain(|(ready, _)| *ready != stream);
+        self.stream_windows.remove(&stream);
+        if self.responding == Some(stream) {
+            self.cancelled = true;
+        }
+    }
+
+    ///Ends one stream with an error, the connection carries on.
+    fn reset(&mut self, out: &Stream, stream: u32, code: u32) -> Result<(), Error> {
+        self.close(stream);
+        write_frame(out, RST_STREAM, 0, stream, &code.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+fn on_ping(out: &Stream, frame: Frame) -> Result<(), Error> {
+    if frame.stream != 0 {
+        return Err(Error::Connection(PROTOCOL_ERROR, "PING on a stream"));
+    }
+    if frame.payload.len() != 8 {
+        return Err(Error::Connection(FRAME_SIZE_ERROR, "PING of wrong size"));
+    }
+    if frame.flags & ACK == 0 {
+        write_frame(out, PING, ACK, 0, &frame.payload)?;
+    }
+    Ok(())
+}
+
+impl Incoming {
+    ///Trailers can't carry pseudo-headers or anything that changes how the
+    ///request is framed, routed or authorized.
+    fn add_trailers(&mut self, trailers: Vec<(String, String)>) {
+        for (name, value) in trailers {
+            if name.starts_with(':') {
+                self.error = Some(bad_request("Pseudo-header in trailers"));
+            } else if !http::FORBIDDEN_TRAILERS.contains(&name.as_str()) {
+                self.fields.push((name, value));
+            }
+        }
+    }
+
+    fn into_request(self) -> Result<Request, ParseError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+
+        let mut method = None;
+        let mut scheme = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut headers = Headers::default();
+        let mut cookies = Vec::new();
+        let mut size = 0;
+        let mut regular = false;
+
+        for (i, (name, value)) in self.fields.into_iter().enumerate() {
+            size += name.len() + value.len() + FIELD_OVERHEAD;
+            if size > http::MAX_HEADER_BYTES || i >= http::MAX_HEADERS {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            if name.is_empty() || name.bytes().any(|b| b.is_ascii_uppercase()) {
+                return Err(bad_request("Header names must be lowercase"));
+            }
+            if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+                return Err(bad_request("Invalid header value"));
+            }
+
+            if let Some(pseudo) = name.strip_prefix(':') {
+                if regular {
+                    return Err(bad_request("Pseudo-header after regular headers"));
+                }
+                let slot = match pseudo {
+                    "method" => &mut method,
+                    "scheme" => &mut scheme,
+                    "path" => &mut path,
+                    "authority" => &mut authority,
+                    _ => return Err(bad_request("Unknown pseudo-header")),
+                };
+                if slot.replace(value).is_some() {
+                    return Err(bad_request("Repeated pseudo-header"));
+                }
+                continue;
+            }
+
+            regular = true;
+            if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers")
+            {
+                return Err(bad_request("Connection specific header"));
+            }
+            //Clients may split cookies into a field each for better compression
+            if name == "cookie" {
+                cookies.push(value);
+            } else {
+                headers.push(&name, &value);
+            }
+        }
+
+        let method = method.ok_or_else(|| bad_request("Missing :method"))?;
+        if method == "CONNECT" {
+            return Err(ParseError::NotImplemented(String::from(
+                "CONNECT is not supported",
+            )));
+        }
+        let (Some(_), Some(target)) = (scheme, path) else {
+            return Err(bad_request("Missing :scheme or :path"));
+        };
+        if !(target.starts_with('/') || (target == "*" && method == "OPTIONS")) {
+            return Err(bad_request("Invalid :path"));
+        }
+
+        //Handlers look for the host where HTTP/1.1 has it
+        if let Some(authority) = authority {
+            if !headers.contains("host") {
+                headers.push("host", &authority);
+            }
+        }
+        if !cookies.is_empty() {
+            headers.push("cookie", &cookies.join("; "));
+        }
+        for length in headers.get_all("content-length") {
+            if length.parse::<usize>().ok() != Some(self.body.len()) {
+                return Err(bad_request("Content-Length does not match the body"));
+            }
+        }
+
+        Ok(Request {
+            method,
+            target,
+            version: Version::Http2,
+            headers,
+            body: self.body,
+            peer: None,
+        })
+    }
+}
+
+///Serves the HTTP/2 connection of `client` for as long as it has frames
+///buffered, then parks it again or closes it. `readable` is set when the
+///client was woken from parking, with frames waiting on the socket.
+pub fn serve(
+    mut client: Client,
+    mut connection: Box<Connection>,
+    shared: &Shared,
+    parking: &Parking,
+    readable: bool,
+) {
+    match run(&mut client, &mut connection, shared, readable) {
+        Ok(true) => {
+            client.http2 = Some(connection);
+            parking.park(client);
+        }
+        Ok(false) => (),
+        Err(Error::Connection(code, reason)) => {
+            println!("Closing HTTP/2 connection: {reason}");
+            let _ = write_goaway(client.reader.get_ref(), connection.last_stream, code);
+        }
+        Err(Error::Io(err)) => {
+            //Clients closing idle connections, TLS ones without a close_notify
+            if !matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+            ) {
+                println!("HTTP/2 connection failed");
+                println!("{err}");
+            }
+        }
+    }
+}
+
+///Returns whether the connection stays open.
+fn run(
+    client: &mut Client,
+    connection: &mut Connection,
+    shared: &Shared,
+    mut readable: bool,
+) -> Result<bool, Error> {
+    if !connection.settings_sent {
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, http::MAX_HEADER_BYTES),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        write_frame(client.reader.get_ref(), SETTINGS, 0, 0, &settings)?;
+        connection.settings_sent = true;
+    }
+
+    loop {
+        if let Some((stream, request)) = connection.ready.pop_front() {
+            respond(client, connection, shared, stream, request)?;
+            continue;
+        }
+        if connection.going_away && connection.incoming.is_empty() {
+            write_goaway(client.reader.get_ref(), connection.last_stream, NO_ERROR)?;
+            return Ok(false);
+        }
+        //Frames still on the socket aren't buffered yet, parking would wake right back up
+        if !readable && !client.has_buffered() {
+            return Ok(true);
+        }
+        readable = false;
+        connection.receive(&mut client.reader)?;
+    }
+}
+
+fn respond(
+    client: &mut Client,
+    connection: &mut Connection,
+    shared: &Shared,
+    stream: u32,
+    mut request: Result<Request, ParseError>,
+) -> Result<(), Error> {
+    if let Ok(request) = &mut request {
+        request.peer = client.reader.get_ref().peer_ip();
+    }
+    connection.responding = Some(stream);
+    connection.cancelled = false;
+    let writer = RefCell::new(ResponseWriter {
+        connection,
+        reader: &mut client.reader,
+        stream,
+        head: Vec::new(),
+        head_done: false,
+        fields: None,
+        failure: None,
+    });
+
+    let aborted = {
+        let responder = Responder::with_sink(&writer, Version::Http2);
+        match &request {
+            Ok(request) => {
+                //The query is left out, it can carry an authority
+                println!("{} {} {}", request.method, request.path(), request.version);
+                let responder = crate::configure(responder, request, &shared.settings);
+                crate::handle_request(&responder, request, shared);
+                !responder.keep_alive()
+            }
+            Err(err) => {
+                println!("Rejected request: {err}");
+                crate::serve_error_json(&responder, err.status(), err.to_string());
+                false
+            }
+        }
+    };
+    writer.into_inner().finish(aborted)
+}
+
+///Takes a response written as HTTP/1.1 and sends it on one stream. The
+///head becomes a HEADERS frame, held back until the first bytes of the
+///body so that a response without one fits in a single frame.
+struct ResponseWriter<'a> {
+    connection: &'a mut Connection,
+    reader: &'a mut BufReader<Stream>,
+    stream: u32,
+    ///The head as far as it has been written.
+    head: Vec<u8>,
+    head_done: bool,
+    ///Fields of the head not sent yet.
+    fields: Option<Vec<(String, String)>>,
+    ///What broke the connection while waiting for the client to take more data.
+    failure: Option<Error>,
+}
+
+impl ResponseWriter<'_> {
+    fn send_data(&mut self, mut data: &[u8], end_stream: bool) -> io::Result<()> {
+        if data.is_empty() && !end_stream {
+            return Ok(());
+        }
+        if let Some(fields) = self.fields.take() {
+            let only_headers = end_stream && data.is_empty();
+            self.send_headers(&fields, if only_headers { END_STREAM } else { 0 })?;
+            if only_headers {
+                return Ok(());
+            }
+        }
+
+        loop {
+            if self.connection.cancelled {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "Stream reset by the client",
+                ));
+            }
+            let stream_window = self.connection.stream_windows.get(&self.stream).copied();
+            let window = self.connection.send_window.min(stream_window.unwrap_or(0));
+            let len = data
+                .len()
+                .min(self.connection.max_frame_size)
+                .min(window.max(0) as usize);
+            if len == 0 && !data.is_empty() {
+                //Wait for the client to make room, answering whatever else it sends meanwhile
+                if let Err(err) = self.connection.receive(self.reader) {
+                    self.failure = Some(err);
+                    return Err(io::Error::other("HTTP/2 connection failed"));
+                }
+                continue;
+            }
+
+            let last = len == data.len();
+            let flags = if last && end_stream { END_STREAM } else { 0 };
+            write_frame(
+                self.reader.get_ref(),
+                DATA,
+                flags,
+                self.stream,
+                &data[..len],
+            )?;
+            self.connection.send_window -= len as i64;
+            if let Some(window) = self.connection.stream_windows.get_mut(&self.stream) {
+                *window -= len as i64;
+            }
+            data = &data[len..];
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    fn send_headers(&mut self, fields: &[(String, String)], flags: u8) -> io::Result<()> {
+        let fields: Vec<(&str, &str)> = fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let block = hpack::encode(&fields);
+        let out = self.reader.get_ref();
+
+        let mut fragments = block.chunks(self.connection.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = flags;
+        while let Some(fragment) = fragments.next() {
+            if fragments.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            write_frame(out, kind, flags, self.stream, fragment)?;
+            kind = CONTINUATION;
+            flags = 0;
+        }
+        Ok(())
+    }
+
+    ///Ends the stream, with RST_STREAM when the handler broke off.
+    fn finish(self, aborted: bool) -> Result<(), Error> {
+        let mut writer = self;
+        let result = if writer.connection.cancelled {
+            Ok(())
+        } else if aborted || !writer.head_done {
+            write_frame(
+                writer.reader.get_ref(),
+                RST_STREAM,
+                0,
+                writer.stream,
+                &INTERNAL_ERROR.to_be_bytes(),
+            )
+        } else {
+            writer.send_data(&[], true)
+        };
+
+        writer.connection.responding = None;
+        writer.connection.stream_windows.remove(&writer.stream);
+        match writer.failure {
+            Some(err) => Err(err),
+            None => result.map_err(Error::Io),
+        }
+    }
+}
+
+impl Write for ResponseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.head_done {
+            self.send_data(buf, false)?;
+            return Ok(buf.len());
+        }
+
+        self.head.extend_from_slice(buf);
+        let end = match self
+            .head
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            Some(i) => i + 4,
+            None => return Ok(buf.len()),
+        };
+        let body = self.head.split_off(end);
+        self.fields = Some(parse_head(&self.head)?);
+        self.head_done = true;
+        self.send_data(&body, false)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut out = self.reader.get_ref();
+        out.flush()
+    }
+}
+
+impl Sink for RefCell<ResponseWriter<'_>> {
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        self.borrow_mut().write_all(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.borrow_mut().flush()
+    }
+}
+
+///Turns an HTTP/1.1 response head into HTTP/2 fields.
+fn parse_head(head: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid response head");
+    let head = std::str::from_utf8(head).map_err(|_| invalid())?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .ok_or_else(invalid)?;
+
+    let mut fields = vec![(String::from(":status"), status.to_string())];
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        let name = name.trim().to_ascii_lowercase();
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            fields.push((name, value.trim().to_string()));
+        }
+    }
+    Ok(fields)
+}
+
+fn read_frame(reader: &mut BufReader<Stream>) -> Result<Frame, Error> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if len > DEFAULT_MAX_FRAME_SIZE {
+        return Err(Error::Connection(
+            FRAME_SIZE_ERROR,
+            "Frame larger than allowed",
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+fn write_frame(
+    mut out: &Stream,
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    out.write_all(&frame)
+}
+
+fn write_goaway(out: &Stream, last_stream: u32, code: u32) -> io::Result<()> {
+    let mut payload = last_stream.to_be_bytes().to_vec();
+    payload.extend_from_slice(&code.to_be_bytes());
+    write_frame(out, GOAWAY, 0, 0, &payload)
+}
+
+///The payload of a DATA or HEADERS frame without its padding.
+fn unpad(frame: &Frame) -> Result<&[u8], Error> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let invalid = Error::Connection(PROTOCOL_ERROR, "Padding longer than the frame");
+    let (&padding, rest) = frame.payload.split_first().ok_or(invalid)?;
+    match rest.len().checked_sub(padding as usize) {
+        Some(len) => Ok(&rest[..len]),
+        None => Err(Error::Connection(
+            PROTOCOL_ERROR,
+            "Padding longer than the frame",
+        )),
+    }
+}
+
+fn bad_request(reason: &str) -> ParseError {
+    ParseError::BadRequest(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn incoming(fields: &[(&str, &str)], body: &[u8]) -> Incoming {
+        Incoming {
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_vec(),
+            error: None,
+        }
+    }
+
+    const GET: [(&str, &str); 3] = [(":method", "GET"), (":scheme", "https"), (":path", "/")];
+
+    fn rejected(fields: &[(&str, &str)]) -> bool {
+        matches!(
+            incoming(fields, b"").into_request(),
+            Err(ParseError::BadRequest(_))
+        )
+    }
+
+    #[test]
+    fn requests_from_fields() {
+        let request = incoming(
+            &[
+                (":method", "POST"),
+                (":scheme", "https"),
+                (":authority", "app.test"),
+                (":path", "/api/task?x=1"),
+                ("cookie", "a=1"),
+                ("content-length", "2"),
+                ("cookie", "b=2"),
+                ("te", "trailers"),
+            ],
+            b"{}",
+        )
+        .into_request()
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/api/task?x=1");
+        assert_eq!(request.version, Version::Http2);
+        assert_eq!(request.headers.get("host"), Some("app.test"));
+        assert_eq!(request.headers.get("cookie"), Some("a=1; b=2"));
+        assert_eq!(request.body, b"{}");
+
+        //A host field wins over :authority
+        let mut fields = GET.to_vec();
+        fields.insert(3, (":authority", "app.test"));
+        fields.push(("host", "other.test"));
+        let request = incoming(&fields, b"").into_request().unwrap();
+        assert_eq!(request.headers.get("host"), Some("other.test"));
+
+        let options = [(":method", "OPTIONS"), (":scheme", "https"), (":path", "*")];
+        assert!(incoming(&options, b"").into_request().is_ok());
+    }
+
+    #[test]
+    fn invalid_pseudo_headers() {
+        assert!(rejected(&[(":scheme", "https"), (":path", "/")]));
+        assert!(rejected(&[(":method", "GET"), (":path", "/")]));
+        assert!(rejected(&[(":method", "GET"), (":scheme", "https")]));
+        assert!(rejected(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "")
+        ]));
+        assert!(rejected(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "*")
+        ]));
+        assert!(rejected(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "a/b")
+        ]));
+        assert!(rejected(&[GET[0], GET[1], GET[2], (":method", "POST")]));
+        assert!(rejected(&[GET[0], GET[1], GET[2], (":status", "200")]));
+        assert!(rejected(&[GET[0], GET[1], ("accept", "*/*"), GET[2]]));
+        assert!(matches!(
+            incoming(
+                &[(":method", "CONNECT"), (":authority", "app.test:443")],
+                b""
+            )
+            .into_request(),
+            Err(ParseError::NotImplemented(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for field in [
+            ("Accept", "*/*"),
+            ("", "x"),
+            ("accept", "a\r\nb"),
+            ("connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("te", "gzip"),
+            ("content-length", "3"),
+        ] {
+            let mut fields = GET.to_vec();
+            fields.push(field);
+            assert!(rejected(&fields), "{field:?}");
+        }
+
+        let mut fields = GET.to_vec();
+        let many = vec![("x-many", "1"); http::MAX_HEADERS];
+        fields.extend_from_slice(&many);
+        assert!(matches!(
+            incoming(&fields, b"").into_request(),
+            Err(ParseError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn trailers_are_filtered() {
+        let mut request = incoming(&GET, b"");
+        request.add_trailers(vec![
+            (String::from("x-checksum"), String::from("abc")),
+            (String::from("cookie"), String::from("session=1")),
+            (String::from("content-length"), String::from("100")),
+        ]);
+        let request = request.into_request().unwrap();
+        assert_eq!(request.headers.get("x-checksum"), Some("abc"));
+        assert_eq!(request.headers.get("cookie"), None);
+        assert_eq!(request.headers.get("content-length"), None);
+
+        let mut request = incoming(&GET, b"");
+        request.add_trailers(vec![(String::from(":path"), String::from("/admin"))]);
+        assert!(request.into_request().is_err());
+    }
+
+    ///A connection past its preface, the reader for its server side and the client's socket.
+    fn open() -> (Connection, BufReader<Stream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut connection = Connection::new();
+        connection.preface = b"";
+        (connection, BufReader::new(Stream::plain(server)), peer)
+    }
+
+    fn send(peer: &mut TcpStream, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        peer.write_all(&frame).unwrap();
+    }
+
+    ///Sends a frame and lets the connection read it, returning the code if
+    ///it ended the connection.
+    fn deliver(
+        connection: &mut Connection,
+        reader: &mut BufReader<Stream>,
+        peer: &mut TcpStream,
+        (kind, flags, stream): (u8, u8, u32),
+        payload: &[u8],
+    ) -> Option<u32> {
+        send(peer, kind, flags, stream, payload);
+        match connection.receive(reader) {
+            Ok(()) => None,
+            Err(Error::Connection(code, _)) => Some(code),
+            Err(Error::Io(err)) => panic!("{err}"),
+        }
+    }
+
+    ///Next frame the server sent as kind, flags, stream and payload.
+    fn received(peer: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0; FRAME_HEADER_LEN];
+        peer.read_exact(&mut header).unwrap();
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0; len];
+        peer.read_exact(&mut payload).unwrap();
+        let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        (header[3], header[4], stream, payload)
+    }
+
+    fn block() -> Vec<u8> {
+        hpack::encode(&GET)
+    }
+
+    #[test]
+    fn continuation() {
+        let (mut connection, mut reader, mut peer) = open();
+        let mut block = hpack::encode(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/api/tasks"),
+            ("x-long", &"x".repeat(200)),
+        ]);
+        let rest = block.split_off(10);
+        let c = &mut connection;
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (HEADERS, END_STREAM, 1), &block),
+            None
+        );
+        assert!(c.ready.is_empty());
+        let (middle, last) = rest.split_at(100);
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (CONTINUATION, 0, 1), middle),
+            None
+        );
+        assert!(c.ready.is_empty());
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (CONTINUATION, END_HEADERS, 1),
+                last
+            ),
+            None
+        );
+        let (stream, request) = c.ready.pop_front().unwrap();
+        assert_eq!(stream, 1);
+        let request = request.unwrap();
+        assert_eq!(request.target, "/api/tasks");
+        assert_eq!(request.headers.get("x-long").map(str::len), Some(200));
+
+        //Nothing else may come between HEADERS and its last CONTINUATION
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (HEADERS, 0, 3), &block),
+            None
+        );
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (PING, 0, 0), &[0; 8]),
+            Some(PROTOCOL_ERROR)
+        );
+
+        let (mut connection, mut reader, mut peer) = open();
+        let c = &mut connection;
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (HEADERS, 0, 1), &block),
+            None
+        );
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (CONTINUATION, END_HEADERS, 3),
+                &rest
+            ),
+            Some(PROTOCOL_ERROR)
+        );
+
+        let (mut connection, mut reader, mut peer) = open();
+        assert_eq!(
+            deliver(
+                &mut connection,
+                &mut reader,
+                &mut peer,
+                (CONTINUATION, END_HEADERS, 1),
+                &rest
+            ),
+            Some(PROTOCOL_ERROR)
+        );
+    }
+
+    #[test]
+    fn settings() {
+        let (mut connection, mut reader, mut peer) = open();
+        let c = &mut connection;
+        let mut settings = Vec::new();
+        for (id, value) in [
+            (SETTINGS_MAX_FRAME_SIZE, 32768u32),
+            (SETTINGS_INITIAL_WINDOW_SIZE, 1000),
+            (0xff, 1),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (SETTINGS, 0, 0), &settings),
+            None
+        );
+        assert_eq!(received(&mut peer), (SETTINGS, ACK, 0, Vec::new()));
+        assert_eq!(c.max_frame_size, 32768);
+        assert_eq!(c.initial_window, 1000);
+
+        //The client acknowledging ours needs no answer
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (SETTINGS, ACK, 0), &[]),
+            None
+        );
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (SETTINGS, ACK, 0),
+                &settings[..6]
+            ),
+            Some(FRAME_SIZE_ERROR)
+        );
+        for (header, payload, code) in [
+            ((SETTINGS, 0, 1), &settings[..6], PROTOCOL_ERROR),
+            ((SETTINGS, 0, 0), &settings[..5], FRAME_SIZE_ERROR),
+            ((SETTINGS, 0, 0), &[0, 5, 0, 0, 0x10, 0][..], PROTOCOL_ERROR),
+            ((SETTINGS, 0, 0), &[0, 5, 0x01, 0, 0, 0], PROTOCOL_ERROR),
+            ((SETTINGS, 0, 0), &[0, 4, 0x80, 0, 0, 0], FLOW_CONTROL_ERROR),
+        ] {
+            let (mut connection, mut reader, mut peer) = open();
+            assert_eq!(
+                deliver(&mut connection, &mut reader, &mut peer, header, payload),
+                Some(code),
+                "{payload:x?}"
+            );
+        }
+    }
+
+    #[test]
+    fn windows_of_received_data() {
+        let (mut connection, mut reader, mut peer) = open();
+        let c = &mut connection;
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS, 1),
+                &block()
+            ),
+            None
+        );
+
+        //Both windows are handed back as soon as the data arrives, padding included
+        let mut padded = vec![4];
+        padded.extend_from_slice(b"hello");
+        padded.extend_from_slice(&[0; 4]);
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (DATA, PADDED, 1), &padded),
+            None
+        );
+        assert_eq!(
+            received(&mut peer),
+            (WINDOW_UPDATE, 0, 0, 10u32.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            received(&mut peer),
+            (WINDOW_UPDATE, 0, 1, 10u32.to_be_bytes().to_vec())
+        );
+
+        //The last frame of a stream only needs the connection window back
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (DATA, END_STREAM, 1), b"!"),
+            None
+        );
+        assert_eq!(
+            received(&mut peer),
+            (WINDOW_UPDATE, 0, 0, 1u32.to_be_bytes().to_vec())
+        );
+        let (_, request) = c.ready.pop_front().unwrap();
+        assert_eq!(request.unwrap().body, b"hello!");
+
+        //DATA on a stream that is done resets it, on one never opened ends the connection
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (DATA, 0, 1), b"late"),
+            None
+        );
+        assert_eq!(received(&mut peer).0, WINDOW_UPDATE);
+        assert_eq!(
+            received(&mut peer),
+            (RST_STREAM, 0, 1, STREAM_CLOSED.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (DATA, 0, 5), b"early"),
+            Some(PROTOCOL_ERROR)
+        );
+    }
+
+    #[test]
+    fn window_updates() {
+        let (mut connection, mut reader, mut peer) = open();
+        let c = &mut connection;
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS, 1),
+                &block()
+            ),
+            None
+        );
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS, 3),
+                &block()
+            ),
+            None
+        );
+
+        let increment = |n: u32| n.to_be_bytes();
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (WINDOW_UPDATE, 0, 0),
+                &increment(1000)
+            ),
+            None
+        );
+        assert_eq!(c.send_window, DEFAULT_WINDOW + 1000);
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (WINDOW_UPDATE, 0, 1),
+                &increment(10)
+            ),
+            None
+        );
+        assert_eq!(c.stream_windows[&1], DEFAULT_WINDOW + 10);
+        //Updates for streams that are gone are ignored
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (WINDOW_UPDATE, 0, 9),
+                &increment(10)
+            ),
+            None
+        );
+
+        //A new initial window moves every open stream by the difference
+        let mut settings = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        settings.extend_from_slice(&100u32.to_be_bytes());
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (SETTINGS, 0, 0), &settings),
+            None
+        );
+        assert_eq!(received(&mut peer).0, SETTINGS);
+        assert_eq!(c.stream_windows[&1], 110);
+        assert_eq!(c.stream_windows[&3], 100);
+        assert_eq!(c.send_window, DEFAULT_WINDOW + 1000);
+
+        //Errors on a stream only reset that stream
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (WINDOW_UPDATE, 0, 1),
+                &increment(0)
+            ),
+            None
+        );
+        assert_eq!(
+            received(&mut peer),
+            (RST_STREAM, 0, 1, PROTOCOL_ERROR.to_be_bytes().to_vec())
+        );
+        let overflow = increment(MAX_WINDOW as u32);
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (WINDOW_UPDATE, 0, 3), &overflow),
+            None
+        );
+        assert_eq!(
+            received(&mut peer),
+            (RST_STREAM, 0, 3, FLOW_CONTROL_ERROR.to_be_bytes().to_vec())
+        );
+        assert!(c.stream_windows.is_empty());
+
+        //While on the connection they end it
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (WINDOW_UPDATE, 0, 0), &overflow),
+            Some(FLOW_CONTROL_ERROR)
+        );
+        let (mut connection, mut reader, mut peer) = open();
+        assert_eq!(
+            deliver(
+                &mut connection,
+                &mut reader,
+                &mut peer,
+                (WINDOW_UPDATE, 0, 0),
+                &increment(0)
+            ),
+            Some(PROTOCOL_ERROR)
+        );
+    }
+
+    #[test]
+    fn responses_wait_for_the_windows() {
+        let (mut connection, mut reader, mut peer) = open();
+        let c = &mut connection;
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS, 1),
+                &block()
+            ),
+            None
+        );
+        c.stream_windows.insert(1, 10);
+        c.send_window = 15;
+
+        //Room the client makes while the response waits for it
+        send(&mut peer, WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes());
+        send(&mut peer, WINDOW_UPDATE, 0, 0, &100u32.to_be_bytes());
+        let mut writer = ResponseWriter {
+            connection: c,
+            reader: &mut reader,
+            stream: 1,
+            head: Vec::new(),
+            head_done: false,
+            fields: None,
+            failure: None,
+        };
+        writer
+            .write_all(b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 40\r\n\r\n")
+            .unwrap();
+        writer.write_all(&[b'x'; 40]).unwrap();
+        assert!(writer.finish(false).is_ok());
+
+        let (kind, flags, stream, block) = received(&mut peer);
+        assert_eq!((kind, flags, stream), (HEADERS, END_HEADERS, 1));
+        assert_eq!(
+            Decoder::new().decode(&block).unwrap(),
+            [
+                (String::from(":status"), String::from("200")),
+                (String::from("content-length"), String::from("40")),
+            ]
+        );
+        //10 fit the stream window and 5 more the connection window, the rest
+        //only once both updates were read
+        let lengths: Vec<(usize, u8)> = (0..4)
+            .map(|_| received(&mut peer))
+            .map(|(kind, flags, _, payload)| {
+                assert_eq!(kind, DATA);
+                (payload.len(), flags)
+            })
+            .collect();
+        assert_eq!(lengths, [(10, 0), (5, 0), (25, 0), (0, END_STREAM)]);
+        assert_eq!(connection.send_window, 15 + 100 - 40);
+    }
+
+    #[test]
+    fn streams_past_the_limit_are_refused() {
+        let (mut connection, mut reader, mut peer) = open();
+        let c = &mut connection;
+        for i in 0..MAX_CONCURRENT_STREAMS as u32 {
+            let stream = 2 * i + 1;
+            assert_eq!(
+                deliver(
+                    c,
+                    &mut reader,
+                    &mut peer,
+                    (HEADERS, END_HEADERS, stream),
+                    &block()
+                ),
+                None
+            );
+        }
+
+        //The refused block still goes through the decoder, its field is indexed
+        let next = 2 * MAX_CONCURRENT_STREAMS as u32 + 1;
+        let mut indexed = block();
+        indexed.extend_from_slice(&[0x40, 0x01, b'x', 0x01, b'y']);
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS, next),
+                &indexed
+            ),
+            None
+        );
+        assert_eq!(
+            received(&mut peer),
+            (RST_STREAM, 0, next, REFUSED_STREAM.to_be_bytes().to_vec())
+        );
+        assert!(!c.incoming.contains_key(&next));
+
+        //Once the client cancels one there is room again
+        assert_eq!(
+            deliver(c, &mut reader, &mut peer, (RST_STREAM, 0, 1), &[0, 0, 0, 8]),
+            None
+        );
+        let mut refers = block();
+        refers.push(0xbe);
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS | END_STREAM, next + 2),
+                &refers
+            ),
+            None
+        );
+        let (stream, request) = c.ready.pop_front().unwrap();
+        assert_eq!(stream, next + 2);
+        assert_eq!(request.unwrap().headers.get("x"), Some("y"));
+
+        //Stream ids only ever go up
+        assert_eq!(
+            deliver(
+                c,
+                &mut reader,
+                &mut peer,
+                (HEADERS, END_HEADERS, 3 + 2 * MAX_CONCURRENT_STREAMS as u32),
+                &block()
+            ),
+            Some(STREAM_CLOSED)
+        );
+    }
+}
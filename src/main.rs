@@ -1,30 +1,47 @@
-use chrono::{TimeDelta, Utc};
-use data_structs::{CompleteTask, IdCarrier, JsonError, SessionUser, Settings, Sql, Task, User};
-use mime_guess;
+use api::{ApiRequest, Handler};
+use connection::{Client, Parking, ParkingLot, Responder, Stream};
+use data_structs::{JsonError, Settings};
+use events::Events;
+use files::FileCache;
+use http::{HttpError, ParseError, Request};
+use router::{Resolution, Router};
 use rusqlite::Connection;
-use sha256::digest;
+use rustls::{ServerConfig, ServerConnection};
+use session::Sessions;
 use std::{
-    collections::HashMap,
     fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex, RwLock},
+    io::{self, prelude::*},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
 use threadspool::ThreadSpool;
-use uuid::Uuid;
-
+use tls::Certificates;
+use websocket::WebSockets;
+
+mod api;
+mod authorization;
+mod compression;
+mod connection;
+mod cors;
 mod data_structs;
+mod events;
+mod files;
+mod hpack;
+mod http;
+mod http2;
+mod password;
+mod recurrence;
+mod router;
+mod session;
 mod threadspool;
+mod tls;
+mod websocket;
 
 const SETTINGS_PATH: &str = "settings.json";
-
-enum HttpError {
-    BadRequest = 400,
-    Forbidden = 403,
-    NotFound = 404,
-    LengthRequired = 411,
-    InternalServerError = 500,
-}
+///Every method some route answers, for OPTIONS *.
+const SERVER_METHODS: [&str; 6] = ["GET", "HEAD", "POST", "PATCH", "DELETE", "OPTIONS"];
 
 fn main() {
     let settings = match fs::read_to_string(SETTINGS_PATH) {
@@ -42,6 +59,16 @@ fn main() {
         }
     };
 
+    if let Err(err) = password::init(&settings.password) {
+        println!("Invalid password settings in {SETTINGS_PATH}");
+        panic!("{err}");
+    }
+
+    if let Err(err) = cors::check(&settings.cors) {
+        println!("Invalid CORS settings in {SETTINGS_PATH}");
+        panic!("{err}");
+    }
+
     if !fs::exists(&settings.data_path).unwrap() {
         fs::File::create(&settings.data_path).unwrap();
         let sql_init = String::from_utf8(fs::read("init.sql").unwrap()).unwrap();
@@ -49,8 +76,6 @@ fn main() {
         sql_connection.execute_batch(&sql_init).unwrap();
     }
 
-    let settings = Arc::new(settings);
-
     let sql_connection = Connection::open(settings.data_path.as_str()).unwrap();
     sql_connection
         .execute("PRAGMA foreign_keys = ON;", [])
@@ -60,607 +85,480 @@ fn main() {
     let addr = format!("{}:{}", settings.bind_addr, settings.bind_port);
     println!("{addr}");
 
-    let session: HashMap<String, SessionUser> = HashMap::new();
-    let session = Arc::new(RwLock::new(session));
-
-    let spool = ThreadSpool::new(settings.n_threads);
-    let listener: TcpListener = match TcpListener::bind(&addr) {
-        Ok(listener) => listener,
+    let session = match Sessions::new(sql_connection.clone(), &settings.sessions) {
+        Ok(session) => Arc::new(session),
         Err(err) => {
-            println!("Could not bind on address {}", addr);
+            println!("Could not set up sessions");
             panic!("{err}");
         }
     };
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let settings = settings.clone();
-        let sql_connection = sql_connection.clone();
-        let session = session.clone();
-
-        spool.execute(move || {
-            //lets have a limit of one mibibyte as for now
-            let mut buf_reader = BufReader::new(&stream).take(1048576);
-
-            let mut http_header: Vec<String> = Vec::new();
-            let mut buffer = String::new();
-
-            //Used to make iterator with lines() but that took ownership
-            //over the reader which made it impossible to extract the body
-            loop {
-                match buf_reader.read_line(&mut buffer) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("{err}");
-                    }
-                }
-                let trim_line = buffer.trim();
-                if trim_line.is_empty() {
-                    break;
-                }
-                http_header.push(trim_line.to_string());
-                buffer.clear();
-            }
-
-            if http_header.is_empty() {
-                println!("Empty request!");
-                return;
-            }
-
-            let top_header: Vec<&str> = http_header[0].split(" ").collect();
-            let request_line = match top_header
-                .iter()
-                .filter(|header| !header.contains("HTTP"))
-                .map(|h| h.to_string())
-                .reduce(|a, b| format!("{a} {b}"))
-            {
-                Some(h) => h,
-                None => {
-                    serve_404_html(stream, String::from("Your header sucks"));
-                    return;
-                }
-            };
-
-            println!("{request_line}");
-
-            let header_map: HashMap<String, &str> = http_header[1..]
-                .into_iter()
-                .filter_map(|line| line.split_once(":"))
-                .map(|pair| {
-                    let (key, value) = pair;
-                    (key.to_lowercase(), value.trim())
-                })
-                .collect();
-
-            let request_path: Vec<&str> = request_line.split(" ").collect();
-            let request_path = match request_path.last() {
-                Some(p) => *p,
-                None => {
-                    serve_404_html(stream, String::from("Your header sucks"));
-                    return;
-                }
-            };
-
-            if request_path.starts_with("/api/") {
-                handle_api_request(
-                    &stream,
-                    buf_reader,
-                    header_map,
-                    sql_connection,
-                    session,
-                    request_line,
-                );
-            } else {
-                handle_file_request(stream, settings, request_line);
-            }
-        });
-    }
-}
-
-fn handle_api_request(
-    stream: &TcpStream,
-    buf_reader: std::io::Take<BufReader<&TcpStream>>,
-    header: HashMap<String, &str>,
-    sql_connection: Arc<Mutex<Connection>>,
-    session: Arc<RwLock<HashMap<String, SessionUser>>>,
-    request_line: String,
-) {
-    match request_line.as_str() {
-        "GET /api/task" => {
-            let user_id = match extract_user_id(&header, session) {
-                Ok(user_id) => user_id,
+    session
+        .clone()
+        .sweep_every(settings.sessions.sweep_interval);
+
+    let tls_config = match &settings.tls {
+        Some(tls) => {
+            let certificates = match Certificates::load(&tls.certificates) {
+                Ok(certificates) => Arc::new(certificates),
                 Err(err) => {
-                    serve_error_json(stream, HttpError::Forbidden, String::from(err));
-                    return;
+                    println!("Could not load TLS certificates");
+                    panic!("{err}");
                 }
             };
-
-            let mut tasks: Vec<Box<Task>> = match query_to_object::<Task>(
-                sql_connection.clone(),
-                format!("SELECT * FROM tasks WHERE user_id = '{user_id}'").as_str(),
-            ) {
-                Ok(vec_of_boxes) => vec_of_boxes,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::InternalServerError, err.to_string());
-                    return;
-                }
-            };
-
-            for t in &mut tasks {
-                let complete_tasks: Vec<Box<CompleteTask>> = match query_to_object::<CompleteTask>(
-                    sql_connection.clone(),
-                    format!("SELECT * FROM complete_tasks WHERE task_id = '{}';", t.id).as_str(),
-                ) {
-                    Ok(ct) => ct,
-                    Err(err) => {
-                        serve_error_json(stream, HttpError::InternalServerError, err.to_string());
-                        return;
-                    }
-                };
-
-                t.complete_tasks = complete_tasks;
+            if let Err(err) = tls::reload_on_sighup(certificates.clone()) {
+                println!("Could not listen for SIGHUP, certificates won't be reloaded");
+                println!("{err}");
             }
-
-            let tasks: Vec<String> = tasks.into_iter().map(|t| t.to_json()).collect();
-
-            serve_200_json(stream, format!("[{}]", tasks.join(",")));
-        }
-        "POST /api/task" => {
-            let user_id = match extract_user_id(&header, session) {
-                Ok(user_id) => user_id,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::Forbidden, String::from(err));
-                    return;
-                }
-            };
-
-            let body = extract_body(stream, buf_reader, header);
-            if body.is_none() {
-                return;
-            }
-
-            let task = match Task::from_json(body.unwrap().as_str()) {
-                Ok(task) => task,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            };
-
-            let sql_connection = sql_connection.lock().unwrap();
-            match sql_connection.execute(
-                task.to_sql_insert()
-                    .replace("{}", user_id.as_str())
-                    .as_str(),
-                (),
-            ) {
-                Ok(_) => {}
+            match certificates.server_config(settings.http2) {
+                Ok(config) => Some(config),
                 Err(err) => {
-                    drop(sql_connection);
-                    serve_error_json(stream, HttpError::InternalServerError, err.to_string());
-                    return;
+                    println!("Could not set up TLS");
+                    panic!("{err}");
                 }
             }
-            drop(sql_connection);
-
-            serve_200_json(stream, task.to_json());
         }
-        "DELETE /api/task" => {
-            let user_id = match extract_user_id(&header, session) {
-                Ok(user_id) => user_id,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::Forbidden, String::from(err));
-                    return;
-                }
-            };
-
-            let body: String;
-            match extract_body(stream, buf_reader, header) {
-                Some(b) => body = b,
-                None => return,
-            };
-
-            let id_carrier: IdCarrier;
-            match serde_json::de::from_str::<IdCarrier>(body.as_str()) {
-                Ok(ic) => id_carrier = ic,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            }
-
-            let task_id = &id_carrier.id;
-
-            let sql_connection = sql_connection.lock().unwrap();
-            match sql_connection.execute(
-                format!("DELETE FROM tasks WHERE id = '{task_id}' AND user_id = '{user_id}';")
-                    .as_str(),
-                (),
-            ) {
-                Ok(_) => (),
-                Err(err) => {
-                    serve_error_json(stream, HttpError::InternalServerError, err.to_string());
-                    return;
-                }
-            };
-            drop(sql_connection);
-
-            serve_200_json(stream, serde_json::to_string(&id_carrier).unwrap());
+        None => None,
+    };
+    let redirect_port = settings
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.redirect_port.clone());
+
+    let spool = Arc::new(ThreadSpool::new(settings.n_threads));
+    let keep_alive_timeout = Duration::from_secs(settings.keep_alive_timeout);
+
+    let file_cache = FileCache::new(&settings.cache);
+    let events = match Events::new(&settings.events) {
+        Ok((events, delivery)) => {
+            events::deliver(delivery);
+            Arc::new(events)
         }
-        "POST /api/complete_task" => {
-            let body = match extract_body(stream, buf_reader, header) {
-                Some(b) => b,
-                None => return,
-            };
-
-            let complete_task = match CompleteTask::from_json(&body) {
-                Ok(ct) => ct,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            };
-
-            let sql_connection = sql_connection.lock().unwrap();
-            match sql_connection.execute(complete_task.to_sql_insert().as_str(), ()) {
-                Ok(_) => (),
-                Err(err) => {
-                    drop(sql_connection);
-                    serve_error_json(stream, HttpError::InternalServerError, err.to_string());
-                    return;
-                }
-            }
-            drop(sql_connection);
+        Err(err) => {
+            println!("Could not set up event delivery");
+            panic!("{err}");
+        }
+    };
+    let (websockets, hub) = match websocket::hub() {
+        Ok(hub) => hub,
+        Err(err) => {
+            println!("Could not set up the WebSocket hub");
+            panic!("{err}");
+        }
+    };
+    let shared = Arc::new(Shared {
+        settings,
+        sql_connection,
+        session,
+        router: api::router(),
+        file_cache,
+        events,
+        websockets,
+    });
+
+    //WebSocket messages are run on the workers, in between sockets wait in the hub
+    {
+        let events = shared.events.listen();
+        let settings = &shared.settings.websocket;
+        let shared = shared.clone();
+        let spool = spool.clone();
+        websocket::serve(hub, events, settings, move |operation| {
+            let shared = shared.clone();
+            spool.execute(move || websocket::perform(operation, &shared));
+        });
+    }
 
-            serve_200_json(stream, complete_task.to_json());
+    //Connections wait for their next request parked, off the workers
+    let (parking, lot) = match connection::parking() {
+        Ok(parking) => parking,
+        Err(err) => {
+            println!("Could not set up waiting for idle connections");
+            panic!("{err}");
         }
-        "DELETE /api/complete_task" => {
-            let _user_id = match extract_user_id(&header, session) {
-                Ok(user_id) => user_id,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::Forbidden, String::from(err));
-                    return;
-                }
-            };
+    };
+    serve_parked(
+        lot,
+        parking.clone(),
+        shared.clone(),
+        spool.clone(),
+        keep_alive_timeout,
+    );
 
-            let body: String;
-            match extract_body(stream, buf_reader, header) {
-                Some(b) => body = b,
-                None => return,
+    if let Some(redirect_port) = redirect_port {
+        let redirect_addr = format!("{}:{redirect_port}", shared.settings.bind_addr);
+        println!("{redirect_addr} redirects to HTTPS");
+        let listener = bind(&redirect_addr);
+        let (parking, lot) = match connection::parking() {
+            Ok(parking) => parking,
+            Err(err) => {
+                println!("Could not set up waiting for connections to redirect");
+                panic!("{err}");
             }
-
-            let id_carrier: IdCarrier;
-            match serde_json::from_str::<IdCarrier>(body.as_str()) {
-                Ok(ic) => id_carrier = ic,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
+        };
+        {
+            let shared = shared.clone();
+            let spool = spool.clone();
+            connection::watch_idle(lot, keep_alive_timeout, move |client| {
+                let shared = shared.clone();
+                spool.execute(move || redirect_to_https(client, &shared.settings));
+            });
+        }
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        println!("Could not accept connection");
+                        println!("{err}");
+                        continue;
+                    }
+                };
+                if let Err(err) = stream.set_read_timeout(Some(keep_alive_timeout)) {
+                    println!("{err}");
+                    continue;
                 }
+                parking.park(Client::new(Stream::plain(stream)));
             }
+        });
+    }
 
-            let complete_task_id = &id_carrier.id;
-            let sql_connection = sql_connection.lock().unwrap();
-            match sql_connection.execute(
-                format!("DELETE FROM complete_tasks WHERE id = '{complete_task_id}';").as_ref(),
-                (),
-            ) {
-                Ok(_) => (),
-                Err(err) => {
-                    drop(sql_connection);
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            };
-            drop(sql_connection);
+    accept(bind(&addr), tls_config, keep_alive_timeout, &parking);
+}
 
-            serve_200_json(stream, serde_json::ser::to_string(&id_carrier).unwrap());
-        }
-        "GET /api/user" => {
-            let user_id = match extract_user_id(&header, session) {
-                Ok(user_id) => user_id,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::Forbidden, String::from(err));
-                    return;
-                }
-            };
+///Waits on the connections in `lot`, serving each on a worker once its
+///client sent something.
+fn serve_parked(
+    lot: ParkingLot,
+    parking: Parking,
+    shared: Arc<Shared>,
+    spool: Arc<ThreadSpool>,
+    timeout: Duration,
+) {
+    connection::watch_idle(lot, timeout, move |client| {
+        let shared = shared.clone();
+        let parking = parking.clone();
+        spool.execute(move || serve_client(client, &shared, &parking));
+    });
+}
 
-            serve_200_json(stream, format!("{{\"userId\":\"{}\"}}", user_id));
+///Takes connections off `listener` for good, parking each right away.
+fn accept(
+    listener: TcpListener,
+    tls_config: Option<Arc<ServerConfig>>,
+    keep_alive_timeout: Duration,
+    parking: &Parking,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Could not accept connection");
+                println!("{err}");
+                continue;
+            }
+        };
+        if let Err(err) = stream.set_read_timeout(Some(keep_alive_timeout)) {
+            println!("{err}");
+            continue;
         }
-        "POST /api/user" => {
-            let body = match extract_body(stream, buf_reader, header) {
-                Some(body) => body,
-                None => return,
-            };
 
-            let mut user = match User::from_json(body.as_str()) {
-                Ok(user) => user,
+        let stream = match &tls_config {
+            Some(config) => match ServerConnection::new(config.clone()) {
+                Ok(connection) => Stream::tls(stream, connection),
                 Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
+                    println!("Could not start TLS");
+                    println!("{err}");
+                    continue;
                 }
-            };
-            let mut passwd = user.password.as_bytes().to_owned();
-            passwd.extend([user.salt]);
-            user.password = digest(passwd);
+            },
+            None => Stream::plain(stream),
+        };
 
-            let sql_connection = sql_connection.lock().unwrap();
-            match sql_connection.execute(user.to_sql_insert().as_str(), ()) {
-                Ok(_) => {}
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            }
-            drop(sql_connection);
+        //Even the first request is only read once it started arriving, clients
+        //that connect and send nothing never hold a worker
+        parking.park(Client::new(stream));
+    }
+}
 
-            serve_200_json(stream, user.to_json());
+fn bind(addr: &str) -> TcpListener {
+    match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Could not bind on address {}", addr);
+            panic!("{err}");
         }
-        "DELETE /api/user" => {
-            let user_id = match extract_user_id(&header, session.clone()) {
-                Ok(user_id) => user_id,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::Forbidden, String::from(err));
-                    return;
-                }
-            };
-
-            let sql_connection = sql_connection.lock().unwrap();
-            match sql_connection.execute(
-                format!("DELETE FROM users WHERE id = '{user_id}';").as_str(),
-                (),
-            ) {
-                Ok(_) => (),
-                Err(err) => {
-                    drop(sql_connection);
-                    serve_error_json(stream, HttpError::InternalServerError, err.to_string());
-                    return;
-                }
-            }
-            drop(sql_connection);
-
-            let mut session = session.write().unwrap();
-            session.remove(user_id.as_str());
-            drop(session);
+    }
+}
 
-            let body = r#"{"user_id":"{}"}"#;
-            let body = body.replace("{}", user_id.as_str());
-            serve_200_json(stream, body);
+///Answers a request on the plain HTTP port with the same URL over HTTPS.
+fn redirect_to_https(mut client: Client, settings: &Settings) {
+    let request = match http::read_request(&mut client.reader) {
+        Ok(Some(request)) => request,
+        Ok(None) | Err(ParseError::Io(_)) => return,
+        Err(err) => {
+            let responder = Responder::new(client.reader.get_ref(), http::Version::Http10, false);
+            serve_error_json(&responder, err.status(), err.to_string());
+            return;
         }
-        "POST /api/login" => {
-            let body = match extract_body(stream, buf_reader, header) {
-                Some(body) => body,
-                None => return,
-            };
+    };
+    let responder = Responder::new(client.reader.get_ref(), request.version, false);
+    let mut stream = &responder;
+
+    let host = request.headers.get("host").unwrap_or(&settings.bind_addr);
+    //Drop the port of this listener, minding the colons of IPv6 addresses
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let port = match settings.bind_port.as_str() {
+        "443" => String::new(),
+        port => format!(":{port}"),
+    };
+    //308 keeps the method and body, browsers turn a 301 POST into a GET
+    let status = match request.method.as_str() {
+        "GET" | "HEAD" => "301 Moved Permanently",
+        _ => "308 Permanent Redirect",
+    };
 
-            let user: User = match serde_json::de::from_str(body.as_str()) {
-                Ok(login) => login,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            };
+    let header = format!(
+        "HTTP/1.1 {status}\r\n{}Location: https://{host}{port}{}\r\nContent-Length: 0\r\n\r\n",
+        stream.common_headers(),
+        request.target
+    );
+    if let Err(err) = stream.write_all(header.as_bytes()) {
+        println!("Could not write header to stream");
+        println!("{err}");
+    }
+}
 
-            let passwd = user.password;
+///State every request handler has access to.
+struct Shared {
+    settings: Settings,
+    sql_connection: Arc<Mutex<Connection>>,
+    session: Arc<Sessions>,
+    router: Router<Handler>,
+    file_cache: FileCache,
+    events: Arc<Events>,
+    websockets: WebSockets,
+}
 
-            let user = match query_to_object::<User>(
-                sql_connection,
-                format!("SELECT * FROM users WHERE username = '{}';", user.username).as_str(),
-            ) {
-                Ok(user) => user,
-                Err(err) => {
-                    serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                    return;
-                }
-            };
+///Serves requests on one connection for as long as it has requests buffered,
+///then parks it again or closes it.
+fn serve_client(mut client: Client, shared: &Shared, parking: &Parking) {
+    if let Some(connection) = client.http2.take() {
+        http2::serve(client, connection, shared, parking, true);
+        return;
+    }
 
-            let user = match user.first() {
-                Some(user) => user,
-                None => {
-                    serve_error_json(
-                        stream,
-                        HttpError::BadRequest,
-                        String::from("User not found or invalid password"),
-                    );
-                    return;
+    loop {
+        let mut request = match http::read_request(&mut client.reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(ParseError::Io(err)) => {
+                //Clients closing idle connections, TLS ones without a close_notify
+                if !matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::UnexpectedEof
+                ) {
+                    println!("Could not read request");
+                    println!("{err}");
                 }
+                return;
+            }
+            Err(err) => {
+                println!("Rejected request: {err}");
+                let responder =
+                    Responder::new(client.reader.get_ref(), http::Version::Http10, false);
+                serve_error_json(&responder, err.status(), err.to_string());
+                return;
+            }
+        };
+        if request.version == http::Version::Http2 {
+            //The client preface, over TLS only after agreeing on h2 in the handshake
+            let stream = client.reader.get_ref();
+            let accepted = if stream.is_tls() {
+                stream.alpn_protocol().as_deref() == Some(b"h2")
+            } else {
+                shared.settings.h2c
             };
-
-            let mut user_passwd: Vec<u8> = passwd.as_bytes().into_iter().map(|b| *b).collect();
-            user_passwd.extend([user.salt]);
-            let hashed_passwd = digest(user_passwd);
-            if user.password == hashed_passwd {
-                let session_uuid = Uuid::new_v4();
-
-                {
-                    let mut session = session.write().unwrap();
-                    session.insert(
-                        session_uuid.to_string(),
-                        SessionUser {
-                            user_id: user.id.clone(),
-                            expire: Utc::now() + TimeDelta::seconds(60 * 60),
-                        },
-                    );
-                }
-
-                let json = format!(
-                    "{{\"username\": \"{}\",\"userId\":\"{}\",\"authority\":\"{}\"}}",
-                    user.username,
-                    user.id,
-                    session_uuid.to_string(),
+            if accepted {
+                http2::serve(
+                    client,
+                    Box::new(http2::Connection::new()),
+                    shared,
+                    parking,
+                    false,
                 );
-                println!("{json}");
-
-                serve_200_json(stream, json);
             } else {
+                let responder = Responder::new(stream, http::Version::Http10, false);
                 serve_error_json(
-                    stream,
-                    HttpError::BadRequest,
-                    String::from("User not found or invalid password"),
+                    &responder,
+                    HttpError::HttpVersionNotSupported,
+                    String::from("HTTP/2 was not negotiated"),
                 );
-                return;
             }
+            return;
         }
-        _ => {
-            serve_error_json(
-                stream,
-                HttpError::NotFound,
-                format!("What the hell is {request_line} supposed to mean?"),
-            );
-        }
-    }
-}
+        client.served += 1;
+        request.peer = client.reader.get_ref().peer_ip();
 
-fn extract_user_id(
-    header: &HashMap<String, &str>,
-    session: Arc<RwLock<HashMap<String, SessionUser>>>,
-) -> Result<String, &'static str> {
-    let authority = match header.get("authority") {
-        Some(auth) => *auth,
-        None => {
-            return Err("No Authority in header");
-        }
-    };
+        //The query is left out, it can carry an authority
+        println!("{} {} {}", request.method, request.path(), request.version);
 
-    let session_user: SessionUser;
-    {
-        let session = session.read().unwrap();
-        session_user = match session.get(authority) {
-            Some(yay) => yay.clone(),
-            None => {
-                return Err("No user associated with Authority");
-            }
-        };
-    }
+        let keep_alive =
+            request.keep_alive() && client.served < shared.settings.max_requests_per_connection;
+        let responder = Responder::new(client.reader.get_ref(), request.version, keep_alive);
+        let responder = configure(responder, &request, &shared.settings);
+        handle_request(&responder, &request, shared);
 
-    if session_user.expire < Utc::now() {
-        let mut session = session.write().unwrap();
-        session.remove(authority);
-        return Err("Authority expired");
+        if let Some(hand_over) = responder.take_hand_over() {
+            drop(responder);
+            hand_over(client);
+            return;
+        }
+        if !responder.keep_alive() {
+            return;
+        }
+        //Pipelined requests already sitting in the buffer are served right away
+        if !client.has_buffered() {
+            parking.park(client);
+            return;
+        }
     }
+}
 
-    Ok(session_user.user_id)
+///Sets `responder` up the way every response to `request` needs,
+///whichever protocol carries it.
+fn configure<'a>(
+    responder: Responder<'a>,
+    request: &'a Request,
+    settings: &Settings,
+) -> Responder<'a> {
+    responder
+        .with_compression(
+            request.headers.get("accept-encoding"),
+            settings.compression_threshold,
+        )
+        .with_headers(cors::headers(&settings.cors, request))
+        .discard_body(request.method == "HEAD")
 }
 
-//TODO return result instead of accepting stream
-fn extract_body(
-    stream: &TcpStream,
-    buf_reader: std::io::Take<BufReader<&TcpStream>>,
-    header: HashMap<String, &str>,
-) -> Option<String> {
-    let content_length = header
-        .get("content-length")
-        .unwrap_or(&"0")
-        .parse()
-        .unwrap_or(0);
-
-    let mut body = String::with_capacity(content_length);
-
-    if content_length > 0 {
-        match buf_reader
-            .take(content_length as u64)
-            .read_to_string(&mut body)
-        {
-            Ok(_) => Some(body),
-            Err(err) => {
-                serve_error_json(stream, HttpError::BadRequest, err.to_string());
-                return None;
-            }
+///Hands `request` to whatever answers its target.
+fn handle_request(responder: &Responder, request: &Request, shared: &Shared) {
+    if request.target == "*" {
+        if request.method == "OPTIONS" {
+            serve_options(responder, request, &shared.settings, &SERVER_METHODS);
+        } else {
+            serve_error_json(
+                responder,
+                HttpError::BadRequest,
+                String::from("* is only a target for OPTIONS"),
+            );
         }
+    } else if request.path().starts_with("/api/") {
+        handle_api_request(responder, request, shared, None);
     } else {
-        serve_error_json(stream, HttpError::LengthRequired, String::new());
-        return None;
+        files::handle_file_request(responder, &shared.settings, &shared.file_cache, request);
     }
 }
 
-fn handle_file_request(mut stream: TcpStream, settings: Arc<Settings>, request_line: String) {
-    let request_path = match request_line.split(" ").last() {
-        Some(path) => match path {
-            "/" => "/index.html",
-            path => path,
-        },
-        None => {
-            serve_404_html(stream, format!("Your header sucks!"));
-            return;
-        }
-    };
-
-    let file_path = format!("{}{request_path}", settings.root_path);
-    let file_data = match fs::read(&file_path) {
-        Ok(data) => data,
-        Err(err) => {
-            serve_404_html(stream, err.to_string());
-            return;
+///`origin` is the WebSocket the request came in on.
+fn handle_api_request(stream: &Responder, request: &Request, shared: &Shared, origin: Option<u64>) {
+    let method = request.method.as_str();
+    let (path, query) = router::split_target(&request.target);
+    //HEAD runs the GET handler, the responder drops the body
+    let route_method = if method == "HEAD" { "GET" } else { method };
+
+    match shared.router.resolve(route_method, path) {
+        Resolution::Found(handler, params) => handler(ApiRequest {
+            stream,
+            request,
+            settings: &shared.settings,
+            sql_connection: shared.sql_connection.clone(),
+            session: shared.session.clone(),
+            events: shared.events.clone(),
+            websockets: shared.websockets.clone(),
+            origin,
+            params,
+            query,
+        }),
+        Resolution::MethodNotAllowed(allowed) if method == "OPTIONS" => {
+            serve_options(stream, request, &shared.settings, &allow_list(allowed));
         }
-    };
-
-    let mime = mime_guess::from_path(&request_path)
-        .first_or_octet_stream()
-        .to_string();
-    let header = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
-        mime,
-        file_data.len()
-    );
-
-    match stream.write_all(header.as_bytes()) {
-        Err(err) => {
-            println!("Could not write header to stream");
-            println!("{err}");
+        Resolution::MethodNotAllowed(allowed) => {
+            let allow = format!("Allow: {}\r\n", allow_list(allowed).join(", "));
+            write_error_json(
+                stream,
+                HttpError::MethodNotAllowed,
+                format!("{path} does not accept {method}"),
+                &allow,
+            );
         }
-        _ => {}
-    }
-    match stream.write_all(file_data.as_slice()) {
-        Err(err) => {
-            println!("Could not write content to stream");
-            println!("{err}");
+        Resolution::NotFound => {
+            serve_error_json(
+                stream,
+                HttpError::NotFound,
+                format!("What the hell is {method} {path} supposed to mean?"),
+            );
         }
-        _ => {}
     }
 }
 
-fn query_to_object<T: Sql>(
-    sql_connection: Arc<Mutex<Connection>>,
-    sql_query: &str,
-) -> rusqlite::Result<Vec<Box<T>>> {
-    let mut results = Vec::new();
-
-    {
-        let conn = sql_connection.lock().unwrap();
-        let mut statement = conn.prepare(sql_query)?;
-        //Would totally love to drop the connection mutex before any data conversions,
-        //however, the data from 'query()' does not live long enough rip
-        let query = statement.query_map([], |row| T::from_sql_row(row))?;
-
-        results.extend(query);
+///Methods something routed to `methods` answers, HEAD and OPTIONS included.
+fn allow_list(mut methods: Vec<&'static str>) -> Vec<&'static str> {
+    if let Some(i) = methods.iter().position(|method| *method == "GET") {
+        methods.insert(i + 1, "HEAD");
     }
+    methods.push("OPTIONS");
+    methods
+}
 
-    let vec_of_boxes = results.into_iter().filter_map(|r| r.ok()).collect();
+///Answers OPTIONS with the methods allowed, which browsers also send as
+///the CORS preflight.
+fn serve_options(mut stream: &Responder, request: &Request, settings: &Settings, methods: &[&str]) {
+    let header = format!(
+        "HTTP/1.1 204 No Content\r\n{}Allow: {}\r\n{}\r\n",
+        stream.common_headers(),
+        methods.join(", "),
+        cors::preflight_headers(&settings.cors, request, methods)
+    );
+    if let Err(err) = stream.write_all(header.as_bytes()) {
+        println!("Could not write header to stream");
+        println!("{err}");
+    }
+}
 
-    Ok(vec_of_boxes)
+fn serve_200_json(stream: &Responder, body: String) {
+    write_200_json(stream, body, "");
 }
 
-fn serve_200_json(mut stream: &TcpStream, body: String) {
-    let body = body.as_bytes();
+///`headers` are extra header lines, each terminated with \r\n.
+fn write_200_json(mut stream: &Responder, body: String, headers: &str) {
+    let (body, encoding_headers) = stream.encode("application/json", body.as_bytes());
     let header = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        "HTTP/1.1 200 OK\r\n{}{}Content-Type: application/json\r\n{}Content-Length: {}\r\n\r\n",
+        stream.common_headers(),
+        headers,
+        encoding_headers,
         body.len()
     );
-    match stream.write_all(header.as_bytes()) {
-        Err(err) => {
-            println!("Could not write header to stream");
-            println!("{err}");
-        }
-        _ => {}
+    if let Err(err) = stream.write_all(header.as_bytes()) {
+        println!("Could not write header to stream");
+        println!("{err}");
     }
-    match stream.write_all(body) {
-        Err(err) => {
-            println!("Could not write body to stream");
-            println!("{err}");
-        }
-        _ => {}
+    if let Err(err) = stream.write_all(&body) {
+        println!("Could not write body to stream");
+        println!("{err}");
     }
 }
 
-fn serve_error_json(mut stream: &TcpStream, error: HttpError, internal: String) {
+fn serve_error_json(stream: &Responder, error: HttpError, internal: String) {
+    write_error_json(stream, error, internal, "");
+}
+
+///`headers` are extra header lines, each terminated with \r\n.
+fn write_error_json(mut stream: &Responder, error: HttpError, internal: String, headers: &str) {
     let body = match error {
         HttpError::BadRequest => JsonError {
             message: "400 Bad Request",
@@ -677,37 +575,71 @@ fn serve_error_json(mut stream: &TcpStream, error: HttpError, internal: String)
             code: 404,
             internal,
         },
+        HttpError::MethodNotAllowed => JsonError {
+            message: "405 Method Not Allowed",
+            code: 405,
+            internal,
+        },
         HttpError::LengthRequired => JsonError {
             message: "411 Length Required",
             code: 411,
             internal,
         },
+        HttpError::PayloadTooLarge => JsonError {
+            message: "413 Payload Too Large",
+            code: 413,
+            internal,
+        },
+        HttpError::UriTooLong => JsonError {
+            message: "414 URI Too Long",
+            code: 414,
+            internal,
+        },
+        HttpError::UpgradeRequired => JsonError {
+            message: "426 Upgrade Required",
+            code: 426,
+            internal,
+        },
+        HttpError::RequestHeaderFieldsTooLarge => JsonError {
+            message: "431 Request Header Fields Too Large",
+            code: 431,
+            internal,
+        },
         HttpError::InternalServerError => JsonError {
             message: "500 Internal Server Error",
             code: 500,
             internal,
         },
+        HttpError::NotImplemented => JsonError {
+            message: "501 Not Implemented",
+            code: 501,
+            internal,
+        },
+        HttpError::HttpVersionNotSupported => JsonError {
+            message: "505 HTTP Version Not Supported",
+            code: 505,
+            internal,
+        },
     };
 
     let message = format!("{{\"error\":{}}}", serde_json::to_string(&body).unwrap());
 
     let response = format!(
-        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+        "HTTP/1.1 {}\r\n{}{}Content-Length: {}\r\n\r\n{}",
         body.message,
-        message.as_bytes().len(),
+        stream.common_headers(),
+        headers,
+        message.len(),
         message
     );
 
-    match stream.write_all(response.as_bytes()) {
-        Err(err) => {
-            println!("Could not write {} message to stream", body.code);
-            println!("{err}");
-        }
-        _ => {}
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        println!("Could not write {} message to stream", body.code);
+        println!("{err}");
     };
 }
 
-fn serve_404_html(mut stream: TcpStream, message: String) {
+fn serve_404_html(mut stream: &Responder, message: String) {
     let first = r#"
 <!DOCTYPE html>
 <html lang="en">
@@ -730,13 +662,210 @@ fn serve_404_html(mut stream: TcpStream, message: String) {
 
     let content404 = format!("{first}{message}{second}");
     let content404_len = content404.len();
-    let response =
-        format!("HTTP/1.1 404 NOT FOUND\r\ncontent-length: {content404_len}\r\n\r\n{content404}");
-    match stream.write_all(response.as_bytes()) {
-        Err(err) => {
-            println!("Could not write 404 message to stream");
-            println!("{err}");
+    let response = format!(
+        "HTTP/1.1 404 NOT FOUND\r\n{}content-length: {content404_len}\r\n\r\n{content404}",
+        stream.common_headers()
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        println!("Could not write 404 message to stream");
+        println!("{err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::BufReader,
+        net::{SocketAddr, TcpStream},
+        path::PathBuf,
+        time::Instant,
+    };
+    use uuid::Uuid;
+
+    ///A web root with a few small files, removed on drop.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new() -> Root {
+            let dir = std::env::temp_dir().join(format!("webber-serve-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("index.html"), "<p>index</p>").unwrap();
+            for name in ["a", "b", "c"] {
+                fs::write(dir.join(format!("{name}.txt")), name.repeat(10)).unwrap();
+            }
+            Root(dir)
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    ///Starts a server with `n_threads` workers the way `main` does, on a free port.
+    fn server(root: &Root, n_threads: usize) -> SocketAddr {
+        let settings: Settings = serde_json::from_value(serde_json::json!({
+            "root_path": root.0,
+            "bind_addr": "127.0.0.1",
+            "bind_port": "0",
+            "n_threads": n_threads,
+            "data_path": ":memory:",
+            "keep_alive_timeout": 5,
+            "max_requests_per_connection": 3,
+        }))
+        .unwrap();
+        let sql_connection = Connection::open_in_memory().unwrap();
+        sql_connection
+            .execute_batch(include_str!("../init.sql"))
+            .unwrap();
+        let sql_connection = Arc::new(Mutex::new(sql_connection));
+        let session = Arc::new(Sessions::new(sql_connection.clone(), &settings.sessions).unwrap());
+        let (events, _) = Events::new(&settings.events).unwrap();
+        let (websockets, _) = websocket::hub().unwrap();
+        let keep_alive_timeout = Duration::from_secs(settings.keep_alive_timeout);
+        let shared = Arc::new(Shared {
+            settings,
+            sql_connection,
+            session,
+            router: api::router(),
+            file_cache: FileCache::new(&Default::default()),
+            events: Arc::new(events),
+            websockets,
+        });
+
+        let spool = Arc::new(ThreadSpool::new(n_threads));
+        let (parking, lot) = connection::parking().unwrap();
+        serve_parked(lot, parking.clone(), shared, spool, keep_alive_timeout);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || accept(listener, None, keep_alive_timeout, &parking));
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        BufReader::new(stream)
+    }
+
+    ///Reads one response, its head and body. `head` says the request was
+    ///HEAD, whose response has no body whatever its Content-Length.
+    fn response(reader: &mut BufReader<TcpStream>, head: bool) -> (String, String) {
+        let mut lines = String::new();
+        while !lines.ends_with("\r\n\r\n") {
+            assert!(reader.read_line(&mut lines).unwrap() > 0, "{lines}");
         }
-        _ => {}
+        let len: usize = lines
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+        let mut body = vec![0; if head { 0 } else { len }];
+        reader.read_exact(&mut body).unwrap();
+        (lines, String::from_utf8(body).unwrap())
+    }
+
+    ///Whether the server closed the connection without sending anything more.
+    fn closed(reader: &mut BufReader<TcpStream>) -> bool {
+        reader.fill_buf().unwrap().is_empty()
+    }
+
+    fn get(path: &str) -> String {
+        format!("GET {path} HTTP/1.1\r\nHost: serve.test\r\n\r\n")
+    }
+
+    #[test]
+    fn keep_alive_reuses_the_connection() {
+        let root = Root::new();
+        let mut reader = connect(server(&root, 2));
+
+        for (i, name) in ["a", "b", "c"].into_iter().enumerate() {
+            reader
+                .get_mut()
+                .write_all(get(&format!("/{name}.txt")).as_bytes())
+                .unwrap();
+            let (head, body) = response(&mut reader, false);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+            assert_eq!(body, name.repeat(10));
+            //HTTP/1.1 stays open unless told otherwise, up to max_requests_per_connection
+            assert_eq!(head.contains("Connection: close\r\n"), i == 2, "{head}");
+        }
+        assert!(closed(&mut reader));
+
+        //Asking for the connection to close is honoured right away
+        let mut reader = connect(reader.get_ref().peer_addr().unwrap());
+        let request = "GET /a.txt HTTP/1.1\r\nHost: serve.test\r\nConnection: close\r\n\r\n";
+        reader.get_mut().write_all(request.as_bytes()).unwrap();
+        let (head, _) = response(&mut reader, false);
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        assert!(closed(&mut reader));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let root = Root::new();
+        let mut reader = connect(server(&root, 2));
+
+        //All three sent before reading anything, the last one split in two
+        let pipelined = format!("{}{}{}", get("/c.txt"), get("/a.txt"), get("/b.txt"));
+        let (first, rest) = pipelined.split_at(pipelined.len() - 10);
+        reader.get_mut().write_all(first.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        reader.get_mut().write_all(rest.as_bytes()).unwrap();
+
+        for name in ["c", "a", "b"] {
+            let (head, body) = response(&mut reader, false);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+            assert_eq!(body, name.repeat(10));
+        }
+        assert!(closed(&mut reader));
+    }
+
+    #[test]
+    fn head_responses_have_no_body() {
+        let root = Root::new();
+        let mut reader = connect(server(&root, 2));
+
+        let head = "HEAD /index.html HTTP/1.1\r\nHost: serve.test\r\n\r\n";
+        let api = "HEAD /api/task HTTP/1.1\r\nHost: serve.test\r\n\r\n";
+        let pipelined = format!("{head}{api}{}", get("/a.txt"));
+        reader.get_mut().write_all(pipelined.as_bytes()).unwrap();
+
+        //Content-Length is the one a GET would get, nothing follows the head
+        let (lines, _) = response(&mut reader, true);
+        assert!(lines.starts_with("HTTP/1.1 200 OK\r\n"), "{lines}");
+        assert!(lines.contains("Content-Length: 12\r\n"), "{lines}");
+        let (lines, _) = response(&mut reader, true);
+        assert!(lines.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{lines}");
+        assert!(!lines.contains("Content-Length: 0\r\n"), "{lines}");
+        //Otherwise the body would be taken for the next response
+        let (lines, body) = response(&mut reader, false);
+        assert!(lines.starts_with("HTTP/1.1 200 OK\r\n"), "{lines}");
+        assert_eq!(body, "a".repeat(10));
+        assert!(closed(&mut reader));
+    }
+
+    #[test]
+    fn silent_clients_hold_no_worker() {
+        let root = Root::new();
+        let addr = server(&root, 1);
+        //Connected but sending nothing, each would keep the only worker for
+        //the keep alive timeout if it was waited on there
+        let silent: Vec<TcpStream> = (0..10).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let mut reader = connect(addr);
+        reader
+            .get_mut()
+            .write_all(get("/a.txt").as_bytes())
+            .unwrap();
+        let (head, _) = response(&mut reader, false);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(silent);
     }
 }
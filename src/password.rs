@@ -0,0 +1,208 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand::rngs::OsRng;
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+
+use crate::data_structs::PasswordSettings;
+
+///What checking a password against the stored hash found out.
+#[derive(PartialEq, Debug)]
+pub enum Verified {
+    No,
+    Yes,
+    ///Right, but the stored hash is SHA-256 or has other costs than
+    ///configured now, and should be replaced with a fresh `hash`.
+    Outdated,
+}
+
+///Hashes `password` with Argon2id and a random salt into a PHC string,
+///which carries the salt and costs along with the hash.
+pub fn hash(password: &str, settings: &PasswordSettings) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = hasher(settings)?
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| err.to_string())?;
+    Ok(hash.to_string())
+}
+
+///Checks `password` against what `users.password` holds. Rows from before
+///Argon2 hold sha256(password || salt) in hex, with `salt` in its own column.
+pub fn verify(password: &str, stored: &str, salt: u8, settings: &PasswordSettings) -> Verified {
+    if !stored.starts_with('$') {
+        let mut salted = password.as_bytes().to_vec();
+        salted.push(salt);
+        let hashed = sha256::digest(salted);
+        return match bool::from(hashed.as_bytes().ct_eq(stored.as_bytes())) {
+            true => Verified::Outdated,
+            false => Verified::No,
+        };
+    }
+
+    let parsed = match PasswordHash::new(stored) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("Unreadable password hash");
+            println!("{err}");
+            return Verified::No;
+        }
+    };
+    //The costs come from the stored string, the comparison is constant time
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Verified::No;
+    }
+
+    let current = match Params::try_from(&parsed) {
+        Ok(current) => current,
+        Err(_) => return Verified::Outdated,
+    };
+    if parsed.algorithm != Algorithm::Argon2id.ident()
+        || current.m_cost() != settings.memory_cost
+        || current.t_cost() != settings.time_cost
+        || current.p_cost() != settings.parallelism
+    {
+        return Verified::Outdated;
+    }
+    Verified::Yes
+}
+
+///A hash of nothing in particular, for `verify_nobody` to check against.
+static NOBODY: OnceLock<String> = OnceLock::new();
+
+///Checks `settings` are costs Argon2 accepts and gets `verify_nobody`
+///ready, so even the first login of an unknown user takes its usual time.
+pub fn init(settings: &PasswordSettings) -> Result<(), String> {
+    let nobody = hash("", settings)?;
+    let _ = NOBODY.set(nobody);
+    Ok(())
+}
+
+///Takes as long as `verify` for a user that doesn't exist, so response
+///times don't tell which usernames are taken.
+pub fn verify_nobody(password: &str, settings: &PasswordSettings) {
+    if let Some(nobody) = NOBODY.get() {
+        verify(password, nobody, 0, settings);
+    }
+}
+
+fn hasher(settings: &PasswordSettings) -> Result<Argon2<'static>, String> {
+    Ok(Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        params(settings)?,
+    ))
+}
+
+fn params(settings: &PasswordSettings) -> Result<Params, String> {
+    Params::new(
+        settings.memory_cost,
+        settings.time_cost,
+        settings.parallelism,
+        None,
+    )
+    .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn settings(memory_cost: u32, time_cost: u32, parallelism: u32) -> PasswordSettings {
+        PasswordSettings {
+            memory_cost,
+            time_cost,
+            parallelism,
+        }
+    }
+
+    #[test]
+    fn argon2_hashes() {
+        let settings = settings(1024, 1, 1);
+        let stored = hash("hunter2", &settings).unwrap();
+        assert!(
+            stored.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"),
+            "{stored}"
+        );
+        assert_ne!(stored, hash("hunter2", &settings).unwrap());
+
+        assert_eq!(verify("hunter2", &stored, 0, &settings), Verified::Yes);
+        assert_eq!(verify("hunter3", &stored, 0, &settings), Verified::No);
+        assert_eq!(verify("", &stored, 0, &settings), Verified::No);
+        assert_eq!(
+            verify("hunter2", "$argon2id$junk", 0, &settings),
+            Verified::No
+        );
+    }
+
+    #[test]
+    fn legacy_hashes_are_outdated() {
+        let settings = settings(1024, 1, 1);
+        let stored = sha256::digest(b"hunter2\x07".to_vec());
+        assert_eq!(verify("hunter2", &stored, 7, &settings), Verified::Outdated);
+        assert_eq!(verify("hunter2", &stored, 8, &settings), Verified::No);
+        assert_eq!(verify("hunter3", &stored, 7, &settings), Verified::No);
+        assert_eq!(verify("hunter2", "", 7, &settings), Verified::No);
+    }
+
+    #[test]
+    fn changed_costs_are_outdated() {
+        let old = settings(1024, 1, 1);
+        let stored = hash("hunter2", &old).unwrap();
+        for new in [
+            settings(2048, 1, 1),
+            settings(1024, 2, 1),
+            settings(1024, 1, 2),
+        ] {
+            assert_eq!(verify("hunter2", &stored, 0, &new), Verified::Outdated);
+            //Only the right password tells the hash is outdated
+            assert_eq!(verify("hunter3", &stored, 0, &new), Verified::No);
+            let fresh = hash("hunter2", &new).unwrap();
+            assert_eq!(verify("hunter2", &fresh, 0, &new), Verified::Yes);
+        }
+
+        //Other variants of Argon2 get replaced as well
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, params(&old).unwrap())
+            .hash_password(b"hunter2", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        assert_eq!(verify("hunter2", &argon2i, 0, &old), Verified::Outdated);
+    }
+
+    #[test]
+    fn invalid_costs() {
+        assert!(init(&settings(0, 1, 1)).is_err());
+        assert!(init(&settings(1024, 0, 1)).is_err());
+        assert!(hash("hunter2", &settings(1024, 1, 0)).is_err());
+    }
+
+    #[test]
+    fn nobody_takes_as_long_as_somebody() {
+        let settings = settings(4096, 2, 1);
+        init(&settings).unwrap();
+        let nobody = NOBODY.get().unwrap();
+        assert_eq!(verify("", nobody, 0, &settings), Verified::Yes);
+
+        let stored = hash("hunter2", &settings).unwrap();
+        let fastest = |check: &dyn Fn()| {
+            (0..3)
+                .map(|_| {
+                    let start = Instant::now();
+                    check();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::ZERO)
+        };
+        let somebody = fastest(&|| {
+            verify("hunter3", &stored, 0, &settings);
+        });
+        let nobody = fastest(&|| verify_nobody("hunter3", &settings));
+        assert!(nobody * 4 > somebody, "{nobody:?} against {somebody:?}");
+    }
+}
@@ -0,0 +1,255 @@
+use chrono::{Days, Months, NaiveDate};
+
+///How a task repeats, as described by the recurrence columns of `tasks`.
+///A `step` of 0 means the task happens once, on `start`.
+///Month steps are always counted from `start` so an anchor on the 31st
+///clamps to the end of shorter months without drifting (Jan 31, Feb 28, Mar 31).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Recurrence {
+    pub start: NaiveDate,
+    pub monthly: bool,
+    pub step: u32,
+    pub stop: NaiveDate,
+}
+
+impl Recurrence {
+    pub fn new(start: NaiveDate, monthly: bool, step: u32, stop: NaiveDate) -> Recurrence {
+        Recurrence {
+            start,
+            monthly,
+            step,
+            stop,
+        }
+    }
+
+    pub fn is_recurring(&self) -> bool {
+        self.step != 0
+    }
+
+    ///Date of the n:th occurrence counted from zero, ignoring the stop date.
+    ///None when the date falls outside what chrono can represent.
+    pub fn nth(&self, n: u32) -> Option<NaiveDate> {
+        if n == 0 {
+            return Some(self.start);
+        }
+        if !self.is_recurring() {
+            return None;
+        }
+
+        let offset = n.checked_mul(self.step)?;
+        if self.monthly {
+            self.start.checked_add_months(Months::new(offset))
+        } else {
+            self.start.checked_add_days(Days::new(offset as u64))
+        }
+    }
+
+    ///Every occurrence from `start` up to and including `stop`.
+    ///A non recurring task always yields its start date, even past `stop`.
+    pub fn occurrences(&self) -> Occurrences {
+        Occurrences {
+            recurrence: *self,
+            n: 0,
+            done: false,
+        }
+    }
+
+    ///Occurrences within `from..=to`.
+    pub fn between(&self, from: NaiveDate, to: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+        self.occurrences()
+            .skip_while(move |date| *date < from)
+            .take_while(move |date| *date <= to)
+    }
+}
+
+pub struct Occurrences {
+    recurrence: Recurrence,
+    n: u32,
+    done: bool,
+}
+
+impl Iterator for Occurrences {
+    type Item = NaiveDate;
+
+    fn next(&mut self) -> Option<NaiveDate> {
+        if self.done {
+            return None;
+        }
+
+        let date = match self.recurrence.nth(self.n) {
+            Some(date) => date,
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+
+        if !self.recurrence.is_recurring() {
+            self.done = true;
+            return Some(date);
+        }
+
+        if date > self.recurrence.stop {
+            self.done = true;
+            return None;
+        }
+
+        self.n = match self.n.checked_add(1) {
+            Some(n) => n,
+            None => {
+                self.done = true;
+                return Some(date);
+            }
+        };
+
+        Some(date)
+    }
+}
+
+///Occurrences up to and including `today` that are not among `completed`,
+///oldest first.
+pub fn pending(
+    recurrence: &Recurrence,
+    completed: &[NaiveDate],
+    today: NaiveDate,
+) -> Vec<NaiveDate> {
+    recurrence
+        .occurrences()
+        .take_while(|date| *date <= today)
+        .filter(|date| !completed.contains(date))
+        .collect()
+}
+
+///The earliest occurrence not among `completed`. This is in the past when
+///the task is overdue. None once every occurrence has been completed.
+pub fn next_due(recurrence: &Recurrence, completed: &[NaiveDate]) -> Option<NaiveDate> {
+    recurrence
+        .occurrences()
+        .find(|date| !completed.contains(date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn month_end_clamps_without_drifting() {
+        let r = Recurrence::new(date(2025, 1, 31), true, 1, date(2025, 5, 31));
+        let dates: Vec<NaiveDate> = r.occurrences().collect();
+        assert_eq!(
+            dates,
+            vec![
+                date(2025, 1, 31),
+                date(2025, 2, 28),
+                date(2025, 3, 31),
+                date(2025, 4, 30),
+                date(2025, 5, 31),
+            ]
+        );
+    }
+
+    #[test]
+    fn leap_years() {
+        let r = Recurrence::new(date(2024, 1, 31), true, 1, date(2024, 3, 31));
+        assert_eq!(r.nth(1), Some(date(2024, 2, 29)));
+
+        //Yearly from a leap day lands on Feb 28 until the next leap year
+        let r = Recurrence::new(date(2024, 2, 29), true, 12, date(2028, 12, 31));
+        let dates: Vec<NaiveDate> = r.occurrences().collect();
+        assert_eq!(
+            dates,
+            vec![
+                date(2024, 2, 29),
+                date(2025, 2, 28),
+                date(2026, 2, 28),
+                date(2027, 2, 28),
+                date(2028, 2, 29),
+            ]
+        );
+
+        let r = Recurrence::new(date(2024, 2, 28), false, 1, date(2024, 3, 1));
+        assert_eq!(r.occurrences().count(), 3);
+    }
+
+    #[test]
+    fn stop_date_is_inclusive() {
+        let r = Recurrence::new(date(2025, 1, 1), false, 7, date(2025, 1, 15));
+        let dates: Vec<NaiveDate> = r.occurrences().collect();
+        assert_eq!(
+            dates,
+            vec![date(2025, 1, 1), date(2025, 1, 8), date(2025, 1, 15)]
+        );
+
+        let r = Recurrence::new(date(2025, 1, 1), false, 7, date(2025, 1, 14));
+        assert_eq!(r.occurrences().last(), Some(date(2025, 1, 8)));
+    }
+
+    #[test]
+    fn non_recurring_happens_once() {
+        let r = Recurrence::new(date(2025, 3, 1), false, 0, date(2025, 1, 1));
+        assert_eq!(r.nth(0), Some(date(2025, 3, 1)));
+        assert_eq!(r.nth(1), None);
+        assert_eq!(r.occurrences().collect::<Vec<_>>(), vec![date(2025, 3, 1)]);
+    }
+
+    #[test]
+    fn nth_overflow_is_none() {
+        let r = Recurrence::new(date(2025, 1, 1), false, u32::MAX, date(2025, 12, 31));
+        assert_eq!(r.nth(2), None);
+        let r = Recurrence::new(date(2025, 1, 1), true, 1, NaiveDate::MAX);
+        assert_eq!(r.nth(u32::MAX), None);
+    }
+
+    #[test]
+    fn between_bounds_are_inclusive() {
+        let r = Recurrence::new(date(2025, 1, 1), false, 1, date(2025, 1, 31));
+        let dates: Vec<NaiveDate> = r.between(date(2025, 1, 10), date(2025, 1, 12)).collect();
+        assert_eq!(
+            dates,
+            vec![date(2025, 1, 10), date(2025, 1, 11), date(2025, 1, 12)]
+        );
+
+        //Window between two occurrences
+        let r = Recurrence::new(date(2025, 1, 1), false, 7, date(2025, 1, 31));
+        assert_eq!(r.between(date(2025, 1, 2), date(2025, 1, 7)).count(), 0);
+
+        //Window past the stop date
+        assert_eq!(r.between(date(2025, 2, 1), date(2025, 3, 1)).count(), 0);
+    }
+
+    #[test]
+    fn pending_leaves_out_completed_and_skipped() {
+        let r = Recurrence::new(date(2025, 1, 1), false, 1, date(2025, 1, 31));
+        let completed = [date(2025, 1, 1)];
+        let skipped = [date(2025, 1, 3)];
+        let dealt_with: Vec<NaiveDate> = completed.iter().chain(&skipped).copied().collect();
+
+        assert_eq!(
+            pending(&r, &dealt_with, date(2025, 1, 4)),
+            vec![date(2025, 1, 2), date(2025, 1, 4)]
+        );
+        //Nothing after today is pending yet
+        assert_eq!(pending(&r, &dealt_with, date(2024, 12, 31)), vec![]);
+    }
+
+    #[test]
+    fn next_due_skips_past_dealt_with_dates() {
+        let r = Recurrence::new(date(2025, 1, 1), false, 1, date(2025, 1, 3));
+        assert_eq!(next_due(&r, &[]), Some(date(2025, 1, 1)));
+        assert_eq!(
+            next_due(&r, &[date(2025, 1, 1), date(2025, 1, 2)]),
+            Some(date(2025, 1, 3))
+        );
+        assert_eq!(
+            next_due(&r, &[date(2025, 1, 1), date(2025, 1, 2), date(2025, 1, 3)]),
+            None
+        );
+
+        let once = Recurrence::new(date(2025, 1, 1), false, 0, date(2025, 1, 1));
+        assert_eq!(next_due(&once, &[date(2025, 1, 1)]), None);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+///Maps a method and path onto a handler.
+///Patterns are split on '/' and a segment written as `{name}` captures
+///whatever is in its place, so `/api/task/{id}` matches `/api/task/abc`.
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+struct Route<H> {
+    method: &'static str,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+pub enum Resolution<'a, H> {
+    Found(&'a H, HashMap<String, String>),
+    ///The path exists but not for this method, carries the methods that do.
+    MethodNotAllowed(Vec<&'static str>),
+    NotFound,
+}
+
+impl<H> Router<H> {
+    pub fn new() -> Router<H> {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn route(mut self, method: &'static str, pattern: &'static str, handler: H) -> Router<H> {
+        let segments = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                match segment
+                    .strip_prefix('{')
+                    .and_then(|segment| segment.strip_suffix('}'))
+                {
+                    Some(name) => Segment::Param(name),
+                    None => Segment::Literal(segment),
+                }
+            })
+            .collect();
+
+        self.routes.push(Route {
+            method,
+            segments,
+            handler,
+        });
+        self
+    }
+
+    ///Path is expected without its query string.
+    pub fn resolve(&self, method: &str, path: &str) -> Resolution<'_, H> {
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        let mut allowed: Vec<&'static str> = Vec::new();
+
+        for route in &self.routes {
+            let params = match route.capture(&parts) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            if route.method == method {
+                return Resolution::Found(&route.handler, params);
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        if allowed.is_empty() {
+            Resolution::NotFound
+        } else {
+            Resolution::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+impl<H> Route<H> {
+    fn capture(&self, parts: &[&str]) -> Option<HashMap<String, String>> {
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => (),
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.to_string(), percent_decode(part));
+                }
+            }
+        }
+
+        Some(params)
+    }
+}
+
+///Splits a request target into its path and decoded query parameters.
+pub fn split_target(target: &str) -> (&str, HashMap<String, String>) {
+    match target.split_once('?') {
+        Some((path, query)) => (path, parse_query(query)),
+        None => (target, HashMap::new()),
+    }
+}
+
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (
+                percent_decode(&key.replace('+', " ")),
+                percent_decode(&value.replace('+', " ")),
+            ),
+            None => (percent_decode(&pair.replace('+', " ")), String::new()),
+        })
+        .collect()
+}
+
+///Decodes %XX escapes, leaving malformed ones as they are.
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        Router::new()
+            .route("GET", "/api/tasks", "tasks")
+            .route("POST", "/api/task", "new task")
+            .route("GET", "/api/task/{id}", "task")
+            .route("PUT", "/api/task/{id}", "edit task")
+            .route("DELETE", "/api/task/{id}", "delete task")
+            .route("GET", "/api/list/{list}/task/{id}", "list task")
+    }
+
+    #[test]
+    fn captures_params() {
+        let router = router();
+        match router.resolve("GET", "/api/task/abc") {
+            Resolution::Found(handler, params) => {
+                assert_eq!(*handler, "task");
+                assert_eq!(params.len(), 1);
+                assert_eq!(params["id"], "abc");
+            }
+            _ => panic!("no route for /api/task/abc"),
+        }
+        match router.resolve("GET", "//api/list/a%20b/task/42/") {
+            Resolution::Found(handler, params) => {
+                assert_eq!(*handler, "list task");
+                assert_eq!(params["list"], "a b");
+                assert_eq!(params["id"], "42");
+            }
+            _ => panic!("no route for the list task"),
+        }
+        match router.resolve("GET", "/api/tasks") {
+            Resolution::Found(handler, params) => {
+                assert_eq!(*handler, "tasks");
+                assert!(params.is_empty());
+            }
+            _ => panic!("no route for /api/tasks"),
+        }
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let router = router();
+        match router.resolve("PATCH", "/api/task/abc") {
+            Resolution::MethodNotAllowed(allowed) => {
+                assert_eq!(allowed, vec!["GET", "PUT", "DELETE"])
+            }
+            _ => panic!("PATCH should not be allowed"),
+        }
+        match router.resolve("GET", "/api/task") {
+            Resolution::MethodNotAllowed(allowed) => assert_eq!(allowed, vec!["POST"]),
+            _ => panic!("GET /api/task should not be allowed"),
+        }
+        //Methods are case sensitive
+        assert!(matches!(
+            router.resolve("get", "/api/tasks"),
+            Resolution::MethodNotAllowed(_)
+        ));
+    }
+
+    #[test]
+    fn not_found() {
+        let router = router();
+        for path in [
+            "/",
+            "/api",
+            "/api/task/abc/extra",
+            "/api/Tasks",
+            "/api/list/a/task",
+        ] {
+            assert!(
+                matches!(router.resolve("GET", path), Resolution::NotFound),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn targets() {
+        let (path, query) = split_target("/api/tasks");
+        assert_eq!(path, "/api/tasks");
+        assert!(query.is_empty());
+
+        let (path, query) = split_target("/api/agenda?from=2024-01-01&to=2024-01-31");
+        assert_eq!(path, "/api/agenda");
+        assert_eq!(query.len(), 2);
+        assert_eq!(query["from"], "2024-01-01");
+        assert_eq!(query["to"], "2024-01-31");
+
+        //Only the first '?' splits, later ones belong to the query
+        let (path, query) = split_target("/search?q=what?");
+        assert_eq!(path, "/search");
+        assert_eq!(query["q"], "what?");
+    }
+
+    #[test]
+    fn queries() {
+        let query = parse_query("a=1&&b&c=&d=x=y&e+f=g+h&i=%2B%26%3D");
+        assert_eq!(query.len(), 6);
+        assert_eq!(query["a"], "1");
+        assert_eq!(query["b"], "");
+        assert_eq!(query["c"], "");
+        assert_eq!(query["d"], "x=y");
+        assert_eq!(query["e f"], "g h");
+        assert_eq!(query["i"], "+&=");
+
+        //The last of repeated keys wins
+        assert_eq!(parse_query("a=1&a=2")["a"], "2");
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2fc%2F"), "a b/c/");
+        assert_eq!(percent_decode("%e2%9C%93"), "✓");
+        //'+' only means a space in query strings
+        assert_eq!(percent_decode("a+b"), "a+b");
+
+        //Malformed escapes are left alone
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%4g"), "%zz%4g");
+        assert_eq!(percent_decode("%%41"), "%A");
+
+        //Bytes that aren't UTF-8 become replacement characters
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+        assert_eq!(percent_decode("a%e2%9Cb"), "a\u{fffd}b");
+    }
+}
@@ -0,0 +1,741 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use rusqlite::{Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::data_structs::{CookieSettings, SessionSettings, SessionUser};
+use crate::http::Headers;
+
+///Databases created before sessions were stored lack the table.
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    user_id TEXT NOT NULL,
+    created TEXT NOT NULL,
+    expires TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    user_agent TEXT,
+    refresh_hash TEXT,
+    ip TEXT,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);";
+///Columns added since the table was first created, for databases that
+///have it without them.
+const ADDED_COLUMNS: &[(&str, &str)] = &[("refresh_hash", "TEXT"), ("ip", "TEXT")];
+const INDEXES: &str =
+    "CREATE UNIQUE INDEX IF NOT EXISTS sessions_refresh_hash ON sessions (refresh_hash);";
+///last_seen is only written back once it is this far behind, so not every
+///request costs a write. Sessions go idle up to this much early, or a
+///quarter of the idle lifetime when that is shorter.
+const LAST_SEEN_RESOLUTION: TimeDelta = TimeDelta::seconds(60);
+///Longer User-Agent headers are cut off before being stored.
+const MAX_USER_AGENT: usize = 256;
+
+///Logged in sessions, stored in the database so they survive restarts.
+///Only hashes of tokens are stored, a leaked database doesn't let anyone
+///log in. Sessions in use are cached in memory.
+///
+///A session's authority stops working once unused for the idle lifetime.
+///Its refresh token then still gets a new authority, up to the absolute
+///lifetime after logging in, the `expires` column.
+pub struct Sessions {
+    sql_connection: Arc<Mutex<Connection>>,
+    idle_lifetime: TimeDelta,
+    absolute_lifetime: TimeDelta,
+    last_seen_resolution: TimeDelta,
+    cookie: CookieSettings,
+    ///Keyed by token hash.
+    cache: RwLock<HashMap<String, SessionUser>>,
+}
+
+impl Sessions {
+    pub fn new(
+        sql_connection: Arc<Mutex<Connection>>,
+        settings: &SessionSettings,
+    ) -> rusqlite::Result<Sessions> {
+        {
+            let sql_connection = sql_connection.lock().unwrap();
+            sql_connection.execute_batch(SCHEMA)?;
+            for (column, definition) in ADDED_COLUMNS {
+                let query = format!("SELECT {column} FROM sessions LIMIT 0;");
+                if sql_connection.prepare(&query).is_err() {
+                    sql_connection.execute_batch(&format!(
+                        "ALTER TABLE sessions ADD COLUMN {column} {definition};"
+                    ))?;
+                }
+            }
+            sql_connection.execute_batch(INDEXES)?;
+        }
+        let idle_lifetime = TimeDelta::seconds(settings.idle_lifetime as i64);
+        Ok(Sessions {
+            sql_connection,
+            idle_lifetime,
+            last_seen_resolution: LAST_SEEN_RESOLUTION.min(idle_lifetime / 4),
+            absolute_lifetime: TimeDelta::seconds(settings.absolute_lifetime as i64),
+            cookie: settings.cookie.clone(),
+            cache: RwLock::new(HashMap::new()),
+        })
+    }
+
+    ///Logs `user_id` in, returning the token the client authenticates with
+    ///and the refresh token.
+    pub fn create(
+        &self,
+        user_id: &str,
+        user_agent: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> rusqlite::Result<(String, String)> {
+        let token = Uuid::new_v4().to_string();
+        let refresh = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let session = SessionUser {
+            id: Uuid::now_v7().to_string(),
+            user_id: user_id.to_string(),
+            created: now,
+            expire: now + self.absolute_lifetime,
+            last_seen: now,
+            user_agent: user_agent.map(|agent| truncate(agent, MAX_USER_AGENT).to_string()),
+            ip: ip.map(|ip| ip.to_string()),
+        };
+
+        let token_hash = sha256::digest(&token);
+        self.sql_connection.lock().unwrap().execute(
+            "INSERT INTO sessions (id, token_hash, user_id, created, expires, last_seen, user_agent, refresh_hash, ip)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9);",
+            (
+                &session.id,
+                &token_hash,
+                user_id,
+                now,
+                session.expire,
+                now,
+                &session.user_agent,
+                sha256::digest(&refresh),
+                &session.ip,
+            ),
+        )?;
+        self.cache.write().unwrap().insert(token_hash, session);
+        Ok((token, refresh))
+    }
+
+    ///Trades `refresh` for a new authority and refresh token of the same
+    ///session, returned with the session's user. Both old ones stop working.
+    pub fn refresh(
+        &self,
+        refresh: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(String, String, String), &'static str> {
+        let refresh_hash = sha256::digest(refresh);
+        let sql_connection = self.sql_connection.lock().unwrap();
+        let found = sql_connection
+            .query_row(
+                "SELECT * FROM sessions WHERE refresh_hash = ?1;",
+                [&refresh_hash],
+                |row| Ok((row.get::<_, String>("token_hash")?, session_from_row(row)?)),
+            )
+            .optional();
+        let (old_hash, mut session) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => return Err("Unknown refresh token"),
+            Err(err) => {
+                println!("Could not look up session");
+                println!("{err}");
+                return Err("Could not look up session");
+            }
+        };
+        drop(sql_connection);
+        if session.expire < Utc::now() {
+            self.remove(&old_hash);
+            return Err("Session expired");
+        }
+        session.last_seen = Utc::now();
+        session.ip = ip.map(|ip| ip.to_string()).or(session.ip);
+
+        let token = Uuid::new_v4().to_string();
+        let refresh = Uuid::new_v4().to_string();
+        let token_hash = sha256::digest(&token);
+        let sql_connection = self.sql_connection.lock().unwrap();
+        //Matching the old refresh hash again keeps two refreshes racing from both succeeding
+        match sql_connection.execute(
+            "UPDATE sessions SET token_hash = ?1, refresh_hash = ?2, last_seen = ?3, ip = ?4
+            WHERE refresh_hash = ?5;",
+            (
+                &token_hash,
+                sha256::digest(&refresh),
+                session.last_seen,
+                &session.ip,
+                &refresh_hash,
+            ),
+        ) {
+            Ok(1) => (),
+            Ok(_) => return Err("Unknown refresh token"),
+            Err(err) => {
+                println!("Could not refresh session");
+                println!("{err}");
+                return Err("Could not refresh session");
+            }
+        }
+        drop(sql_connection);
+
+        let user_id = session.user_id.clone();
+        let mut cache = self.cache.write().unwrap();
+        cache.remove(&old_hash);
+        cache.insert(token_hash, session);
+        Ok((token, refresh, user_id))
+    }
+
+    ///The user `token` logs in, unless it is unknown or expired. Using it
+    ///keeps it from going idle.
+    pub fn user_id(&self, token: &str) -> Result<String, &'static str> {
+        self.lookup(token).map(|session| session.user_id)
+    }
+
+    ///The session `token` belongs to, unless it is unknown or expired.
+    ///Using it keeps it from going idle.
+    pub fn lookup(&self, token: &str) -> Result<SessionUser, &'static str> {
+        let token_hash = sha256::digest(token);
+        let cached = self.cache.read().unwrap().get(&token_hash).cloned();
+        let loaded = cached.is_none();
+        let mut session = match cached {
+            Some(session) => session,
+            None => match self.load(&token_hash) {
+                Ok(Some(session)) => session,
+                Ok(None) => return Err("No user associated with Authority"),
+                Err(err) => {
+                    println!("Could not look up session");
+                    println!("{err}");
+                    return Err("Could not look up session");
+                }
+            },
+        };
+
+        let now = Utc::now();
+        if session.expire < now {
+            self.remove(&token_hash);
+            return Err("Authority expired");
+        }
+        //Kept for its refresh token, the authority itself is done
+        if session.last_seen + self.idle_lifetime < now {
+            return Err("Authority expired");
+        }
+        let touched = now - session.last_seen >= self.last_seen_resolution;
+        if touched {
+            session.last_seen = now;
+        }
+
+        //Ending a session removes its row before its cache entry. Looking
+        //again under the lock keeps an ended session from being put back.
+        let mut cache = self.cache.write().unwrap();
+        match cache.get_mut(&token_hash) {
+            Some(entry) => entry.last_seen = entry.last_seen.max(session.last_seen),
+            None if loaded => match self.exists(&token_hash) {
+                Ok(true) => {
+                    cache.insert(token_hash.clone(), session.clone());
+                }
+                Ok(false) => return Err("No user associated with Authority"),
+                Err(err) => {
+                    println!("Could not look up session");
+                    println!("{err}");
+                    return Err("Could not look up session");
+                }
+            },
+            None => return Err("No user associated with Authority"),
+        }
+        drop(cache);
+        if touched {
+            self.touch(&token_hash, now);
+        }
+        Ok(session)
+    }
+
+    ///Ends the session `token` belongs to, returning it.
+    pub fn revoke(&self, token: &str) -> Result<SessionUser, &'static str> {
+        let token_hash = sha256::digest(token);
+        let sql_connection = self.sql_connection.lock().unwrap();
+        let session = sql_connection
+            .query_row(
+                "DELETE FROM sessions WHERE token_hash = ?1 RETURNING *;",
+                [&token_hash],
+                session_from_row,
+            )
+            .optional();
+        drop(sql_connection);
+        self.cache.write().unwrap().remove(&token_hash);
+        match session {
+            Ok(Some(session)) => Ok(session),
+            Ok(None) => Err("No user associated with Authority"),
+            Err(err) => {
+                println!("Could not remove session");
+                println!("{err}");
+                Err("Could not remove session")
+            }
+        }
+    }
+
+    ///The sessions of `user_id` that haven't ended, oldest first.
+    pub fn list(&self, user_id: &str) -> rusqlite::Result<Vec<SessionUser>> {
+        let sql_connection = self.sql_connection.lock().unwrap();
+        let mut statement = sql_connection.prepare(
+            "SELECT * FROM sessions WHERE user_id = ?1 AND expires >= ?2 ORDER BY created;",
+        )?;
+        let rows = statement.query_map((user_id, Utc::now()), session_from_row)?;
+        let mut sessions = rows.collect::<rusqlite::Result<Vec<SessionUser>>>()?;
+        drop(statement);
+        drop(sql_connection);
+
+        //The cache knows of uses not written back yet
+        let cache = self.cache.read().unwrap();
+        for session in &mut sessions {
+            if let Some(cached) = cache.values().find(|cached| cached.id == session.id) {
+                session.last_seen = session.last_seen.max(cached.last_seen);
+            }
+        }
+        Ok(sessions)
+    }
+
+    ///Ends session `id` if it belongs to `user_id`. Returns whether it did.
+    pub fn revoke_id(&self, user_id: &str, id: &str) -> rusqlite::Result<bool> {
+        let sql_connection = self.sql_connection.lock().unwrap();
+        let removed = sql_connection.execute(
+            "DELETE FROM sessions WHERE id = ?1 AND user_id = ?2;",
+            [id, user_id],
+        )?;
+        drop(sql_connection);
+        self.cache
+            .write()
+            .unwrap()
+            .retain(|_, session| session.id != id);
+        Ok(removed > 0)
+    }
+
+    ///Ends every session of `user_id`, returning their ids.
+    pub fn remove_user(&self, user_id: &str) -> Vec<String> {
+        self.cache
+            .write()
+            .unwrap()
+            .retain(|_, session| session.user_id != user_id);
+        let sql_connection = self.sql_connection.lock().unwrap();
+        let removed = sql_connection
+            .prepare("DELETE FROM sessions WHERE user_id = ?1 RETURNING id;")
+            .and_then(|mut statement| {
+                statement
+                    .query_map([user_id], |row| row.get::<_, String>("id"))?
+                    .collect::<rusqlite::Result<Vec<String>>>()
+            });
+        match removed {
+            Ok(ids) => ids,
+            Err(err) => {
+                println!("Could not remove sessions");
+                println!("{err}");
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn cookies_enabled(&self) -> bool {
+        self.cookie.enabled
+    }
+
+    ///The authority in the session cookie, if cookies are enabled and the
+    ///client sent one.
+    pub fn cookie_token<'a>(&self, headers: &'a Headers) -> Option<&'a str> {
+        self.cookie(headers, &self.cookie.name)
+    }
+
+    ///The refresh token in its cookie.
+    pub fn cookie_refresh<'a>(&self, headers: &'a Headers) -> Option<&'a str> {
+        self.cookie(headers, &format!("{}_refresh", self.cookie.name))
+    }
+
+    fn cookie<'a>(&self, headers: &'a Headers, name: &str) -> Option<&'a str> {
+        if !self.cookie.enabled {
+            return None;
+        }
+        headers
+            .get_all("cookie")
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    ///Set-Cookie lines putting `token` and `refresh` in cookies, each
+    ///ending in \r\n. The refresh token is only sent back to /api/refresh.
+    pub fn set_cookies(&self, token: &str, refresh: &str) -> String {
+        let max_age = self.absolute_lifetime.num_seconds();
+        format!(
+            "Set-Cookie: {}={token}; Path=/api; Max-Age={max_age}{attributes}\r\n\
+            Set-Cookie: {}_refresh={refresh}; Path=/api/refresh; Max-Age={max_age}{attributes}\r\n",
+            self.cookie.name,
+            self.cookie.name,
+            attributes = self.cookie_attributes(),
+        )
+    }
+
+    ///Set-Cookie lines removing both cookies.
+    pub fn clear_cookies(&self) -> String {
+        if !self.cookie.enabled {
+            return String::new();
+        }
+        format!(
+            "Set-Cookie: {}=; Path=/api; Max-Age=0{attributes}\r\n\
+            Set-Cookie: {}_refresh=; Path=/api/refresh; Max-Age=0{attributes}\r\n",
+            self.cookie.name,
+            self.cookie.name,
+            attributes = self.cookie_attributes(),
+        )
+    }
+
+    fn cookie_attributes(&self) -> String {
+        let secure = if self.cookie.secure { "; Secure" } else { "" };
+        format!("; HttpOnly{secure}; SameSite={}", self.cookie.same_site)
+    }
+
+    ///The CSRF token going with the session of `token`. Derived from the
+    ///token, so it changes with it and needs no storing, and another site
+    ///can't come up with it without the cookie it can't read.
+    pub fn csrf_token(&self, token: &str) -> String {
+        sha256::digest(format!("csrf {token}"))
+    }
+
+    pub fn check_csrf(&self, token: &str, csrf: Option<&str>) -> bool {
+        let expected = self.csrf_token(token);
+        csrf.is_some_and(|csrf| bool::from(expected.as_bytes().ct_eq(csrf.as_bytes())))
+    }
+
+    fn load(&self, token_hash: &str) -> rusqlite::Result<Option<SessionUser>> {
+        let sql_connection = self.sql_connection.lock().unwrap();
+        sql_connection
+            .query_row(
+                "SELECT * FROM sessions WHERE token_hash = ?1;",
+                [token_hash],
+                session_from_row,
+            )
+            .optional()
+    }
+
+    fn exists(&self, token_hash: &str) -> rusqlite::Result<bool> {
+        let sql_connection = self.sql_connection.lock().unwrap();
+        sql_connection
+            .query_row(
+                "SELECT 1 FROM sessions WHERE token_hash = ?1;",
+                [token_hash],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|found| found.is_some())
+    }
+
+    fn touch(&self, token_hash: &str, now: DateTime<Utc>) {
+        let sql_connection = self.sql_connection.lock().unwrap();
+        if let Err(err) = sql_connection.execute(
+            "UPDATE sessions SET last_seen = ?1 WHERE token_hash = ?2;",
+            (now, token_hash),
+        ) {
+            println!("Could not update session");
+            println!("{err}");
+        }
+    }
+
+    fn remove(&self, token_hash: &str) {
+        self.cache.write().unwrap().remove(token_hash);
+        let sql_connection = self.sql_connection.lock().unwrap();
+        if let Err(err) =
+            sql_connection.execute("DELETE FROM sessions WHERE token_hash = ?1;", [token_hash])
+        {
+            println!("Could not remove session");
+            println!("{err}");
+        }
+    }
+
+    ///Drops expired sessions from the database and the cache.
+    fn sweep(&self) {
+        let now = Utc::now();
+        self.cache
+            .write()
+            .unwrap()
+            .retain(|_, session| session.expire >= now);
+        let sql_connection = self.sql_connection.lock().unwrap();
+        match sql_connection.execute("DELETE FROM sessions WHERE expires < ?1;", [now]) {
+            Ok(0) => (),
+            Ok(n) => println!("Swept {n} expired sessions"),
+            Err(err) => {
+                println!("Could not sweep sessions");
+                println!("{err}");
+            }
+        }
+    }
+
+    ///Starts a thread sweeping expired sessions every `interval` seconds,
+    ///those nobody presents again would stay around forever otherwise.
+    pub fn sweep_every(self: Arc<Self>, interval: u64) {
+        let interval = Duration::from_secs(interval.max(1));
+        thread::spawn(move || loop {
+            self.sweep();
+            thread::sleep(interval);
+        });
+    }
+}
+
+fn session_from_row(row: &rusqlite::Row) -> rusqlite::Result<SessionUser> {
+    Ok(SessionUser {
+        id: row.get("id")?,
+        user_id: row.get("user_id")?,
+        created: row.get("created")?,
+        expire: row.get("expires")?,
+        last_seen: row.get("last_seen")?,
+        user_agent: row.get("user_agent")?,
+        ip: row.get("ip")?,
+    })
+}
+
+///`value` cut to at most `max` bytes, on a character boundary.
+fn truncate(value: &str, max: usize) -> &str {
+    let mut end = value.len().min(max);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn sessions() -> Sessions {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../init.sql")).unwrap();
+        for user in ["ann", "bob"] {
+            conn.execute(
+                "INSERT INTO users (id, username, password, salt) VALUES (?1, ?1, '', 0);",
+                [user],
+            )
+            .unwrap();
+        }
+        Sessions::new(Arc::new(Mutex::new(conn)), &SessionSettings::default()).unwrap()
+    }
+
+    #[test]
+    fn revoked_tokens_stay_revoked() {
+        let sessions = sessions();
+        let (token, _) = sessions.create("ann", None, None).unwrap();
+        assert_eq!(sessions.user_id(&token), Ok(String::from("ann")));
+        sessions.revoke(&token).unwrap();
+        assert!(sessions.user_id(&token).is_err());
+
+        //Not cached, so looked up in the database
+        let (token, _) = sessions.create("ann", None, None).unwrap();
+        sessions.cache.write().unwrap().clear();
+        sessions.revoke(&token).unwrap();
+        assert!(sessions.user_id(&token).is_err());
+        assert!(sessions.cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn revoke_racing_lookups() {
+        let sessions = Arc::new(sessions());
+        for round in 0..100 {
+            let (token, _) = sessions.create("ann", None, None).unwrap();
+            if round % 2 == 0 {
+                sessions.cache.write().unwrap().clear();
+            }
+            let revoked = Arc::new(AtomicBool::new(false));
+            let looking: Vec<_> = (0..4)
+                .map(|_| {
+                    let sessions = sessions.clone();
+                    let token = token.clone();
+                    let revoked = revoked.clone();
+                    thread::spawn(move || {
+                        //A few more after the revoke, as long as one may still be underway
+                        let mut after = 0;
+                        while after < 10 {
+                            let _ = sessions.lookup(&token);
+                            if revoked.load(Ordering::Relaxed) {
+                                after += 1;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            thread::sleep(Duration::from_micros(200));
+            sessions.revoke(&token).unwrap();
+            revoked.store(true, Ordering::Relaxed);
+            for looking in looking {
+                looking.join().unwrap();
+            }
+            assert!(sessions.lookup(&token).is_err(), "round {round}");
+        }
+    }
+
+    #[test]
+    fn refresh_ends_the_old_tokens() {
+        let sessions = sessions();
+        let (token, refresh) = sessions.create("ann", None, None).unwrap();
+        let id = sessions.lookup(&token).unwrap().id;
+
+        let (new_token, new_refresh, user_id) = sessions.refresh(&refresh, None).unwrap();
+        assert_eq!(user_id, "ann");
+        assert!(sessions.lookup(&token).is_err());
+        assert!(sessions.refresh(&refresh, None).is_err());
+        //Still the same session
+        assert_eq!(sessions.lookup(&new_token).unwrap().id, id);
+
+        //Also when the old token has to be looked up in the database
+        sessions.cache.write().unwrap().clear();
+        assert!(sessions.lookup(&token).is_err());
+        assert!(sessions.lookup(&new_token).is_ok());
+        assert!(sessions.refresh(&new_refresh, None).is_ok());
+    }
+
+    ///Moves when session `id` was last used and when it ends, in the
+    ///database and the cache alike.
+    fn backdate(sessions: &Sessions, id: &str, last_seen: DateTime<Utc>, expire: DateTime<Utc>) {
+        sessions
+            .sql_connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE sessions SET last_seen = ?1, expires = ?2 WHERE id = ?3;",
+                (last_seen, expire, id),
+            )
+            .unwrap();
+        for session in sessions.cache.write().unwrap().values_mut() {
+            if session.id == id {
+                session.last_seen = last_seen;
+                session.expire = expire;
+            }
+        }
+    }
+
+    #[test]
+    fn idle_sessions_can_be_refreshed() {
+        let sessions = sessions();
+        let now = Utc::now();
+        for cached in [true, false] {
+            let (token, refresh) = sessions.create("ann", None, None).unwrap();
+            let id = sessions.lookup(&token).unwrap().id;
+            let idle = now - sessions.idle_lifetime - TimeDelta::seconds(1);
+            backdate(&sessions, &id, idle, now + TimeDelta::days(1));
+            if !cached {
+                sessions.cache.write().unwrap().clear();
+            }
+
+            assert_eq!(sessions.lookup(&token).err(), Some("Authority expired"));
+            //The idle authority is kept for its refresh token
+            assert_eq!(sessions.list("ann").unwrap().last().unwrap().id, id);
+
+            let (new_token, _, _) = sessions.refresh(&refresh, None).unwrap();
+            let session = sessions.lookup(&new_token).unwrap();
+            assert_eq!(session.id, id);
+            assert!(session.last_seen >= now);
+            assert!(sessions.lookup(&token).is_err());
+        }
+    }
+
+    #[test]
+    fn expired_sessions_cannot_be_refreshed() {
+        let sessions = sessions();
+        let now = Utc::now();
+        for cached in [true, false] {
+            let (token, refresh) = sessions.create("ann", None, None).unwrap();
+            let id = sessions.lookup(&token).unwrap().id;
+            //Used a moment ago, but past its absolute lifetime
+            backdate(&sessions, &id, now, now - TimeDelta::seconds(1));
+            if !cached {
+                sessions.cache.write().unwrap().clear();
+            }
+
+            assert_eq!(
+                sessions.refresh(&refresh, None).err(),
+                Some("Session expired")
+            );
+            assert_eq!(
+                sessions.refresh(&refresh, None).err(),
+                Some("Unknown refresh token")
+            );
+            assert!(sessions.lookup(&token).is_err());
+        }
+
+        //Looking up an expired authority ends its session as well
+        let (token, refresh) = sessions.create("ann", None, None).unwrap();
+        let id = sessions.lookup(&token).unwrap().id;
+        backdate(&sessions, &id, now, now - TimeDelta::seconds(1));
+        assert_eq!(sessions.lookup(&token).err(), Some("Authority expired"));
+        assert_eq!(
+            sessions.refresh(&refresh, None).err(),
+            Some("Unknown refresh token")
+        );
+        assert!(sessions.cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn revoking_by_id_needs_the_owner() {
+        let sessions = sessions();
+        let (ann, _) = sessions.create("ann", None, None).unwrap();
+        let (bob, _) = sessions.create("bob", None, None).unwrap();
+        let ann_id = sessions.lookup(&ann).unwrap().id;
+
+        assert_eq!(sessions.revoke_id("bob", &ann_id), Ok(false));
+        assert_eq!(sessions.user_id(&ann), Ok(String::from("ann")));
+        assert_eq!(sessions.list("ann").unwrap().len(), 1);
+        assert_eq!(sessions.revoke_id("ann", "no such session"), Ok(false));
+
+        assert_eq!(sessions.revoke_id("ann", &ann_id), Ok(true));
+        assert!(sessions.user_id(&ann).is_err());
+        assert!(sessions.list("ann").unwrap().is_empty());
+        assert_eq!(sessions.revoke_id("ann", &ann_id), Ok(false));
+        assert_eq!(sessions.user_id(&bob), Ok(String::from("bob")));
+    }
+
+    #[test]
+    fn listed_sessions_include_uses_not_written_back() {
+        let sessions = sessions();
+        let now = Utc::now();
+        let (first, _) = sessions.create("ann", Some("first"), None).unwrap();
+        let (second, _) = sessions.create("ann", Some("second"), None).unwrap();
+        sessions.create("bob", None, None).unwrap();
+        let first = sessions.lookup(&first).unwrap();
+        let second = sessions.lookup(&second).unwrap();
+
+        //The database is behind for the first session, the cache for the second
+        let stored = now - TimeDelta::seconds(30);
+        backdate(&sessions, &first.id, stored, first.expire);
+        for session in sessions.cache.write().unwrap().values_mut() {
+            if session.id == first.id {
+                session.last_seen = now;
+            }
+        }
+        sessions
+            .sql_connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE sessions SET last_seen = ?1 WHERE id = ?2;",
+                (now + TimeDelta::seconds(5), &second.id),
+            )
+            .unwrap();
+
+        let listed = sessions.list("ann").unwrap();
+        let ids: Vec<&str> = listed.iter().map(|session| session.id.as_str()).collect();
+        assert_eq!(ids, [first.id.as_str(), second.id.as_str()]);
+        assert_eq!(listed[0].last_seen, now);
+        assert_eq!(listed[0].user_agent.as_deref(), Some("first"));
+        assert_eq!(listed[1].last_seen, now + TimeDelta::seconds(5));
+
+        //Without a cache entry the stored time is all there is
+        sessions.cache.write().unwrap().clear();
+        assert_eq!(sessions.list("ann").unwrap()[0].last_seen, stored);
+
+        //Ended sessions aren't listed
+        backdate(&sessions, &second.id, now, now - TimeDelta::seconds(1));
+        assert_eq!(sessions.list("ann").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+use crate::data_structs::CertificateSettings;
+
+///The certificates from settings.json, picked by the name a client asks
+///for (SNI). They are read again from disk by `reload` without touching
+///connections or the listener, handshakes already under way finish with
+///the old ones.
+#[derive(Debug)]
+pub struct Certificates {
+    settings: Vec<CertificateSettings>,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl Certificates {
+    pub fn load(settings: &[CertificateSettings]) -> Result<Certificates, String> {
+        let provider = Arc::new(ring::default_provider());
+        let loaded = load_all(settings, &provider)?;
+        Ok(Certificates {
+            settings: settings.to_vec(),
+            provider,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    ///Reads every certificate again. Keeps the current ones if any fails to load.
+    pub fn reload(&self) -> Result<(), String> {
+        let loaded = load_all(&self.settings, &self.provider)?;
+        *self.loaded.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    ///Offers HTTP/2 during the handshake when `http2` is set.
+    pub fn server_config(self: &Arc<Self>, http2: bool) -> Result<Arc<ServerConfig>, String> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        if http2 {
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+impl Certificates {
+    ///The certificate for `server_name`, the default one when none is for it.
+    fn pick(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let loaded = self.loaded.read().unwrap();
+        let name = match server_name {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return loaded.default.clone(),
+        };
+
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+        let found = loaded
+            .by_name
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| loaded.by_name.get(&wildcard)));
+        found.unwrap_or(&loaded.default).clone()
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.pick(client_hello.server_name()))
+    }
+}
+
+fn load_all(settings: &[CertificateSettings], provider: &CryptoProvider) -> Result<Loaded, String> {
+    let mut by_name = HashMap::new();
+    let mut default = None;
+
+    for certificate in settings {
+        let key = Arc::new(load_one(certificate, provider)?);
+        for name in &certificate.server_names {
+            by_name.insert(name.to_ascii_lowercase(), key.clone());
+        }
+        default.get_or_insert(key);
+    }
+
+    match default {
+        Some(default) => Ok(Loaded { by_name, default }),
+        None => Err(String::from("TLS is enabled without any certificates")),
+    }
+}
+
+fn load_one(
+    certificate: &CertificateSettings,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(&certificate.cert_chain)
+        .and_then(|chain| chain.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {err}", certificate.cert_chain))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates", certificate.cert_chain));
+    }
+    let key = PrivateKeyDer::from_pem_file(&certificate.key)
+        .map_err(|err| format!("{}: {err}", certificate.key))?;
+
+    CertifiedKey::from_der(chain, key, provider)
+        .map_err(|err| format!("{}: {err}", certificate.cert_chain))
+}
+
+///Reloads `certificates` every time the process gets SIGHUP.
+pub fn reload_on_sighup(certificates: Arc<Certificates>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match certificates.reload() {
+                Ok(_) => println!("Reloaded TLS certificates"),
+                Err(err) => {
+                    println!("Could not reload TLS certificates, keeping the old ones");
+                    println!("{err}");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+    use uuid::Uuid;
+
+    ///Self-signed certificates for a.test and b.test, generated into a
+    ///directory of their own where they can be swapped around.
+    struct Scratch {
+        dir: PathBuf,
+        ///Name, certificate and PEM encoded key of each.
+        generated: Vec<(&'static str, CertificateDer<'static>, String)>,
+    }
+
+    impl Scratch {
+        fn new() -> Scratch {
+            let dir = std::env::temp_dir().join(format!("webber-tls-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let generated = ["a", "b"]
+                .into_iter()
+                .map(|name| {
+                    let names = vec![format!("{name}.test"), format!("*.{name}.test")];
+                    let certified = rcgen::generate_simple_self_signed(names).unwrap();
+                    let key = certified.signing_key.serialize_pem();
+                    fs::write(dir.join(format!("{name}.crt")), certified.cert.pem()).unwrap();
+                    fs::write(dir.join(format!("{name}.key")), &key).unwrap();
+                    (name, certified.cert.der().clone(), key)
+                })
+                .collect();
+            Scratch { dir, generated }
+        }
+
+        ///Which of the generated certificates `key` is.
+        fn which(&self, key: &CertifiedKey) -> &'static str {
+            self.generated
+                .iter()
+                .find(|(_, der, _)| key.end_entity_cert().unwrap() == der)
+                .map_or("neither", |(name, _, _)| name)
+        }
+
+        fn key(&self, name: &str) -> &str {
+            let (_, _, key) = self
+                .generated
+                .iter()
+                .find(|(generated, _, _)| *generated == name)
+                .unwrap();
+            key
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn settings(dir: &Path) -> Vec<CertificateSettings> {
+        ["a", "b"]
+            .iter()
+            .map(|name| CertificateSettings {
+                cert_chain: dir.join(format!("{name}.crt")).display().to_string(),
+                key: dir.join(format!("{name}.key")).display().to_string(),
+                server_names: vec![format!("{name}.test"), format!("*.{name}.test")],
+            })
+            .collect()
+    }
+
+    fn swap(dir: &Path) {
+        for extension in ["crt", "key"] {
+            let a = dir.join(format!("a.{extension}"));
+            let b = dir.join(format!("b.{extension}"));
+            let swapped = dir.join(format!("swapped.{extension}"));
+            fs::rename(&a, &swapped).unwrap();
+            fs::rename(&b, &a).unwrap();
+            fs::rename(&swapped, &b).unwrap();
+        }
+    }
+
+    #[test]
+    fn picks_by_server_name() {
+        let scratch = Scratch::new();
+        let certificates = Certificates::load(&settings(&scratch.dir)).unwrap();
+
+        assert_eq!(scratch.which(&certificates.pick(Some("a.test"))), "a");
+        assert_eq!(scratch.which(&certificates.pick(Some("B.Test"))), "b");
+        assert_eq!(scratch.which(&certificates.pick(Some("www.b.test"))), "b");
+        //The wildcard covers a single label
+        assert_eq!(scratch.which(&certificates.pick(Some("x.www.b.test"))), "a");
+        assert_eq!(scratch.which(&certificates.pick(Some("other.test"))), "a");
+        assert_eq!(scratch.which(&certificates.pick(None)), "a");
+    }
+
+    #[test]
+    fn reload_swaps_certificates() {
+        let scratch = Scratch::new();
+        let dir = &scratch.dir;
+        let certificates = Arc::new(Certificates::load(&settings(dir)).unwrap());
+        reload_on_sighup(certificates.clone()).unwrap();
+
+        swap(dir);
+        assert_eq!(scratch.which(&certificates.pick(Some("a.test"))), "a");
+        certificates.reload().unwrap();
+        assert_eq!(scratch.which(&certificates.pick(Some("a.test"))), "b");
+        assert_eq!(scratch.which(&certificates.pick(Some("b.test"))), "a");
+
+        //A broken file keeps the certificates loaded
+        fs::write(dir.join("b.key"), "").unwrap();
+        assert!(certificates.reload().is_err());
+        assert_eq!(scratch.which(&certificates.pick(Some("b.test"))), "a");
+
+        //And SIGHUP reloads them
+        fs::write(dir.join("b.key"), scratch.key("a")).unwrap();
+        swap(dir);
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+        for _ in 0..100 {
+            if scratch.which(&certificates.pick(Some("a.test"))) == "a" {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(scratch.which(&certificates.pick(Some("a.test"))), "a");
+        assert_eq!(scratch.which(&certificates.pick(Some("b.test"))), "b");
+    }
+}
@@ -0,0 +1,1039 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    net::IpAddr,
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::connection::{Client, Outgoing, Responder, Sink};
+use crate::data_structs::{JsonError, WebSocketSettings};
+use crate::events::Event;
+use crate::http::{self, Headers, Request, Version};
+use crate::Shared;
+
+///Appended to the client's key before hashing it into Sec-WebSocket-Accept.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+///A socket taking none of the frames waiting for it for this long is
+///dropped. Frames are written without blocking, so a stuck client never
+///holds up anyone else's.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+///A socket with more than this waiting to be written is dropped too.
+const MAX_OUTGOING: usize = 4 * http::MAX_BODY;
+///Token of the waker, sockets are registered under their id.
+const WAKE: Token = Token(usize::MAX);
+///Messages of one socket waiting for the one before to be answered. Past
+///this, the socket isn't read until they are.
+const MAX_QUEUED: usize = 16;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+pub enum HandshakeError {
+    BadRequest(&'static str),
+    ///Answered with 426 and the version the server speaks.
+    UnsupportedVersion,
+}
+
+///Checks `request` is an RFC 6455 opening handshake and returns the value
+///of Sec-WebSocket-Accept for it.
+pub fn accept_key(request: &Request) -> Result<String, HandshakeError> {
+    if request.method != "GET" {
+        return Err(HandshakeError::BadRequest("A WebSocket opens with GET"));
+    }
+    if request.version != Version::Http11 {
+        return Err(HandshakeError::BadRequest("A WebSocket needs HTTP/1.1"));
+    }
+    let has_token = |name: &str, token: &str| {
+        request
+            .headers
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if !has_token("upgrade", "websocket") || !has_token("connection", "upgrade") {
+        return Err(HandshakeError::BadRequest("Not a WebSocket handshake"));
+    }
+    if request.headers.get("sec-websocket-version") != Some("13") {
+        return Err(HandshakeError::UnsupportedVersion);
+    }
+
+    let key = match request.headers.get("sec-websocket-key") {
+        Some(key) => key.trim(),
+        None => return Err(HandshakeError::BadRequest("No Sec-WebSocket-Key")),
+    };
+    if STANDARD.decode(key).map(|key| key.len()) != Ok(16) {
+        return Err(HandshakeError::BadRequest("Malformed Sec-WebSocket-Key"));
+    }
+
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    Ok(STANDARD.encode(hasher.finalize()))
+}
+
+///Hands upgraded connections and answers to the thread started by `serve`.
+#[derive(Clone)]
+pub struct WebSockets {
+    sender: Sender<Command>,
+    waker: Arc<Waker>,
+}
+
+///The receiving end of `WebSockets`, and a sending end for the answers
+///to operations.
+pub struct Hub {
+    receiver: Receiver<Command>,
+    replies: WebSockets,
+    poll: Poll,
+}
+
+pub fn hub() -> io::Result<(WebSockets, Hub)> {
+    let (sender, receiver) = mpsc::channel();
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKE)?);
+    let websockets = WebSockets { sender, waker };
+    let replies = websockets.clone();
+    Ok((
+        websockets,
+        Hub {
+            receiver,
+            replies,
+            poll,
+        },
+    ))
+}
+
+enum Command {
+    Open {
+        client: Box<Client>,
+        user_id: String,
+        session_id: String,
+        authority: String,
+    },
+    Reply {
+        socket: u64,
+        message: String,
+    },
+    Close {
+        session_ids: Vec<String>,
+    },
+    Event(Arc<Event>),
+}
+
+impl WebSockets {
+    ///Takes `client` after its handshake was answered. Its operations run
+    ///as the user `authority` logs in, until session `session_id` ends.
+    pub fn open(&self, client: Client, user_id: String, session_id: String, authority: String) {
+        if let Err(err) = client.reader.get_ref().socket().set_nonblocking(true) {
+            println!("Could not open WebSocket");
+            println!("{err}");
+            return;
+        }
+        //Dropping the client closes it should the hub be gone
+        self.send(Command::Open {
+            client: Box::new(client),
+            user_id,
+            session_id,
+            authority,
+        });
+    }
+
+    ///Closes the sockets opened with any of the sessions `ids`.
+    pub fn close_sessions(&self, ids: &[String]) {
+        self.send(Command::Close {
+            session_ids: ids.to_vec(),
+        });
+    }
+
+    fn send(&self, command: Command) {
+        if self.sender.send(command).is_ok() {
+            if let Err(err) = self.waker.wake() {
+                println!("Could not wake the WebSocket hub");
+                println!("{err}");
+            }
+        }
+    }
+}
+
+///A text message a client sent, to be run like a request to the REST
+///endpoints. Answered through `perform`.
+pub struct Operation {
+    pub socket: u64,
+    peer: Option<IpAddr>,
+    authority: String,
+    message: String,
+    replies: WebSockets,
+}
+
+///What an operation message looks like. `id` is sent back with the
+///acknowledgement so the client can match the two up.
+#[derive(Deserialize)]
+struct OperationMessage {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    path: String,
+    #[serde(default)]
+    body: Option<Value>,
+}
+
+///Runs `operation` through the same handlers as the REST endpoints and
+///sends the acknowledgement, carrying the status and body they answered.
+pub fn perform(operation: Operation, shared: &Shared) {
+    let message = match serde_json::from_str::<OperationMessage>(&operation.message) {
+        Ok(message) => message,
+        Err(err) => {
+            let body = error_body("400 Bad Request", 400, err.to_string());
+            operation.reply(Value::Null, 400, body);
+            return;
+        }
+    };
+
+    let mut headers = Headers::default();
+    headers.push("authority", &operation.authority);
+    let body = match &message.body {
+        Some(body) => body.to_string().into_bytes(),
+        None => Vec::new(),
+    };
+    if !body.is_empty() {
+        headers.push("content-type", "application/json");
+        headers.push("content-length", &body.len().to_string());
+    }
+    let request = Request {
+        method: message.method,
+        target: message.path,
+        version: Version::Http11,
+        headers,
+        body,
+        peer: operation.peer,
+    };
+    if !request.path().starts_with("/api/") {
+        let body = error_body(
+            "404 Not Found",
+            404,
+            String::from("Only /api/ is reachable over a WebSocket"),
+        );
+        operation.reply(message.id, 404, body);
+        return;
+    }
+
+    let response = RefCell::new(Vec::new());
+    let responder = Responder::with_sink(&response, Version::Http11);
+    crate::handle_api_request(&responder, &request, shared, Some(operation.socket));
+    //Streams like /api/events can't be handed over from here, the socket stays
+    responder.take_hand_over();
+    drop(responder);
+
+    let (status, body) = parse_response(&response.into_inner());
+    operation.reply(message.id, status, body);
+}
+
+impl Operation {
+    fn reply(&self, id: Value, status: u16, body: Value) {
+        let message = json!({"type": "ack", "id": id, "status": status, "body": body});
+        self.replies.send(Command::Reply {
+            socket: self.socket,
+            message: message.to_string(),
+        });
+    }
+}
+
+fn error_body(message: &'static str, code: usize, internal: String) -> Value {
+    json!({"error": JsonError { message, code, internal }})
+}
+
+impl Sink for RefCell<Vec<u8>> {
+    fn send(&self, buf: &[u8]) -> io::Result<()> {
+        self.borrow_mut().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+///Status and body of an HTTP/1.1 response a handler wrote. Bodies that
+///aren't JSON come back as a string, empty ones as null.
+fn parse_response(response: &[u8]) -> (u16, Value) {
+    let head_end = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(i) => i,
+        None => return (500, Value::Null),
+    };
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(500);
+    let chunked = head.lines().any(|line| {
+        line.to_ascii_lowercase()
+            .starts_with("transfer-encoding: chunked")
+    });
+
+    let mut body = &response[head_end + 4..];
+    let mut unchunked = Vec::new();
+    if chunked {
+        //The handler framed it itself, so the chunks are well formed
+        while let Some(line_end) = body.windows(2).position(|window| window == b"\r\n") {
+            let size = std::str::from_utf8(&body[..line_end])
+                .ok()
+                .and_then(|size| usize::from_str_radix(size, 16).ok())
+                .unwrap_or(0);
+            let start = line_end + 2;
+            if size == 0 || start + size > body.len() {
+                break;
+            }
+            unchunked.extend_from_slice(&body[start..start + size]);
+            body = body.get(start + size + 2..).unwrap_or_default();
+        }
+        body = &unchunked;
+    }
+
+    if body.is_empty() {
+        return (status, Value::Null);
+    }
+    let body = serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+    (status, body)
+}
+
+///Starts the thread holding every open WebSocket, so none of them occupies
+///a worker between messages. Complete messages are passed to `run`, one
+///per socket at a time so they take effect in the order they were sent.
+///Events from `events` go to the sockets of the user they are about,
+///except the one whose operation caused them.
+pub fn serve<F>(hub: Hub, events: Receiver<Arc<Event>>, settings: &WebSocketSettings, run: F)
+where
+    F: Fn(Operation) + Send + 'static,
+{
+    let ping_interval = Duration::from_secs(settings.ping_interval.max(1));
+    //Passed on as commands, which wake the hub
+    let replies = hub.replies.clone();
+    thread::spawn(move || {
+        for event in events {
+            replies.send(Command::Event(event));
+        }
+    });
+    thread::spawn(move || hub_loop(hub, ping_interval, run));
+}
+
+fn hub_loop<F: Fn(Operation)>(hub: Hub, ping_interval: Duration, run: F) {
+    let Hub {
+        receiver,
+        replies,
+        mut poll,
+    } = hub;
+    let mut sockets: HashMap<u64, Socket> = HashMap::new();
+    let mut events = Events::with_capacity(256);
+    let mut next_id = 0;
+    loop {
+        let now = Instant::now();
+        let wait = sockets
+            .values()
+            .map(|socket| {
+                socket
+                    .deadline(ping_interval)
+                    .saturating_duration_since(now)
+            })
+            .min();
+        if let Err(err) = poll.poll(&mut events, wait) {
+            if err.kind() != io::ErrorKind::Interrupted {
+                println!("Could not wait for WebSockets");
+                println!("{err}");
+                return;
+            }
+            continue;
+        }
+
+        //Sockets to read from, write to or check the timers of
+        let mut ready: Vec<u64> = events
+            .iter()
+            .filter(|event| event.token() != WAKE)
+            .map(|event| event.token().0 as u64)
+            .collect();
+        for command in receiver.try_iter() {
+            match command {
+                Command::Open {
+                    client,
+                    user_id,
+                    session_id,
+                    authority,
+                } => {
+                    next_id += 1;
+                    let fd = client.reader.get_ref().socket().as_raw_fd();
+                    if let Err(err) = poll.registry().register(
+                        &mut SourceFd(&fd),
+                        Token(next_id as usize),
+                        Interest::READABLE | Interest::WRITABLE,
+                    ) {
+                        println!("Could not open WebSocket");
+                        println!("{err}");
+                        continue;
+                    }
+                    sockets.insert(
+                        next_id,
+                        Socket {
+                            id: next_id,
+                            user_id,
+                            session_id,
+                            authority,
+                            client,
+                            buffer: Vec::new(),
+                            fragments: None,
+                            queued: VecDeque::new(),
+                            busy: false,
+                            last_seen: Instant::now(),
+                            pinged: false,
+                            outgoing: Outgoing::default(),
+                            closing: false,
+                        },
+                    );
+                    //Frames may have come along with the handshake
+                    ready.push(next_id);
+                }
+                Command::Reply { socket, message } => {
+                    if let Some(socket) = sockets.get_mut(&socket) {
+                        socket.busy = false;
+                        socket.send(OP_TEXT, message.as_bytes());
+                        //Reading may have stopped with too many messages queued
+                        ready.push(socket.id);
+                    }
+                }
+                Command::Close { session_ids } => {
+                    for socket in sockets.values_mut() {
+                        if session_ids.contains(&socket.session_id) {
+                            socket.close(CLOSE_POLICY_VIOLATION);
+                            ready.push(socket.id);
+                        }
+                    }
+                }
+                Command::Event(event) => {
+                    let message = format!(
+                        "{{\"type\":\"event\",\"event\":\"{}\",\"data\":{}}}",
+                        event.kind, event.data
+                    );
+                    for socket in sockets.values_mut() {
+                        if socket.user_id == event.user_id && event.origin != Some(socket.id) {
+                            socket.send(OP_TEXT, message.as_bytes());
+                            ready.push(socket.id);
+                        }
+                    }
+                }
+            }
+        }
+        let now = Instant::now();
+        ready.extend(
+            sockets
+                .values()
+                .filter(|socket| socket.deadline(ping_interval) <= now)
+                .map(|socket| socket.id),
+        );
+        ready.sort_unstable();
+        ready.dedup();
+
+        for id in ready {
+            let Some(socket) = sockets.get_mut(&id) else {
+                continue;
+            };
+            if !socket.service(ping_interval) {
+                let fd = socket.client.reader.get_ref().socket().as_raw_fd();
+                let _ = poll.registry().deregister(&mut SourceFd(&fd));
+                //Dropping the client closes the connection
+                sockets.remove(&id);
+                continue;
+            }
+            if !socket.busy && !socket.closing {
+                if let Some(message) = socket.queued.pop_front() {
+                    socket.busy = true;
+                    run(Operation {
+                        socket: socket.id,
+                        peer: socket.client.reader.get_ref().peer_ip(),
+                        authority: socket.authority.clone(),
+                        message,
+                        replies: replies.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+///An open WebSocket, its socket nonblocking while it sits in the hub.
+struct Socket {
+    id: u64,
+    user_id: String,
+    ///The session whose authority opened the socket.
+    session_id: String,
+    authority: String,
+    client: Box<Client>,
+    ///Received bytes not yet making up a whole frame.
+    buffer: Vec<u8>,
+    ///Opcode and payload so far of a message sent in fragments.
+    fragments: Option<(u8, Vec<u8>)>,
+    ///Messages waiting for `busy` to clear.
+    queued: VecDeque<String>,
+    ///An operation of this socket is running.
+    busy: bool,
+    last_seen: Instant,
+    ///A ping went out since the client was last heard from.
+    pinged: bool,
+    ///Frames the socket had no room for yet.
+    outgoing: Outgoing,
+    ///A close frame is on its way, the socket is dropped once it is sent.
+    closing: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Socket {
+    ///Reads whatever arrived, acts on the frames in it and writes what is
+    ///waiting. Returns whether the socket stays open.
+    fn service(&mut self, ping_interval: Duration) -> bool {
+        if !self.closing {
+            match self.receive_frames(ping_interval) {
+                Ok(_) => (),
+                Err(Some(code)) => self.close(code),
+                Err(None) => return false,
+            }
+        }
+        let stream = self.client.reader.get_ref();
+        if self.outgoing.flush(stream).is_err() || self.outgoing.len() > MAX_OUTGOING {
+            return false;
+        }
+        match self.outgoing.stuck_since() {
+            Some(since) => since.elapsed() < WRITE_TIMEOUT,
+            None => !self.closing,
+        }
+    }
+
+    ///Reads whatever arrived and acts on the frames in it. Fails with the
+    ///close code to send, or None when the connection is gone already.
+    fn receive_frames(&mut self, ping_interval: Duration) -> Result<(), Option<u16>> {
+        if self.queued.len() < MAX_QUEUED {
+            self.read()?;
+        }
+        while let Some(frame) = parse_frame(&mut self.buffer)? {
+            self.last_seen = Instant::now();
+            self.pinged = false;
+            self.handle(frame)?;
+        }
+
+        let quiet = self.last_seen.elapsed();
+        if quiet >= ping_interval * 2 {
+            return Err(Some(CLOSE_GOING_AWAY));
+        }
+        if quiet >= ping_interval && !self.pinged {
+            self.send(OP_PING, &[]);
+            self.pinged = true;
+        }
+        Ok(())
+    }
+
+    ///When a ping is due, the client is given up on, or the frames waiting
+    ///for it have been stuck too long.
+    fn deadline(&self, ping_interval: Duration) -> Instant {
+        let quiet = if self.pinged {
+            ping_interval * 2
+        } else {
+            ping_interval
+        };
+        let deadline = self.last_seen + quiet;
+        match self.outgoing.stuck_since() {
+            Some(since) => deadline.min(since + WRITE_TIMEOUT),
+            None => deadline,
+        }
+    }
+
+    fn read(&mut self) -> Result<(), Option<u16>> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.client.reader.read(&mut buf) {
+                Ok(0) => return Err(None),
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&buf[..n]);
+                    //Room for the largest message plus its frame header
+                    if self.buffer.len() > http::MAX_BODY + 14 {
+                        return Err(Some(CLOSE_TOO_BIG));
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return Err(None),
+            }
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), Option<u16>> {
+        match frame.opcode {
+            OP_CLOSE => {
+                let code = match frame.payload.len() {
+                    0 => return Err(Some(CLOSE_NORMAL)),
+                    1 => return Err(Some(CLOSE_PROTOCOL_ERROR)),
+                    _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                };
+                if !is_valid_close_code(code) {
+                    return Err(Some(CLOSE_PROTOCOL_ERROR));
+                }
+                if std::str::from_utf8(&frame.payload[2..]).is_err() {
+                    return Err(Some(CLOSE_INVALID_DATA));
+                }
+                //Echoing the code completes the closing handshake
+                Err(Some(code))
+            }
+            OP_PING => {
+                self.send(OP_PONG, &frame.payload);
+                Ok(())
+            }
+            OP_PONG => Ok(()),
+            OP_TEXT | OP_BINARY => {
+                if self.fragments.is_some() {
+                    return Err(Some(CLOSE_PROTOCOL_ERROR));
+                }
+                if frame.fin {
+                    self.receive(frame.opcode, frame.payload)
+                } else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                    Ok(())
+                }
+            }
+            OP_CONTINUATION => {
+                let (opcode, mut payload) = match self.fragments.take() {
+                    Some(fragments) => fragments,
+                    None => return Err(Some(CLOSE_PROTOCOL_ERROR)),
+                };
+                payload.extend_from_slice(&frame.payload);
+                if payload.len() > http::MAX_BODY {
+                    return Err(Some(CLOSE_TOO_BIG));
+                }
+                if frame.fin {
+                    self.receive(opcode, payload)
+                } else {
+                    self.fragments = Some((opcode, payload));
+                    Ok(())
+                }
+            }
+            _ => Err(Some(CLOSE_PROTOCOL_ERROR)),
+        }
+    }
+
+    ///Queues a complete message to be run.
+    fn receive(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), Option<u16>> {
+        if opcode == OP_BINARY {
+            return Err(Some(CLOSE_UNSUPPORTED_DATA));
+        }
+        match String::from_utf8(payload) {
+            Ok(message) => {
+                self.queued.push_back(message);
+                Ok(())
+            }
+            Err(_) => Err(Some(CLOSE_INVALID_DATA)),
+        }
+    }
+
+    ///Queues one unfragmented frame, written by the next `service`.
+    ///Nothing more goes out after a close frame.
+    fn send(&mut self, opcode: u8, payload: &[u8]) {
+        if self.closing {
+            return;
+        }
+        self.outgoing.push(&frame(opcode, payload));
+    }
+
+    ///Sends a close frame with `code` and stops reading.
+    fn close(&mut self, code: u16) {
+        self.send(OP_CLOSE, &code.to_be_bytes());
+        self.closing = true;
+    }
+}
+
+///An unfragmented, unmasked frame as the server sends them.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+///Takes the first whole frame off `buffer`, None until one has arrived.
+fn parse_frame(buffer: &mut Vec<u8>) -> Result<Option<Frame>, Option<u16>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0F;
+    //No extension was negotiated that would give the reserved bits a meaning
+    if buffer[0] & 0x70 != 0 {
+        return Err(Some(CLOSE_PROTOCOL_ERROR));
+    }
+    //Clients have to mask every frame
+    if buffer[1] & 0x80 == 0 {
+        return Err(Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    let (len, mut offset) = match buffer[1] & 0x7F {
+        126 => match buffer.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err(Some(CLOSE_PROTOCOL_ERROR));
+    }
+    if len > http::MAX_BODY as u64 {
+        return Err(Some(CLOSE_TOO_BIG));
+    }
+    let len = len as usize;
+
+    let mask = match buffer.get(offset..offset + 4) {
+        Some(mask) => [mask[0], mask[1], mask[2], mask[3]],
+        None => return Ok(None),
+    };
+    offset += 4;
+    if buffer.len() < offset + len {
+        return Ok(None);
+    }
+
+    let mut payload: Vec<u8> = buffer.drain(..offset + len).skip(offset).collect();
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+///Codes a client may close with, RFC 6455 section 7.4.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Stream;
+    use crate::http::read_request;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    ///A frame as a client sends it, masked.
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let start = frame.len() - payload.len();
+        frame.splice(start..start, mask);
+        for (i, byte) in frame[start + 4..].iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        frame
+    }
+
+    ///A socket in the hub and the client's end of it.
+    fn socket() -> (Socket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let socket = Socket {
+            id: 1,
+            user_id: String::from("ann"),
+            session_id: String::from("session"),
+            authority: String::new(),
+            client: Box::new(Client::new(Stream::plain(server))),
+            buffer: Vec::new(),
+            fragments: None,
+            queued: VecDeque::new(),
+            busy: false,
+            last_seen: Instant::now(),
+            pinged: false,
+            outgoing: Outgoing::default(),
+            closing: false,
+        };
+        (socket, peer)
+    }
+
+    ///Sends `frames` from the client and lets the socket act on them.
+    fn deliver(socket: &mut Socket, peer: &mut TcpStream, frames: &[Vec<u8>]) -> bool {
+        peer.write_all(&frames.concat()).unwrap();
+        //Loopback hands the bytes over right away
+        socket.service(Duration::from_secs(60))
+    }
+
+    ///The next frame the server sent, its opcode and payload.
+    fn received(peer: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        peer.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80, "servers don't fragment");
+        assert_eq!(head[1] & 0x80, 0, "servers don't mask");
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                peer.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0; 8];
+                peer.read_exact(&mut len).unwrap();
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        peer.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    fn handshake(headers: &str) -> Result<String, HandshakeError> {
+        let raw = format!("GET /api/ws HTTP/1.1\r\nHost: a\r\n{headers}\r\n");
+        accept_key(&read_request(&mut raw.as_bytes()).unwrap().unwrap())
+    }
+
+    #[test]
+    fn accept_key_of_the_rfc_sample() {
+        let upgrade = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n";
+        let key = "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        assert_eq!(
+            handshake(&format!("{upgrade}{key}Sec-WebSocket-Version: 13\r\n")).ok(),
+            Some(String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="))
+        );
+        assert!(matches!(
+            handshake(&format!("{upgrade}{key}Sec-WebSocket-Version: 8\r\n")),
+            Err(HandshakeError::UnsupportedVersion)
+        ));
+        assert!(matches!(
+            handshake(&format!("{upgrade}Sec-WebSocket-Version: 13\r\n")),
+            Err(HandshakeError::BadRequest(_))
+        ));
+        assert!(matches!(
+            handshake(&format!(
+                "{upgrade}Sec-WebSocket-Key: c2hvcnQ=\r\nSec-WebSocket-Version: 13\r\n"
+            )),
+            Err(HandshakeError::BadRequest(_))
+        ));
+        assert!(matches!(
+            handshake(&format!("{key}Sec-WebSocket-Version: 13\r\n")),
+            Err(HandshakeError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn unmasks_frames() {
+        //RFC 6455 section 5.7, a masked "Hello"
+        let mut buffer = vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58, 0x81,
+        ];
+        let frame = parse_frame(&mut buffer).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OP_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+        //The start of the next frame is left for later
+        assert_eq!(buffer, [0x81]);
+        assert!(parse_frame(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn payload_lengths() {
+        for (len, header) in [(125, 2), (126, 4), (65535, 4), (65536, 10)] {
+            let payload = vec![b'a'; len];
+            assert_eq!(frame(OP_TEXT, &payload).len(), header + len);
+
+            let mut buffer = masked(true, OP_TEXT, &payload);
+            assert_eq!(buffer.len(), header + 4 + len);
+            //Nothing until the whole frame is there
+            let mut partial = buffer[..buffer.len() - 1].to_vec();
+            assert!(parse_frame(&mut partial).unwrap().is_none());
+            assert_eq!(parse_frame(&mut buffer).unwrap().unwrap().payload, payload);
+            assert!(buffer.is_empty());
+        }
+
+        let mut too_big = vec![0x82, 0xFF];
+        too_big.extend_from_slice(&(http::MAX_BODY as u64 + 1).to_be_bytes());
+        assert_eq!(parse_frame(&mut too_big).err(), Some(Some(CLOSE_TOO_BIG)));
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let protocol_error = Some(Some(CLOSE_PROTOCOL_ERROR));
+        //Unmasked
+        assert_eq!(parse_frame(&mut frame(OP_TEXT, b"a")).err(), protocol_error);
+        //Reserved bits set
+        let mut rsv = masked(true, OP_TEXT, b"a");
+        rsv[0] |= 0x40;
+        assert_eq!(parse_frame(&mut rsv).err(), protocol_error);
+        //Control frames can't be fragmented or longer than 125 bytes
+        assert_eq!(
+            parse_frame(&mut masked(false, OP_PING, b"")).err(),
+            protocol_error
+        );
+        assert_eq!(
+            parse_frame(&mut masked(true, OP_PING, &[0; 126])).err(),
+            protocol_error
+        );
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        let (mut socket, mut peer) = socket();
+        let open = deliver(
+            &mut socket,
+            &mut peer,
+            &[
+                masked(false, OP_TEXT, b"Hel"),
+                //Control frames may come in between fragments
+                masked(true, OP_PING, b"ping"),
+                masked(false, OP_CONTINUATION, b"lo"),
+                masked(true, OP_CONTINUATION, b" there"),
+                masked(true, OP_TEXT, b"again"),
+            ],
+        );
+        assert!(open);
+        assert_eq!(socket.queued, ["Hello there", "again"]);
+        assert_eq!(received(&mut peer), (OP_PONG, b"ping".to_vec()));
+
+        //A continuation with nothing to continue
+        let (mut socket, mut peer) = socket_with(&[masked(true, OP_CONTINUATION, b"a")]);
+        assert_eq!(received(&mut peer), close(CLOSE_PROTOCOL_ERROR));
+        assert!(!socket.service(Duration::from_secs(60)));
+
+        //A new message before the last one ended
+        let (_, mut peer) =
+            socket_with(&[masked(false, OP_TEXT, b"a"), masked(true, OP_TEXT, b"b")]);
+        assert_eq!(received(&mut peer), close(CLOSE_PROTOCOL_ERROR));
+    }
+
+    fn socket_with(frames: &[Vec<u8>]) -> (Socket, TcpStream) {
+        let (mut socket, mut peer) = socket();
+        //Closed sockets are dropped once the close frame is out
+        assert!(!deliver(&mut socket, &mut peer, frames));
+        (socket, peer)
+    }
+
+    fn close(code: u16) -> (u8, Vec<u8>) {
+        (OP_CLOSE, code.to_be_bytes().to_vec())
+    }
+
+    #[test]
+    fn close_codes() {
+        //Echoed to complete the closing handshake
+        let (_, mut peer) = socket_with(&[masked(true, OP_CLOSE, &[0x03, 0xe8, b'b', b'y'])]);
+        assert_eq!(received(&mut peer), close(CLOSE_NORMAL));
+        let (_, mut peer) = socket_with(&[masked(true, OP_CLOSE, &4000u16.to_be_bytes())]);
+        assert_eq!(received(&mut peer), close(4000));
+        //No code at all
+        let (_, mut peer) = socket_with(&[masked(true, OP_CLOSE, b"")]);
+        assert_eq!(received(&mut peer), close(CLOSE_NORMAL));
+
+        //Half a code, one only for local use, and a reason that isn't UTF-8
+        let (_, mut peer) = socket_with(&[masked(true, OP_CLOSE, &[0x03])]);
+        assert_eq!(received(&mut peer), close(CLOSE_PROTOCOL_ERROR));
+        let (_, mut peer) = socket_with(&[masked(true, OP_CLOSE, &1005u16.to_be_bytes())]);
+        assert_eq!(received(&mut peer), close(CLOSE_PROTOCOL_ERROR));
+        let (_, mut peer) = socket_with(&[masked(true, OP_CLOSE, &[0x03, 0xe8, 0xff])]);
+        assert_eq!(received(&mut peer), close(CLOSE_INVALID_DATA));
+
+        //Messages the server doesn't take
+        let (_, mut peer) = socket_with(&[masked(true, OP_BINARY, b"a")]);
+        assert_eq!(received(&mut peer), close(CLOSE_UNSUPPORTED_DATA));
+        let (_, mut peer) = socket_with(&[masked(true, OP_TEXT, &[0xff])]);
+        assert_eq!(received(&mut peer), close(CLOSE_INVALID_DATA));
+        let (_, mut peer) = socket_with(&[masked(true, 0x3, b"")]);
+        assert_eq!(received(&mut peer), close(CLOSE_PROTOCOL_ERROR));
+
+        for code in [1000, 1003, 1007, 1011, 3000, 4999] {
+            assert!(is_valid_close_code(code), "{code}");
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1012, 1015, 2999, 5000] {
+            assert!(!is_valid_close_code(code), "{code}");
+        }
+    }
+
+    #[test]
+    fn slow_readers_are_dropped() {
+        let (mut socket, _peer) = socket();
+        socket.send(OP_TEXT, b"a");
+        assert!(socket.service(Duration::from_secs(60)));
+        assert!(socket.outgoing.is_empty());
+        assert!(socket.outgoing.stuck_since().is_none());
+
+        //With the client's receive buffer full nothing more goes out
+        let fill = vec![0; 1 << 20];
+        while socket
+            .client
+            .reader
+            .get_ref()
+            .write_nonblocking(&fill)
+            .is_ok()
+        {}
+        socket.send(OP_TEXT, b"a");
+        assert!(socket.service(Duration::from_secs(60)));
+        assert!(!socket.outgoing.is_empty());
+        //Given up on once that went on for the write timeout
+        let stuck_since = socket.outgoing.stuck_since().unwrap();
+        assert_eq!(
+            socket.deadline(Duration::from_secs(60)),
+            stuck_since + WRITE_TIMEOUT
+        );
+
+        //Nor is more kept waiting than MAX_OUTGOING
+        let (mut socket, _peer) = self::socket();
+        while socket
+            .client
+            .reader
+            .get_ref()
+            .write_nonblocking(&fill)
+            .is_ok()
+        {}
+        socket.send(OP_TEXT, &vec![0; MAX_OUTGOING]);
+        assert!(!socket.service(Duration::from_secs(60)));
+    }
+}